@@ -0,0 +1,160 @@
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::future::{ready, Ready};
+use tracing::{info, warn};
+
+use crate::{
+    api::types::UserId,
+    audit_log::{record, Actor, AuditAction, AuditEvent, AuditLog},
+    config::{AdminRole, Config},
+    error::{PapiError, PapiResult},
+};
+
+pub const ADMIN_API_KEY_HEADER: &str = "X-Admin-Api-Key";
+
+/// Generates a new admin API key, returning it along with its SHA-256 digest
+pub fn generate_api_key() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let api_key = URL_SAFE_NO_PAD.encode(bytes);
+    let sha256 = hash_api_key(&api_key);
+    (api_key, sha256)
+}
+
+/// Hex encoded SHA-256 digest of an admin API key, as configured
+pub fn hash_api_key(api_key: &str) -> String {
+    Sha256::digest(api_key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Operator identified by a configured admin API key, rejects the request with 401 otherwise
+#[derive(Debug, Clone)]
+pub struct AdminUser {
+    name: String,
+    role: AdminRole,
+}
+
+impl AdminUser {
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn role(&self) -> AdminRole {
+        self.role
+    }
+
+    pub fn actor(&self) -> Actor {
+        Actor::Admin(self.name.clone())
+    }
+
+    /// Audit event of an action of the operator on the data of a user
+    pub fn audit_event(
+        &self,
+        user_id: &UserId,
+        action: AuditAction,
+        request_id: String,
+    ) -> AuditEvent {
+        AuditEvent::new(user_id.clone(), self.actor(), action).with_request_id(request_id)
+    }
+
+    pub fn authenticate(req: &HttpRequest) -> PapiResult<Self> {
+        let config = req
+            .app_data::<Data<Config>>()
+            .ok_or(PapiError::Config("Config not registered".to_string()))?;
+        let api_key = req
+            .headers()
+            .get(ADMIN_API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(PapiError::Unauthorized("Missing admin API key".to_string()))?;
+
+        // only digests are compared, so the comparison does not leak the configured keys
+        let sha256 = hash_api_key(api_key);
+        let Some(key) = config
+            .admin
+            .api_keys
+            .iter()
+            .find(|key| key.sha256 == sha256)
+        else {
+            warn!(target: "audit", path = %req.path(), "Rejected unknown admin API key");
+            return Err(PapiError::Unauthorized("Invalid admin API key".to_string()));
+        };
+
+        Ok(Self {
+            name: key.name.clone(),
+            role: key.role,
+        })
+    }
+
+    /// Fails unless the role of the key includes the given one
+    pub fn require(&self, role: AdminRole, action: &str) -> PapiResult<()> {
+        if self.role < role {
+            warn!(
+                target: "audit",
+                actor = %self.name,
+                role = self.role.as_str(),
+                action,
+                "Denied admin action"
+            );
+            return Err(PapiError::Forbidden(format!(
+                "The {} role is required to {}",
+                role.as_str(),
+                action
+            )));
+        }
+        Ok(())
+    }
+
+    /// Records an admin action and its outcome in the audit trail, appending it to the audit log
+    /// when it was denied or failed, the successful ones being recorded by their own events
+    pub async fn audit<T>(
+        &self,
+        audit_log: &dyn AuditLog,
+        action: &str,
+        user_id: Option<&UserId>,
+        result: &PapiResult<T>,
+        request_id: String,
+    ) {
+        let error = result.as_ref().err();
+        info!(
+            target: "audit",
+            actor = %self.name,
+            role = self.role.as_str(),
+            action,
+            user_id,
+            succeeded = error.is_none(),
+            error = error.map(|e| e.to_string()),
+            "Admin action"
+        );
+        let Some(error) = error else {
+            return;
+        };
+        let audit_action = match error {
+            PapiError::Forbidden(_) => AuditAction::AdminActionDenied,
+            _ => AuditAction::AdminActionFailed,
+        };
+        let event = match user_id {
+            Some(user_id) => AuditEvent::new(user_id.clone(), self.actor(), audit_action),
+            None => AuditEvent::without_user(self.actor(), audit_action),
+        };
+        record(
+            audit_log,
+            event
+                .with_request_id(request_id)
+                .with_details(format!("{}: {}", action, error)),
+        )
+        .await;
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = PapiError;
+    type Future = Ready<PapiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(AdminUser::authenticate(req))
+    }
+}
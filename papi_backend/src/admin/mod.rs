@@ -0,0 +1,352 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    api::types::{AuthorizationSummary, OAuthInfo, Resource, ResourceState, UserId},
+    audit_log::{AuditAction, AuditEvent},
+    consent_ledger::ConsentRecord,
+    erasure::UserDataStores,
+    error::{PapiError, PapiResult},
+    job_queue::{InitiationInfo, Job, JobCount, JobId, JobRecord, PollingInfo},
+};
+
+pub use auth::{generate_api_key, hash_api_key, AdminUser, ADMIN_API_KEY_HEADER};
+
+mod auth;
+
+/// A user and the state of the resources they authorized
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    user_id: UserId,
+    #[serde(flatten)]
+    authorization: AuthorizationSummary,
+}
+
+/// Users of a page of the auth DB matching a search, possibly none, along with where the next
+/// page starts
+#[derive(Debug, Serialize)]
+pub struct UsersPage {
+    users: Vec<UserSummary>,
+    next_cursor: Option<UserId>,
+}
+
+/// The authorizations of a user, as recorded by the consent ledger and the audit log
+#[derive(Debug, Serialize)]
+pub struct AuthorizationHistory {
+    user_id: UserId,
+    /// The last authorization, the only one kept in the auth DB
+    current_authorization: Option<AuthorizationSummary>,
+    /// The consent asked for each authorization, oldest first
+    consents: Vec<ConsentRecord>,
+    /// Grants, resets and revocations of the authorizations, oldest first
+    events: Vec<AuditEvent>,
+}
+
+impl AuthorizationHistory {
+    fn is_empty(&self) -> bool {
+        self.current_authorization.is_none() && self.consents.is_empty() && self.events.is_empty()
+    }
+}
+
+// number of users read at once when going through all of them
+const USERS_SCAN_PAGE_SIZE: usize = 1000;
+
+const AUTHORIZATION_ACTIONS: [AuditAction; 4] = [
+    AuditAction::AuthorizationGranted,
+    AuditAction::TokenExchanged,
+    AuditAction::AuthorizationReset,
+    AuditAction::AuthorizationRevoked,
+];
+
+/// Jobs of a user still in the queue, the acknowledged ones being removed from it
+#[derive(Debug, Serialize)]
+pub struct JobTimeline {
+    user_id: UserId,
+    jobs: Vec<JobRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminStats {
+    users: usize,
+    revoked_users: usize,
+    /// Number of granted resources in each state, across all users
+    resources: BTreeMap<ResourceState, usize>,
+    jobs: Vec<JobCount>,
+}
+
+/// Job enqueued on behalf of an operator
+#[derive(Debug, Serialize)]
+pub struct RetriggerReceipt {
+    user_id: UserId,
+    resource: Resource,
+    job_id: JobId,
+}
+
+impl UserSummary {
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+}
+
+impl UsersPage {
+    pub fn users(&self) -> &[UserSummary] {
+        &self.users
+    }
+}
+
+pub async fn list_users(stores: &UserDataStores) -> PapiResult<Vec<UserSummary>> {
+    let mut users = vec![];
+    let mut cursor = None;
+    loop {
+        let page = search_users(stores, "", USERS_SCAN_PAGE_SIZE, cursor).await?;
+        users.extend(page.users);
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            return Ok(users);
+        }
+    }
+}
+
+/// Lists the users of a page of at most `limit` users whose ID or Google account email contains
+/// the search term, ignoring case
+pub async fn search_users(
+    stores: &UserDataStores,
+    search: &str,
+    limit: usize,
+    cursor: Option<UserId>,
+) -> PapiResult<UsersPage> {
+    let search = search.to_lowercase();
+    let page = stores
+        .auth_db_client
+        .list_auths(limit, cursor)
+        .await
+        .map_err(|e| e.context("could not list users"))?;
+    let mut users: Vec<UserSummary> = page
+        .auths
+        .iter()
+        .filter(|oauth_info| {
+            oauth_info.user_id().to_lowercase().contains(&search)
+                || oauth_info
+                    .email()
+                    .is_some_and(|email| email.to_lowercase().contains(&search))
+        })
+        .map(|oauth_info| UserSummary {
+            user_id: oauth_info.user_id(),
+            authorization: AuthorizationSummary::from(oauth_info),
+        })
+        .collect();
+    users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+    Ok(UsersPage {
+        users,
+        next_cursor: page.next_cursor,
+    })
+}
+
+/// Fails for users who never went through an authorization
+pub async fn authorization_history(
+    stores: &UserDataStores,
+    user_id: &UserId,
+) -> PapiResult<AuthorizationHistory> {
+    let history = read_authorization_history(stores, user_id).await?;
+    if history.is_empty() {
+        return Err(PapiError::NotFound(format!(
+            "No authorization found for user with ID: {}",
+            user_id
+        )));
+    }
+    Ok(history)
+}
+
+/// Returns the authorization history of a user, empty if they never went through an authorization
+pub async fn read_authorization_history(
+    stores: &UserDataStores,
+    user_id: &UserId,
+) -> PapiResult<AuthorizationHistory> {
+    let current_authorization = match stores
+        .auth_db_client
+        .read_last_auth_for_user(user_id.clone())
+        .await
+    {
+        Ok(oauth_info) => Some(AuthorizationSummary::from(&oauth_info)),
+        Err(PapiError::NotFound(_)) => None,
+        Err(e) => return Err(e.context("could not read last authorization")),
+    };
+    let consents = stores
+        .consent_ledger
+        .list_user_consents(user_id)
+        .await
+        .map_err(|e| e.context("could not read consents"))?;
+    let events = stores
+        .audit_log
+        .list_user_events(user_id)
+        .await
+        .map_err(|e| e.context("could not read audit log"))?
+        .into_iter()
+        .filter(|event| AUTHORIZATION_ACTIONS.contains(&event.action()))
+        .collect();
+
+    Ok(AuthorizationHistory {
+        user_id: user_id.clone(),
+        current_authorization,
+        consents,
+        events,
+    })
+}
+
+/// Records a read of the data of each user, failing on the first read which could not be recorded
+/// so that the data is not sent
+pub async fn record_reads<'a>(
+    stores: &UserDataStores,
+    user_ids: impl IntoIterator<Item = &'a UserId>,
+    event: impl Fn(&UserId) -> AuditEvent,
+) -> PapiResult<()> {
+    for user_id in user_ids {
+        stores
+            .audit_log
+            .append(event(user_id))
+            .await
+            .map_err(|e| e.context("could not record read"))?;
+    }
+    Ok(())
+}
+
+pub async fn job_timeline(stores: &UserDataStores, user_id: &UserId) -> PapiResult<JobTimeline> {
+    let jobs = stores
+        .job_queue
+        .list_user_jobs(user_id)
+        .await
+        .map_err(|e| e.context("could not list jobs"))?;
+    Ok(JobTimeline {
+        user_id: user_id.clone(),
+        jobs,
+    })
+}
+
+pub async fn stats(stores: &UserDataStores) -> PapiResult<AdminStats> {
+    let (mut users, mut revoked_users) = (0, 0);
+    let mut resources = BTreeMap::new();
+    let mut cursor = None;
+    loop {
+        let page = stores
+            .auth_db_client
+            .list_auths(USERS_SCAN_PAGE_SIZE, cursor)
+            .await
+            .map_err(|e| e.context("could not list users"))?;
+        users += page.auths.len();
+        revoked_users += page.auths.iter().filter(|a| a.is_revoked()).count();
+        for resource_state in page
+            .auths
+            .iter()
+            .flat_map(|oauth_info| oauth_info.granted_resources().into_values())
+        {
+            *resources.entry(resource_state).or_default() += 1;
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    let jobs = stores
+        .job_queue
+        .count_jobs()
+        .await
+        .map_err(|e| e.context("could not count jobs"))?;
+
+    Ok(AdminStats {
+        users,
+        revoked_users,
+        resources,
+        jobs,
+    })
+}
+
+/// Initiates the archive job of a resource again, e.g. after it failed on Google's side
+pub async fn retrigger_archive_initiation(
+    stores: &UserDataStores,
+    user_id: &UserId,
+    resource: &Resource,
+) -> PapiResult<RetriggerReceipt> {
+    reset_resource_state(stores, user_id, resource, ResourceState::Granted).await?;
+    let job_id = stores
+        .job_queue
+        .enqueue(Job::ArchiveInitiation(InitiationInfo::new(
+            user_id.clone(),
+            resource.clone(),
+        )))
+        .await
+        .map_err(|e| e.context("could not enqueue archive initiation job"))?;
+    Ok(RetriggerReceipt {
+        user_id: user_id.clone(),
+        resource: resource.clone(),
+        job_id,
+    })
+}
+
+/// Downloads the archive of a completed archive job again, by polling it for its download URL
+pub async fn retrigger_download(
+    stores: &UserDataStores,
+    user_id: &UserId,
+    resource: &Resource,
+    archive_job_id: String,
+) -> PapiResult<RetriggerReceipt> {
+    reset_resource_state(stores, user_id, resource, ResourceState::Initiated).await?;
+    let job_id = stores
+        .job_queue
+        .enqueue(Job::ArchivePolling(PollingInfo::new(
+            user_id.clone(),
+            resource.clone(),
+            archive_job_id,
+        )))
+        .await
+        .map_err(|e| e.context("could not enqueue archive polling job"))?;
+    Ok(RetriggerReceipt {
+        user_id: user_id.clone(),
+        resource: resource.clone(),
+        job_id,
+    })
+}
+
+/// Moves a resource back to an earlier state, so that the jobs processing it do not skip it
+async fn reset_resource_state(
+    stores: &UserDataStores,
+    user_id: &UserId,
+    resource: &Resource,
+    resource_state: ResourceState,
+) -> PapiResult<()> {
+    let _user_lock = stores.user_locks.lock(user_id).await;
+    let mut oauth_info = read_authorized(stores, user_id).await?;
+    oauth_info
+        .update_granted_resource_state(resource, resource_state)
+        .map_err(|e| e.context("could not update resource state"))?;
+    stores
+        .auth_db_client
+        .update_auth_for_user(user_id.clone(), oauth_info)
+        .await
+        .map_err(|e| e.context("could not store updated OAuth info"))
+}
+
+/// Resets the authorization on Google's side, stopping its archive jobs
+pub async fn reset_authorization(stores: &UserDataStores, user_id: &UserId) -> PapiResult<()> {
+    let oauth_info = read_authorized(stores, user_id).await?;
+    stores
+        .oauth_client
+        .reset_authorization(&oauth_info)
+        .await
+        .map_err(|e| e.context("could not reset authorization"))
+}
+
+/// Reads the OAuth info of a user whose access token can still be used
+async fn read_authorized(stores: &UserDataStores, user_id: &UserId) -> PapiResult<OAuthInfo> {
+    let oauth_info = stores
+        .auth_db_client
+        .read_last_auth_for_user(user_id.clone())
+        .await
+        .map_err(|e| e.context("could not read last auth for user"))?;
+    if !oauth_info.has_valid_access_token() {
+        return Err(PapiError::InvalidState(format!(
+            "Access token of user with ID: {} expired or revoked, the user must authorize again",
+            user_id
+        )));
+    }
+    Ok(oauth_info)
+}
@@ -1,36 +1,385 @@
+use crate::{
+    admin::{self, AdminUser},
+    api::types::{
+        AuthorizationCodeRequestPayload, AuthorizationUrlQuery, Resource, RetryResourceQuery,
+        RevokeAuthorizationQuery, UserId, UserSearchQuery,
+    },
+    audit_log::{record, AuditAction, AuditLog},
+    auth_db_client::AuthDbClient,
+    config::{AdminRole, Config},
+    consent_ledger::ConsentLedger,
+    erasure::{revoke_authorization, UserDataStores},
+    error::{PapiError, PapiResult},
+    health::check_readiness,
+    job_queue::JobQueue,
+    metrics::{self, METRICS},
+    oauth_state_store::OAuthStateStore,
+    session::{AuthenticatedUser, SessionDelivery, SessionManager},
+    worker_pool::WorkerStatus,
+};
 use actix_web::{
-    web::{Data, Json},
-    HttpRequest, HttpResponse, Responder,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{Data, Json, Path, Query},
+    HttpRequest, HttpResponse, ResponseError,
 };
-use tokio::sync::mpsc::UnboundedSender;
-
-use crate::api::types::{AuthorizationCodeRequestPayload, UserStateMap};
+use tracing_actix_web::RequestId;
 
-use super::{
-    handlers::{get_google_oauth_url, post_google_authorization_code},
-    types::OAuthInfo,
+use super::handlers::{
+    create_session, erase_user_data, export_user_data, get_google_oauth_url, get_user_audit_log,
+    get_user_consents, post_google_authorization_code, revoke_google_authorization,
 };
 
 pub const DATA_PORTABILITY_BASE_URL: &str = "https://www.googleapis.com/auth/dataportability.";
+/// Scopes needed to learn which Google account granted the authorization
+pub const IDENTITY_SCOPES: [&str; 2] = ["openid", "email"];
 
-pub async fn get_auth_api(req: HttpRequest, auth: Data<UserStateMap>) -> impl Responder {
-    println!("Got request: {:?}", req);
-    match get_google_oauth_url(req, auth) {
-        Ok(auth_url) => HttpResponse::Ok()
-            .content_type("application/json")
-            .json(serde_json::json!({"url": auth_url})),
-        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+/// Session of the browsers, only sent as an HttpOnly cookie
+pub async fn post_session_api(
+    req: HttpRequest,
+    session_manager: Data<SessionManager>,
+) -> PapiResult<HttpResponse> {
+    match create_session(&req, &session_manager, SessionDelivery::Cookie).await {
+        Ok((user_id, token)) => Ok(HttpResponse::Ok()
+            .cookie(session_manager.cookie(token))
+            .json(serde_json::json!({ "user_id": user_id }))),
+        // the page cannot remove the HttpOnly cookie itself, so that it can start a new session
+        Err(e @ PapiError::Unauthorized(_)) => {
+            let mut response = e.error_response();
+            response
+                .add_removal_cookie(&session_manager.cookie(String::new()))
+                .map_err(|e| PapiError::Config(format!("Invalid session cookie: {}", e)))?;
+            Ok(response)
+        }
+        Err(e) => Err(e),
     }
 }
 
-pub async fn post_auth_api(
+/// Session of the other clients, returned as a bearer token
+pub async fn post_session_token_api(
     req: HttpRequest,
+    session_manager: Data<SessionManager>,
+) -> PapiResult<HttpResponse> {
+    let (user_id, token) =
+        create_session(&req, &session_manager, SessionDelivery::BearerToken).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "user_id": user_id, "token": token })))
+}
+
+/// Starts an authorization, so it is a POST for the session cookie to be checked against CSRF
+pub async fn post_auth_url_api(
+    user: AuthenticatedUser,
+    query: Query<AuthorizationUrlQuery>,
+    auth: Data<dyn OAuthStateStore>,
+    auth_db_client: Data<dyn AuthDbClient>,
+    consent_ledger: Data<dyn ConsentLedger>,
+    config: Data<Config>,
+) -> PapiResult<HttpResponse> {
+    let (auth_url, requested_resources) = get_google_oauth_url(
+        user,
+        query,
+        auth,
+        auth_db_client,
+        consent_ledger,
+        Data::clone(&config),
+    )
+    .await?;
+    // the frontend shows what the user consents to along with the link
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(serde_json::json!({
+            "url": auth_url,
+            "requested_resources": requested_resources,
+            "privacy_notice_version": config.consent.privacy_notice_version,
+            "purposes": config.consent.purposes,
+        })))
+}
+
+pub async fn post_auth_api(
+    user: AuthenticatedUser,
     payload: Json<AuthorizationCodeRequestPayload>,
-    auth: Data<UserStateMap>,
-    authorization_tx: Data<UnboundedSender<OAuthInfo>>,
-) -> impl Responder {
-    match post_google_authorization_code(req, payload, auth, authorization_tx).await {
-        Ok(()) => HttpResponse::Ok().body("OK"),
-        Err(err) => HttpResponse::InternalServerError().body(err),
+    auth: Data<dyn OAuthStateStore>,
+    job_queue: Data<dyn JobQueue>,
+    audit_log: Data<dyn AuditLog>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    post_google_authorization_code(
+        user,
+        payload,
+        auth,
+        job_queue,
+        audit_log,
+        request_id.to_string(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().body("OK"))
+}
+
+pub async fn delete_auth_api(
+    user: AuthenticatedUser,
+    query: Query<RevokeAuthorizationQuery>,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let receipt = revoke_google_authorization(user, query, stores, request_id.to_string()).await?;
+    Ok(HttpResponse::Ok().json(receipt))
+}
+
+pub async fn delete_user_api(
+    user: AuthenticatedUser,
+    stores: Data<UserDataStores>,
+    session_manager: Data<SessionManager>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let receipt = erase_user_data(user, stores, request_id.to_string()).await?;
+    // the session of the erased user must not be used anymore
+    let mut cookie = session_manager.cookie(String::new());
+    cookie.make_removal();
+    Ok(HttpResponse::Ok().cookie(cookie).json(receipt))
+}
+
+pub async fn get_user_export_api(
+    user: AuthenticatedUser,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let archive = export_user_data(user, stores, request_id.to_string()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("papi_export.zip".to_string())],
+        })
+        .streaming(archive))
+}
+
+pub async fn get_user_audit_log_api(
+    user: AuthenticatedUser,
+    audit_log: Data<dyn AuditLog>,
+) -> PapiResult<HttpResponse> {
+    let audit_log = get_user_audit_log(user, audit_log).await?;
+    Ok(HttpResponse::Ok().json(audit_log))
+}
+
+pub async fn get_user_consents_api(
+    user: AuthenticatedUser,
+    consent_ledger: Data<dyn ConsentLedger>,
+    config: Data<Config>,
+) -> PapiResult<HttpResponse> {
+    let consents = get_user_consents(user, consent_ledger, config).await?;
+    Ok(HttpResponse::Ok().json(consents))
+}
+
+/// Requires an admin API key, as the metrics tell about the activity of the users
+pub async fn get_metrics_api(
+    _admin: AdminUser,
+    stores: Data<UserDataStores>,
+) -> PapiResult<HttpResponse> {
+    metrics::observe_queue(stores.job_queue.as_ref())
+        .await
+        .map_err(|e| e.context("could not count archive jobs"))?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.encode()?))
+}
+
+/// The process is alive, regardless of its dependencies
+pub async fn get_healthz_api() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
+}
+
+pub async fn get_readyz_api(
+    stores: Data<UserDataStores>,
+    worker_status: Data<WorkerStatus>,
+) -> HttpResponse {
+    let readiness = check_readiness(&stores, &worker_status).await;
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
     }
 }
+
+pub async fn get_admin_users_api(
+    admin: AdminUser,
+    query: Query<UserSearchQuery>,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let users = admin::search_users(&stores, &query.search(), query.limit(), query.cursor()).await;
+    admin
+        .audit(
+            stores.audit_log.as_ref(),
+            "search_users",
+            None,
+            &users,
+            request_id.to_string(),
+        )
+        .await;
+    let users = users?;
+    admin::record_reads(
+        &stores,
+        users.users().iter().map(|user| user.user_id()),
+        |user_id| {
+            admin
+                .audit_event(user_id, AuditAction::DataRead, request_id.to_string())
+                .with_details("user list".to_string())
+        },
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn get_admin_user_api(
+    admin: AdminUser,
+    user_id: Path<UserId>,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let history = admin::authorization_history(&stores, &user_id).await;
+    admin
+        .audit(
+            stores.audit_log.as_ref(),
+            "get_authorization_history",
+            Some(&user_id),
+            &history,
+            request_id.to_string(),
+        )
+        .await;
+    let history = history?;
+    // no data is sent unless the read is recorded
+    stores
+        .audit_log
+        .append(
+            admin
+                .audit_event(&user_id, AuditAction::DataRead, request_id.to_string())
+                .with_details("authorization history".to_string()),
+        )
+        .await
+        .map_err(|e| e.context("could not record read"))?;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+pub async fn get_admin_user_jobs_api(
+    admin: AdminUser,
+    user_id: Path<UserId>,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let timeline = admin::job_timeline(&stores, &user_id).await;
+    admin
+        .audit(
+            stores.audit_log.as_ref(),
+            "get_job_timeline",
+            Some(&user_id),
+            &timeline,
+            request_id.to_string(),
+        )
+        .await;
+    let timeline = timeline?;
+    stores
+        .audit_log
+        .append(
+            admin
+                .audit_event(&user_id, AuditAction::DataRead, request_id.to_string())
+                .with_details("job timeline".to_string()),
+        )
+        .await
+        .map_err(|e| e.context("could not record read"))?;
+    Ok(HttpResponse::Ok().json(timeline))
+}
+
+pub async fn post_admin_retry_resource_api(
+    admin: AdminUser,
+    path: Path<(UserId, Resource)>,
+    query: Query<RetryResourceQuery>,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let (user_id, resource) = path.into_inner();
+    let receipt = match (
+        admin.require(AdminRole::Operator, "retry resources"),
+        query.archive_job_id(),
+    ) {
+        (Err(e), _) => Err(e),
+        (Ok(()), Some(archive_job_id)) => {
+            admin::retrigger_download(&stores, &user_id, &resource, archive_job_id).await
+        }
+        (Ok(()), None) => admin::retrigger_archive_initiation(&stores, &user_id, &resource).await,
+    };
+    admin
+        .audit(
+            stores.audit_log.as_ref(),
+            "retry_resource",
+            Some(&user_id),
+            &receipt,
+            request_id.to_string(),
+        )
+        .await;
+    let receipt = receipt?;
+    record(
+        stores.audit_log.as_ref(),
+        admin
+            .audit_event(
+                &user_id,
+                AuditAction::ResourceRetried,
+                request_id.to_string(),
+            )
+            .with_resource(resource)
+            .with_receipt(&receipt),
+    )
+    .await;
+    Ok(HttpResponse::Ok().json(receipt))
+}
+
+pub async fn post_admin_revoke_api(
+    admin: AdminUser,
+    user_id: Path<UserId>,
+    query: Query<RevokeAuthorizationQuery>,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    let receipt = match admin.require(AdminRole::Operator, "revoke authorizations") {
+        Ok(()) => revoke_authorization(&stores, &user_id, query.delete_data()).await,
+        Err(e) => Err(e),
+    };
+    admin
+        .audit(
+            stores.audit_log.as_ref(),
+            "revoke_authorization",
+            Some(&user_id),
+            &receipt,
+            request_id.to_string(),
+        )
+        .await;
+    let receipt = receipt?;
+    record(
+        stores.audit_log.as_ref(),
+        admin
+            .audit_event(
+                &user_id,
+                AuditAction::AuthorizationRevoked,
+                request_id.to_string(),
+            )
+            .with_receipt(&receipt),
+    )
+    .await;
+    Ok(HttpResponse::Ok().json(receipt))
+}
+
+pub async fn get_admin_stats_api(
+    admin: AdminUser,
+    stores: Data<UserDataStores>,
+    request_id: RequestId,
+) -> PapiResult<HttpResponse> {
+    // only aggregates, which are not the data of any user, so no read is recorded
+    let stats = admin::stats(&stores).await;
+    admin
+        .audit(
+            stores.audit_log.as_ref(),
+            "get_stats",
+            None,
+            &stats,
+            request_id.to_string(),
+        )
+        .await;
+    Ok(HttpResponse::Ok().json(stats?))
+}
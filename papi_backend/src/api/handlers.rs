@@ -1,168 +1,815 @@
-use super::types::{AuthorizationCodeRequestPayload, OAuthInfo, ResourceState, UserStateMap};
+use super::types::{
+    AuthorizationCodeRequestPayload, AuthorizationUrlQuery, OAuthInfo, OAuthState,
+    PendingAuthorization, Pkce, Resource, ResourceState, RevocationReceipt,
+    RevokeAuthorizationQuery, UserAuditLog, UserId,
+};
 use crate::{
     api::{
-        api::DATA_PORTABILITY_BASE_URL,
+        api::{DATA_PORTABILITY_BASE_URL, IDENTITY_SCOPES},
         types::{AuthorizationParams, AuthorizationUrl},
     },
+    audit_log::{record, Actor, AuditAction, AuditEvent, AuditLog},
     auth_db_client::AuthDbClient,
-    oauth_client::OAuthClient,
+    config::{AccountSwitchingPolicy, Config, GoogleConfig},
+    consent_ledger::{
+        ConsentLedger, ConsentPolicy, ConsentRecord, ConsentStatus, ProcessingPurpose,
+    },
+    erasure::{erase_user, revoke_authorization, ErasureReceipt, UserDataStores},
+    error::{PapiError, PapiResult},
+    export::UserExport,
+    job_queue::{DownloadInfo, InitiationInfo, Job, JobQueue, PollingInfo},
+    metrics::{outcome, METRICS},
+    oauth_client::{code_already_exchanged, OAuthClient},
+    oauth_state_store::OAuthStateStore,
     papi_line_client::PapiLineClient,
-    REQUESTED_RESOURCES,
+    session::{AuthenticatedUser, SessionDelivery, SessionManager},
+    worker_pool::{JobContext, UserLocks},
 };
 use actix_web::{
-    web::{Data, Json},
+    web::{Bytes, Data, Json, Query},
     HttpRequest,
 };
-use std::env;
-use tokio::sync::mpsc::UnboundedSender;
+use futures::{future, stream, Stream, StreamExt};
+use std::io;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
 use uuid::Uuid;
 
-fn get_user_id(req: HttpRequest) -> Result<String, String> {
-    let user_id = req
-        .headers()
-        .get("X-Client-Id")
-        .ok_or("Missing X-Client-Id header")?
-        .to_str()
-        .map_err(|e| format!("Invalid X-Client-Id header: {}", e))?
-        .to_string();
+/// Renews the session sent with the request, or creates a new user if none was sent
+pub async fn create_session(
+    req: &HttpRequest,
+    session_manager: &SessionManager,
+    delivery: SessionDelivery,
+) -> PapiResult<(UserId, String)> {
+    let user_id = if session_manager.sends_session(req, delivery) {
+        // an expired or revoked session is reported rather than replaced by a new user
+        AuthenticatedUser::authenticate(req).await?.user_id()
+    } else {
+        Uuid::new_v4().to_string()
+    };
+    let token = session_manager.issue(user_id.clone())?;
+
+    info!(user_id = %user_id, "Issued session");
 
-    Ok(user_id)
+    Ok((user_id, token))
 }
 
-pub fn get_google_oauth_url(req: HttpRequest, auth: Data<UserStateMap>) -> Result<String, String> {
-    let user_id = get_user_id(req)?;
+/// Returns the state of the last authorization of the user along with the resources it left out
+async fn find_missing_resources(
+    auth_db_client: &dyn AuthDbClient,
+    user_id: &UserId,
+    requested_resources: &[Resource],
+) -> PapiResult<(OAuthState, Vec<Resource>)> {
+    let oauth_info = match auth_db_client
+        .read_last_auth_for_user(user_id.clone())
+        .await
+    {
+        Ok(oauth_info) => oauth_info,
+        Err(PapiError::NotFound(_)) => {
+            return Err(PapiError::InvalidState(format!(
+                "User with ID: {} has no authorization to extend",
+                user_id
+            )))
+        }
+        Err(e) => return Err(e.context("could not read last auth for user")),
+    };
+    if oauth_info.is_revoked() || oauth_info.access_token().is_none() {
+        return Err(PapiError::InvalidState(format!(
+            "Last authorization of user with ID: {} is revoked or not exchanged yet, the user must authorize again",
+            user_id
+        )));
+    }
+    let missing_resources = oauth_info.missing_resources(requested_resources);
+    if missing_resources.is_empty() {
+        return Err(PapiError::InvalidState(format!(
+            "User with ID: {} already granted all the requested resources",
+            user_id
+        )));
+    }
+    Ok((oauth_info.state(), missing_resources))
+}
+
+/// Returns the authorization URL along with the resources it asks for
+#[instrument(name = "authorization", skip_all, fields(user_id = %user.user_id()))]
+pub async fn get_google_oauth_url(
+    user: AuthenticatedUser,
+    query: Query<AuthorizationUrlQuery>,
+    auth: Data<dyn OAuthStateStore>,
+    auth_db_client: Data<dyn AuthDbClient>,
+    consent_ledger: Data<dyn ConsentLedger>,
+    config: Data<Config>,
+) -> PapiResult<(String, Vec<Resource>)> {
+    let user_id = user.user_id();
+
+    // Google keeps the scopes granted earlier, so only the missing ones need asking
+    let (extended_state, requested_resources) = if query.incremental() {
+        let (extended_state, missing_resources) = find_missing_resources(
+            auth_db_client.as_ref(),
+            &user_id,
+            &config.google.requested_resources,
+        )
+        .await?;
+        (Some(extended_state), missing_resources)
+    } else {
+        (None, config.google.requested_resources.clone())
+    };
 
     let oauth_state = Uuid::new_v4().to_string();
+    let pkce = Pkce::generate();
 
-    let params = AuthorizationParams::default()
+    let params = AuthorizationParams::new(config.google.client_id.clone())
         .with_state(oauth_state.clone())
+        .with_code_challenge(&pkce)
         .with_scope(
-            REQUESTED_RESOURCES
+            requested_resources
+                .iter()
                 .map(|r| format!("{}{}", DATA_PORTABILITY_BASE_URL, r))
-                .join("+"),
+                .chain(IDENTITY_SCOPES.map(String::from))
+                .collect::<Vec<String>>()
+                .join(" "),
         )
-        .with_redirect_uri(env::var("REDIRECT_URI").map_err(|_| "REDIRECT_URI must be set")?);
+        .with_redirect_uri(config.google.redirect_uri.clone());
 
-    let auth_url = AuthorizationUrl::new(params).as_url();
+    let auth_url = AuthorizationUrl::new(&config.google.accounts_base_url, params).as_url()?;
 
-    println!(
-        "User with ID: {} requested authorization URL: {}",
-        user_id, auth_url
+    info!(
+        incremental = query.incremental(),
+        "Requested authorization URL"
     );
+    METRICS
+        .authorization_attempts
+        .with_label_values(&["started"])
+        .inc();
 
-    auth.write()
-        .map_err(|e| format!("Lock is poisoned: {}", e))?
-        .insert(user_id, oauth_state);
+    // what the user is asked to consent to is recorded before they are sent to Google
+    consent_ledger
+        .record_request(ConsentRecord::new(
+            oauth_state.clone(),
+            user_id.clone(),
+            &config.consent,
+            requested_resources.clone(),
+        ))
+        .await
+        .map_err(|e| e.context("could not record consent request"))?;
+
+    let mut pending_authorization =
+        PendingAuthorization::new(oauth_state, pkce, config.google.oauth_state_ttl);
+    if let Some(extended_state) = extended_state {
+        pending_authorization = pending_authorization.with_extended_state(extended_state);
+    }
+    auth.insert(&user_id, pending_authorization)
+        .await
+        .map_err(|e| e.context("could not store pending authorization"))?;
 
-    Ok(auth_url)
+    Ok((auth_url, requested_resources))
 }
 
+#[instrument(name = "authorization_code", skip_all, fields(user_id = %user.user_id()))]
 pub async fn post_google_authorization_code(
-    req: HttpRequest,
+    user: AuthenticatedUser,
     payload: Json<AuthorizationCodeRequestPayload>,
-    auth: Data<UserStateMap>,
-    authorization_tx: Data<UnboundedSender<OAuthInfo>>,
-) -> Result<(), String> {
-    let user_id = get_user_id(req)?;
-
-    if let Some(oauth_state) = auth
-        .write()
-        .map_err(|e| format!("Lock is poisoned: {}", e))?
-        .remove(&user_id)
-    {
-        if oauth_state != payload.state() {
-            return Err(format!(
-                "User with ID: {} sent invalid state. Expected: {}, got: {}",
-                user_id,
-                oauth_state,
-                payload.state()
-            ));
-        }
+    auth: Data<dyn OAuthStateStore>,
+    job_queue: Data<dyn JobQueue>,
+    audit_log: Data<dyn AuditLog>,
+    request_id: String,
+) -> PapiResult<()> {
+    let user_id = user.user_id();
+
+    let Some(pending_authorization) = auth
+        .take(&user_id, &payload.state())
+        .await
+        .map_err(|e| e.context("could not read pending authorization"))?
+    else {
+        METRICS
+            .authorization_attempts
+            .with_label_values(&["invalid_state"])
+            .inc();
+        return Err(PapiError::InvalidRequest(format!(
+            "User with ID: {} sent unknown or expired state: {}",
+            user_id,
+            payload.state()
+        )));
+    };
+
+    info!("Posted authorization code");
+    METRICS
+        .authorization_attempts
+        .with_label_values(&["code_received"])
+        .inc();
 
-        let oauth_code = payload.code();
+    let mut oauth_info = OAuthInfo::new(
+        user_id.clone(),
+        pending_authorization.state(),
+        payload.code(),
+        pending_authorization.pkce().code_verifier(),
+    );
+    if let Some(extended_state) = pending_authorization.extended_state() {
+        oauth_info = oauth_info.with_extended_state(extended_state);
+    }
 
-        println!(
-            "User with ID: {} posted authorization code: {}",
-            user_id, oauth_code
-        );
+    job_queue
+        .enqueue(Job::TokenExchange(oauth_info))
+        .await
+        .map_err(|e| e.context("could not enqueue token exchange job"))?;
+    record(
+        audit_log.as_ref(),
+        AuditEvent::new(user_id, Actor::User, AuditAction::AuthorizationGranted)
+            .with_request_id(request_id),
+    )
+    .await;
 
-        let oauth_info = OAuthInfo::new(user_id, oauth_state, oauth_code);
+    Ok(())
+}
 
-        authorization_tx.send(oauth_info).unwrap();
+/// Checks the Google account which granted the authorization against the ones previously
+/// connected, according to the account switching policy
+async fn check_google_account(
+    auth_db_client: &dyn AuthDbClient,
+    account_switching_policy: AccountSwitchingPolicy,
+    oauth_info: &OAuthInfo,
+) -> PapiResult<()> {
+    let user_id = oauth_info.user_id();
+    let google_sub = oauth_info.google_sub().ok_or(PapiError::InvalidState(
+        "Google account not found".to_string(),
+    ))?;
 
+    let previous_google_sub = match auth_db_client
+        .read_last_auth_for_user(user_id.clone())
+        .await
+    {
+        Ok(previous_oauth_info) => previous_oauth_info.google_sub(),
+        Err(PapiError::NotFound(_)) => None,
+        Err(e) => return Err(e.context("could not read previous oauth info")),
+    };
+    let is_switching_account = previous_google_sub.is_some_and(|sub| sub != google_sub);
+
+    let mut other_users = auth_db_client
+        .find_users_by_google_sub(&google_sub)
+        .await
+        .map_err(|e| e.context("could not look up Google account"))?;
+    other_users.remove(&user_id);
+
+    if !is_switching_account && other_users.is_empty() {
         return Ok(());
     }
 
-    Err(format!("User with ID: {} not found", user_id))
+    match account_switching_policy {
+        AccountSwitchingPolicy::Reject if is_switching_account => {
+            Err(PapiError::InvalidState(format!(
+                "User with ID: {} already connected a different Google account",
+                user_id
+            )))
+        }
+        AccountSwitchingPolicy::Reject => Err(PapiError::InvalidState(format!(
+            "Google account {:?} of user with ID: {} is already connected by another user",
+            oauth_info.email(),
+            user_id
+        ))),
+        AccountSwitchingPolicy::Allow => {
+            info!(
+                email = ?oauth_info.email(),
+                is_switching_account,
+                also_connected_by = ?other_users,
+                "Accepted Google account"
+            );
+            Ok(())
+        }
+    }
 }
 
-pub async fn handle_data_archive(
-    auth_db_client: &AuthDbClient,
-    oauth_client: &OAuthClient,
-    mut oauth_info: OAuthInfo,
-) -> Result<(), String> {
+#[instrument(name = "revocation", skip_all, fields(user_id = %user.user_id()))]
+pub async fn revoke_google_authorization(
+    user: AuthenticatedUser,
+    query: Query<RevokeAuthorizationQuery>,
+    stores: Data<UserDataStores>,
+    request_id: String,
+) -> PapiResult<RevocationReceipt> {
+    let user_id = user.user_id();
+    let receipt = revoke_authorization(&stores, &user_id, query.delete_data()).await?;
+    record(
+        stores.audit_log.as_ref(),
+        AuditEvent::new(user_id, Actor::User, AuditAction::AuthorizationRevoked)
+            .with_request_id(request_id)
+            .with_receipt(&receipt),
+    )
+    .await;
+    Ok(receipt)
+}
+
+pub async fn erase_user_data(
+    user: AuthenticatedUser,
+    stores: Data<UserDataStores>,
+    request_id: String,
+) -> PapiResult<ErasureReceipt> {
+    let user_id = user.user_id();
+    let receipt = erase_user(&stores, &user_id)
+        .await
+        .map_err(|e| e.context("could not erase user data"))?;
+    record(
+        stores.audit_log.as_ref(),
+        AuditEvent::new(user_id, Actor::User, AuditAction::UserErased)
+            .with_request_id(request_id)
+            .with_receipt(&receipt),
+    )
+    .await;
+    Ok(receipt)
+}
+
+// size of the buffer between the archive being written and the response being sent
+const EXPORT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Starts writing the export of a user in the background, returning the archive as it is written
+pub async fn export_user_data(
+    user: AuthenticatedUser,
+    stores: Data<UserDataStores>,
+    request_id: String,
+) -> PapiResult<impl Stream<Item = io::Result<Bytes>>> {
+    let user_id = user.user_id();
+    let export = UserExport::prepare(&stores, &user_id)
+        .await
+        .map_err(|e| e.context("could not prepare user export"))?;
+    // no data is sent unless the export is recorded
+    stores
+        .audit_log
+        .append(
+            AuditEvent::new(user_id.clone(), Actor::User, AuditAction::DataExported)
+                .with_request_id(request_id),
+        )
+        .await
+        .map_err(|e| e.context("could not record user export"))?;
+
+    let (writer, reader) = tokio::io::duplex(EXPORT_BUFFER_SIZE);
+    let stores = stores.into_inner();
+    let written = tokio::spawn(
+        async move {
+            let res = export.write_zip(stores, writer).await;
+            if let Err(e) = &res {
+                error!(error = %e, "Error exporting user data");
+            }
+            res
+        }
+        .instrument(info_span!("export", user_id = %user_id)),
+    );
+
+    // the response has already started when the export fails, so the stream ends with an error
+    // for the connection to be aborted, rather than leaving a truncated archive as if complete
+    let failure = stream::once(async move {
+        match written.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(Err(io::Error::other(e.to_string()))),
+            Err(e) => Some(Err(io::Error::other(e.to_string()))),
+        }
+    })
+    .filter_map(future::ready);
+    Ok(ReaderStream::new(reader).chain(failure))
+}
+
+#[instrument(name = "token_exchange", skip_all, fields(user_id = %oauth_info.user_id()))]
+pub async fn handle_token_exchange(context: &JobContext, oauth_info: OAuthInfo) -> PapiResult<()> {
+    let res = exchange_token(context, oauth_info).await;
+    let outcome = match &res {
+        // the Google account was refused by the account switching policy
+        Err(PapiError::InvalidState(_)) => "rejected",
+        res => outcome(res),
+    };
+    METRICS.token_exchanges.with_label_values(&[outcome]).inc();
+    res
+}
+
+async fn exchange_token(context: &JobContext, mut oauth_info: OAuthInfo) -> PapiResult<()> {
     // convert authorization code to access token
-    oauth_client
+    context
+        .oauth_client
         .convert_authorization_to_access_token(&mut oauth_info)
         .await
-        .map_err(|e| {
-            format!(
-                "could not convert authorization code to access token: {}",
-                e
-            )
-        })?;
+        .map_err(|e| e.context("could not convert authorization code to access token"))?;
+
+    // the code cannot be exchanged again, so the job is not retried past this point
+    store_access_token(context, oauth_info)
+        .await
+        .map_err(code_already_exchanged)
+}
+
+async fn store_access_token(context: &JobContext, mut oauth_info: OAuthInfo) -> PapiResult<()> {
+    let auth_db_client = context.auth_db_client.as_ref();
+    let oauth_client = context.oauth_client.as_ref();
+    let google_config = &context.config.google;
+
+    let user_id = oauth_info.user_id();
+
+    if let Err(e) = check_google_account(
+        auth_db_client,
+        google_config.account_switching_policy,
+        &oauth_info,
+    )
+    .await
+    {
+        // the rejected authorization must not stay granted on Google's side either
+        if matches!(e, PapiError::InvalidState(_)) {
+            if let Err(reset_error) = oauth_client.reset_authorization(&oauth_info).await {
+                warn!(error = %reset_error, "Error resetting rejected authorization");
+            }
+        }
+        return Err(e);
+    }
 
-    oauth_client
-        .initiate_data_archives(&mut oauth_info)
-        .map_err(|e| format!("could not initialize data archives: {}", e))?;
+    // the progress carried over must not be updated by a download of the extended authorization
+    // before the new one replaces it
+    let user_lock = context.user_locks.lock(&user_id).await;
+    if let Some(extended_state) = oauth_info.extended_state() {
+        carry_over_progress(auth_db_client, &mut oauth_info, &extended_state).await?;
+    }
+    oauth_info.mark_not_granted(&google_config.requested_resources);
+    let granted_resources = oauth_info.accessible_resources();
+    let not_granted_resources = oauth_info.resources_in_state(&ResourceState::NotGranted);
+    let resources_to_initiate = oauth_info.resources_in_state(&ResourceState::Granted);
 
-    print!("OAuth info pre store: {:?}", oauth_info);
+    info!(
+        email = ?oauth_info.email(),
+        not_granted = ?not_granted_resources,
+        "Storing OAuth info"
+    );
+    match context
+        .consent_ledger
+        .record_grant(&oauth_info.state(), granted_resources.clone())
+        .await
+    {
+        Ok(()) => {}
+        // the data will not be used until the user consents again
+        Err(PapiError::NotFound(_)) => warn!("No consent request recorded for the authorization"),
+        Err(e) => return Err(e.context("could not record consent grant")),
+    }
     auth_db_client
         .create_auth(oauth_info)
         .await
-        .map_err(|e| format!("could not store oauth info: {}", e))?;
+        .map_err(|e| e.context("could not store oauth info"))?;
+    drop(user_lock);
+    let mut details = format!("granted resources: {}", granted_resources.join(", "));
+    if !not_granted_resources.is_empty() {
+        details.push_str(&format!(
+            "; not granted: {}",
+            not_granted_resources.join(", ")
+        ));
+    }
+    record(
+        context.audit_log.as_ref(),
+        AuditEvent::new(user_id.clone(), Actor::System, AuditAction::TokenExchanged)
+            .with_details(details),
+    )
+    .await;
+
+    for resource in &resources_to_initiate {
+        context
+            .job_queue
+            .enqueue(Job::ArchiveInitiation(InitiationInfo::new(
+                user_id.clone(),
+                resource.clone(),
+            )))
+            .await
+            .map_err(|e| e.context("could not enqueue archive initiation job"))?;
+    }
 
     Ok(())
 }
 
+/// Keeps what the extended authorization already archived, as long as it is still the last one
+/// of the user and was granted by the same Google account
+async fn carry_over_progress(
+    auth_db_client: &dyn AuthDbClient,
+    oauth_info: &mut OAuthInfo,
+    extended_state: &OAuthState,
+) -> PapiResult<()> {
+    let extended = match auth_db_client
+        .read_last_auth_for_user(oauth_info.user_id())
+        .await
+    {
+        Ok(extended) => extended,
+        Err(PapiError::NotFound(_)) => return Ok(()),
+        Err(e) => return Err(e.context("could not read extended oauth info")),
+    };
+    if extended.state() != *extended_state
+        || extended.is_revoked()
+        || extended.google_sub() != oauth_info.google_sub()
+    {
+        info!("Extended authorization replaced or revoked, archiving all granted resources");
+        return Ok(());
+    }
+    oauth_info.carry_over_progress(&extended);
+    Ok(())
+}
+
+#[instrument(
+    name = "archive_initiation",
+    skip_all,
+    fields(user_id = %initiation_info.user_id(), resource = %initiation_info.resource())
+)]
+pub async fn handle_archive_initiation(
+    auth_db_client: &dyn AuthDbClient,
+    oauth_client: &OAuthClient,
+    job_queue: &dyn JobQueue,
+    audit_log: &dyn AuditLog,
+    consent_policy: ConsentPolicy<'_>,
+    user_locks: &UserLocks,
+    initiation_info: InitiationInfo,
+) -> PapiResult<()> {
+    let user_id = initiation_info.user_id();
+    let resource = initiation_info.resource();
+
+    let oauth_info = auth_db_client
+        .read_last_auth_for_user(user_id.clone())
+        .await
+        .map_err(|e| e.context("could not read last auth for user"))?;
+
+    // the job might be delivered again after the archive has already been initiated
+    if oauth_info
+        .validate_access_token(&resource, &ResourceState::Granted)
+        .is_err()
+    {
+        info!("Skipping initiation: access token expired or resource already initiated");
+        return Ok(());
+    }
+    if let Err(e) = consent_policy
+        .check(&oauth_info, ProcessingPurpose::Archiving, &resource)
+        .await
+    {
+        if !matches!(e, PapiError::InvalidState(_)) {
+            return Err(e);
+        }
+        info!(reason = %e, "Skipping initiation: no consent to archiving the resource");
+        return Ok(());
+    }
+
+    let job_id = oauth_client
+        .initiate_data_archive(&oauth_info, &resource)
+        .await
+        .map_err(|e| e.context("could not initiate data archive"))?;
+
+    {
+        let _user_lock = user_locks.lock(&user_id).await;
+        let mut oauth_info = auth_db_client
+            .read_last_auth_for_user(user_id.clone())
+            .await
+            .map_err(|e| e.context("could not read last auth for user"))?;
+        oauth_info
+            .update_granted_resource_state(&resource, ResourceState::Initiated)
+            .map_err(|e| e.context("could not update resource state"))?;
+        auth_db_client
+            .update_auth_for_user(user_id.clone(), oauth_info)
+            .await
+            .map_err(|e| e.context("could not store updated OAuth info"))?;
+    }
+    record(
+        audit_log,
+        AuditEvent::new(
+            user_id.clone(),
+            Actor::System,
+            AuditAction::ArchiveInitiated,
+        )
+        .with_resource(resource.clone())
+        .with_details(format!("archive job: {}", job_id)),
+    )
+    .await;
+
+    job_queue
+        .enqueue(Job::ArchivePolling(PollingInfo::new(
+            user_id, resource, job_id,
+        )))
+        .await
+        .map(|_| ())
+        .map_err(|e| e.context("could not enqueue archive polling job"))
+}
+
+/// Checks the state of an archive job, enqueueing its download once it is ready and another check
+/// otherwise.
+///
+/// Every check is a job of its own, so that the polling survives restarts of the backend.
+#[instrument(
+    name = "archive_polling",
+    skip_all,
+    fields(
+        user_id = %polling_info.user_id(),
+        resource = %polling_info.resource(),
+        archive_job_id = %polling_info.archive_job_id(),
+    )
+)]
+pub async fn handle_archive_polling(
+    auth_db_client: &dyn AuthDbClient,
+    oauth_client: &OAuthClient,
+    job_queue: &dyn JobQueue,
+    google_config: &GoogleConfig,
+    polling_info: PollingInfo,
+) -> PapiResult<()> {
+    let user_id = polling_info.user_id();
+    let resource = polling_info.resource();
+
+    let oauth_info = auth_db_client
+        .read_last_auth_for_user(polling_info.user_id())
+        .await
+        .map_err(|e| e.context("could not read last auth for user"))?;
+
+    if oauth_info
+        .validate_initalized_access_token(&resource)
+        .is_err()
+    {
+        info!("Skipping polling: authorization revoked, access token expired or resource already downloaded");
+        observe_archive_job(&polling_info, None);
+        return Ok(());
+    }
+
+    let res = oauth_client
+        .poll_archive_state(&oauth_info, &resource, polling_info.archive_job_id())
+        .await;
+    let download_url = match res {
+        Ok(None) => {
+            let mut polling_info = polling_info;
+            polling_info.record_polling_start();
+            let timeout = google_config.archive_polling_timeout;
+            if polling_info
+                .polling_duration()
+                .is_some_and(|polling_duration| polling_duration >= timeout.as_millis() as i64)
+            {
+                observe_archive_job(&polling_info, Some("timed_out"));
+                return Err(PapiError::UpstreamPermanent(format!(
+                    "Job with ID {} still not ready after {}s",
+                    polling_info.archive_job_id(),
+                    timeout.as_secs()
+                )));
+            }
+            return job_queue
+                .enqueue_after(
+                    Job::ArchivePolling(polling_info),
+                    google_config.archive_poll_interval,
+                )
+                .await
+                .map(|_| ())
+                .map_err(|e| e.context("could not enqueue archive polling job"));
+        }
+        Ok(Some(download_url)) => download_url,
+        Err(e) => {
+            // a transient failure is retried by the job queue
+            if !e.is_retryable() {
+                observe_archive_job(&polling_info, Some("failed"));
+            }
+            return Err(e.context("could not poll archive state"));
+        }
+    };
+    observe_archive_job(&polling_info, Some("completed"));
+
+    job_queue
+        .enqueue(Job::DataDownload(DownloadInfo::new(
+            user_id,
+            resource,
+            polling_info.archive_job_id(),
+            Ok(download_url),
+        )))
+        .await
+        .map(|_| ())
+        .map_err(|e| e.context("could not enqueue data download job"))
+}
+
+/// Records the duration of the polling of an archive job, along with its outcome, unless it was
+/// given up
+fn observe_archive_job(polling_info: &PollingInfo, outcome: Option<&str>) {
+    let resource = polling_info.resource();
+    if let (Some(outcome), Some(polling_duration)) = (outcome, polling_info.polling_duration()) {
+        METRICS
+            .archive_job_duration
+            .with_label_values(&[resource.as_str(), outcome])
+            .observe(polling_duration as f64 / 1000.0);
+    }
+}
+
+#[instrument(
+    name = "data_download",
+    skip_all,
+    fields(
+        user_id = %download_info.user_id(),
+        resource = %download_info.resource(),
+        archive_job_id = download_info.archive_job_id().as_deref(),
+    )
+)]
 pub async fn handle_data_download(
-    auth_db_client: &AuthDbClient,
+    auth_db_client: &dyn AuthDbClient,
     papi_line_client: &PapiLineClient,
     oauth_client: &OAuthClient,
-    user_id: String,
-    ready_to_download_resource: String,
-    resource_res: Result<String, String>,
-) -> Result<(), String> {
-    let download_url = resource_res.map_err(|e| format!("could not get download URL: {:?}", e))?;
-    let mut oauth_info = auth_db_client
+    audit_log: &dyn AuditLog,
+    consent_policy: ConsentPolicy<'_>,
+    user_locks: &UserLocks,
+    download_info: DownloadInfo,
+) -> PapiResult<()> {
+    let user_id = download_info.user_id();
+    let ready_to_download_resource = download_info.resource();
+    let download_url = download_info.download_url().map_err(|e| {
+        // the archive job failed upstream, downloading it again will not help
+        PapiError::UpstreamPermanent(format!("could not get download URL: {}", e))
+    })?;
+
+    // the lock is held until the files are stored, so that a revocation or an erasure waits for
+    // the download instead of deleting the files before they are written. Other resources of the
+    // same user are downloaded one after the other
+    let _user_lock = user_locks.lock(&user_id).await;
+    let mut oauth_info = match auth_db_client
         .read_last_auth_for_user(user_id.clone())
         .await
-        .map_err(|e| format!("could not read last auth for user: {:?}", e))?;
+    {
+        Ok(oauth_info) => oauth_info,
+        // the polling started before the user was erased
+        Err(PapiError::NotFound(_)) => {
+            info!("Skipping download: user erased");
+            return Ok(());
+        }
+        Err(e) => return Err(e.context("could not read last auth for user")),
+    };
+
+    // the polling started before the user revoked the authorization
+    if oauth_info.is_revoked() {
+        info!("Skipping download: authorization revoked");
+        return Ok(());
+    }
 
     oauth_info.validate_initalized_access_token(&ready_to_download_resource)?;
+    if let Err(e) = consent_policy
+        .check(
+            &oauth_info,
+            ProcessingPurpose::Archiving,
+            &ready_to_download_resource,
+        )
+        .await
+    {
+        if !matches!(e, PapiError::InvalidState(_)) {
+            return Err(e);
+        }
+        info!(reason = %e, "Skipping download: no consent to archiving the resource");
+        return Ok(());
+    }
 
     papi_line_client
-        .download_file(user_id.clone(), &ready_to_download_resource, &download_url)
+        .download_file(
+            user_id.clone(),
+            &ready_to_download_resource,
+            download_info.archive_job_id(),
+            &download_url,
+        )
         .await
-        .map_err(|e| format!("could not download file: {:?}", e))?;
+        .map_err(|e| e.context("could not download file"))?;
 
     oauth_info
         .update_granted_resource_state(&ready_to_download_resource, ResourceState::Downloaded)
-        .map_err(|e| format!("could not update resource state: {:?}", e))?;
-    if oauth_info.is_all_resources_downloaded() {
-        println!(
-            "All resources downloaded, resetting authorization for user {}",
-            user_id
-        );
+        .map_err(|e| e.context("could not update resource state"))?;
+    let is_all_resources_downloaded = oauth_info.is_all_resources_downloaded();
+    if is_all_resources_downloaded {
+        info!("All resources downloaded, resetting authorization");
         oauth_client
-            .reset_authorization(&mut oauth_info)
+            .reset_authorization(&oauth_info)
             .await
-            .map_err(|e| format!("could not reset authorization: {:?}", e))?;
+            .map_err(|e| e.context("could not reset authorization"))?;
     }
     auth_db_client
-        .update_auth_for_user(user_id, oauth_info)
+        .update_auth_for_user(user_id.clone(), oauth_info)
         .await
-        .map_err(|e| format!("could not store updated OAuth info: {:?}", e))?;
+        .map_err(|e| e.context("could not store updated OAuth info"))?;
+
+    let mut downloaded =
+        AuditEvent::new(user_id.clone(), Actor::System, AuditAction::DataDownloaded)
+            .with_resource(ready_to_download_resource);
+    if let Some(archive_job_id) = download_info.archive_job_id() {
+        downloaded = downloaded.with_details(format!("archive job: {}", archive_job_id));
+    }
+    record(audit_log, downloaded).await;
+    if is_all_resources_downloaded {
+        record(
+            audit_log,
+            AuditEvent::new(user_id, Actor::System, AuditAction::AuthorizationReset)
+                .with_details("all resources downloaded".to_string()),
+        )
+        .await;
+    }
 
     Ok(())
 }
+
+/// Returns what was done with the data of the user, and by whom
+pub async fn get_user_audit_log(
+    user: AuthenticatedUser,
+    audit_log: Data<dyn AuditLog>,
+) -> PapiResult<UserAuditLog> {
+    let user_id = user.user_id();
+    let events = audit_log
+        .list_user_events(&user_id)
+        .await
+        .map_err(|e| e.context("could not read audit log"))?;
+    Ok(UserAuditLog::new(user_id, events))
+}
+
+/// Returns the consents given by the user, telling whether they must consent again
+pub async fn get_user_consents(
+    user: AuthenticatedUser,
+    consent_ledger: Data<dyn ConsentLedger>,
+    config: Data<Config>,
+) -> PapiResult<ConsentStatus> {
+    let consents = consent_ledger
+        .list_user_consents(&user.user_id())
+        .await
+        .map_err(|e| e.context("could not read consents"))?;
+    Ok(ConsentStatus::new(&config.consent, consents))
+}
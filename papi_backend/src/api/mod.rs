@@ -1,14 +1,131 @@
-use actix_web::web;
-use api::{get_auth_api, post_auth_api};
+use actix_web::web::{self, Data};
+use api::{
+    delete_auth_api, delete_user_api, get_admin_stats_api, get_admin_user_api,
+    get_admin_user_jobs_api, get_admin_users_api, get_healthz_api, get_metrics_api, get_readyz_api,
+    get_user_audit_log_api, get_user_consents_api, get_user_export_api,
+    post_admin_retry_resource_api, post_admin_revoke_api, post_auth_api, post_auth_url_api,
+    post_session_api, post_session_token_api,
+};
+use std::sync::Arc;
 
+use crate::{
+    audit_log::AuditLog,
+    auth_db_client::AuthDbClient,
+    config::Config,
+    consent_ledger::ConsentLedger,
+    erasure::UserDataStores,
+    job_queue::JobQueue,
+    oauth_client::OAuthClient,
+    oauth_state_store::OAuthStateStore,
+    papi_line_client::PapiLineClient,
+    session::SessionManager,
+    worker_pool::{UserLocks, WorkerStatus},
+};
+
+#[allow(clippy::module_inception)]
 mod api;
 pub mod handlers;
 pub mod types;
 
 pub fn auth_config(cfg: &mut web::ServiceConfig) {
+    cfg.route("/healthz", web::get().to(get_healthz_api))
+        .route("/readyz", web::get().to(get_readyz_api))
+        .route("/metrics", web::get().to(get_metrics_api))
+        .route("/session", web::post().to(post_session_api))
+        .route("/session/token", web::post().to(post_session_token_api))
+        .route("/user", web::delete().to(delete_user_api))
+        .route("/user/export", web::get().to(get_user_export_api))
+        .route("/user/audit-log", web::get().to(get_user_audit_log_api))
+        .route("/user/consents", web::get().to(get_user_consents_api));
     cfg.service(
         web::scope("/auth")
-            .route("", web::get().to(get_auth_api))
-            .route("", web::post().to(post_auth_api)),
+            .route("", web::post().to(post_auth_api))
+            .route("", web::delete().to(delete_auth_api))
+            .route("/url", web::post().to(post_auth_url_api)),
+    );
+    // authenticated with admin API keys rather than user sessions
+    cfg.service(
+        web::scope("/admin")
+            .route("/stats", web::get().to(get_admin_stats_api))
+            .route("/users", web::get().to(get_admin_users_api))
+            .route("/users/{user_id}", web::get().to(get_admin_user_api))
+            .route(
+                "/users/{user_id}/jobs",
+                web::get().to(get_admin_user_jobs_api),
+            )
+            .route(
+                "/users/{user_id}/resources/{resource}/retry",
+                web::post().to(post_admin_retry_resource_api),
+            )
+            .route(
+                "/users/{user_id}/revoke",
+                web::post().to(post_admin_revoke_api),
+            ),
     );
 }
+
+/// State shared by the HTTP workers.
+///
+/// App state initialized inside the closure passed to `HttpServer::new` is local to the worker
+/// thread and may become de-synced if modified, so it is created once and cloned into each worker.
+#[derive(Clone)]
+pub struct AppState {
+    oauth_state_store: Data<dyn OAuthStateStore>,
+    job_queue: Data<dyn JobQueue>,
+    config: Data<Config>,
+    session_manager: Data<SessionManager>,
+    oauth_client: Data<OAuthClient>,
+    papi_line_client: Data<PapiLineClient>,
+    auth_db_client: Data<dyn AuthDbClient>,
+    audit_log: Data<dyn AuditLog>,
+    consent_ledger: Data<dyn ConsentLedger>,
+    user_locks: Data<UserLocks>,
+    stores: Data<UserDataStores>,
+    worker_status: Data<WorkerStatus>,
+}
+
+impl AppState {
+    pub fn new(stores: UserDataStores, config: Config) -> Self {
+        Self {
+            oauth_state_store: Data::from(Arc::clone(&stores.oauth_state_store)),
+            job_queue: Data::from(Arc::clone(&stores.job_queue)),
+            session_manager: Data::new(SessionManager::new(
+                &config.session,
+                &config.http.cors_allowed_origins,
+                Arc::clone(&stores.session_store),
+            )),
+            config: Data::new(config),
+            oauth_client: Data::from(Arc::clone(&stores.oauth_client)),
+            papi_line_client: Data::from(Arc::clone(&stores.papi_line_client)),
+            auth_db_client: Data::from(Arc::clone(&stores.auth_db_client)),
+            audit_log: Data::from(Arc::clone(&stores.audit_log)),
+            consent_ledger: Data::from(Arc::clone(&stores.consent_ledger)),
+            user_locks: Data::from(Arc::clone(&stores.user_locks)),
+            stores: Data::new(stores),
+            // not ready until the worker pool processing the jobs is attached
+            worker_status: Data::new(WorkerStatus::default()),
+        }
+    }
+
+    pub fn with_worker_status(mut self, worker_status: Arc<WorkerStatus>) -> Self {
+        self.worker_status = Data::from(worker_status);
+        self
+    }
+
+    /// Registers the shared state and the routes
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(Data::clone(&self.oauth_state_store))
+            .app_data(Data::clone(&self.job_queue))
+            .app_data(Data::clone(&self.config))
+            .app_data(Data::clone(&self.session_manager))
+            .app_data(Data::clone(&self.oauth_client))
+            .app_data(Data::clone(&self.papi_line_client))
+            .app_data(Data::clone(&self.auth_db_client))
+            .app_data(Data::clone(&self.audit_log))
+            .app_data(Data::clone(&self.consent_ledger))
+            .app_data(Data::clone(&self.user_locks))
+            .app_data(Data::clone(&self.stores))
+            .app_data(Data::clone(&self.worker_status));
+        auth_config(cfg);
+    }
+}
@@ -1,9 +1,17 @@
+use crate::{
+    audit_log::AuditEvent,
+    error::{PapiError, PapiResult},
+    query_params::QueryParams,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::Utc;
+use rand::RngCore;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::env;
-use std::{collections::HashMap, sync::RwLock};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::LazyLock};
+use tokio::time::Duration;
+use tracing::warn;
 
 pub type UserId = String;
 
@@ -13,8 +21,15 @@ pub type OAuthCode = String;
 
 pub type AccessToken = String;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub type CodeVerifier = String;
+
+/// Stable identifier of a Google account, the `sub` claim of its ID tokens
+pub type GoogleSubject = String;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ResourceState {
+    /// Requested but left out by the user on the consent screen
+    NotGranted,
     Granted,
     Initiated,
     Downloaded,
@@ -38,11 +53,14 @@ impl OAuthAccessToken {
         &self,
         resource: &str,
         expected_resource_state: &ResourceState,
-    ) -> Result<bool, String> {
+    ) -> PapiResult<bool> {
         let resource_state = self
             .granted_resources
             .get(resource)
-            .ok_or(format!("Resource '{:?}' not found", resource))?;
+            .ok_or(PapiError::NotFound(format!(
+                "Resource '{:?}' not found",
+                resource
+            )))?;
 
         Ok(resource_state == expected_resource_state)
     }
@@ -51,12 +69,21 @@ impl OAuthAccessToken {
         &mut self,
         resource: &str,
         new_resource_state: ResourceState,
-    ) -> Result<(), String> {
+    ) -> PapiResult<()> {
         if let Some(resource_state) = self.granted_resources.get_mut(resource) {
+            if *resource_state == ResourceState::NotGranted {
+                return Err(PapiError::InvalidState(format!(
+                    "Resource '{:?}' not granted",
+                    resource
+                )));
+            }
             *resource_state = new_resource_state;
             Ok(())
         } else {
-            Err(format!("Resource '{:?}' not found", resource))
+            Err(PapiError::NotFound(format!(
+                "Resource '{:?}' not found",
+                resource
+            )))
         }
     }
 }
@@ -66,21 +93,51 @@ pub struct OAuthInfo {
     user_id: UserId,
     created_at: i64,
     state: OAuthState,
-    code: OAuthCode,
+    // the code and its verifier are only needed until the code is exchanged, so they are never
+    // stored afterwards
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<OAuthCode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code_verifier: Option<CodeVerifier>,
+    // skipped when missing as it is the key of a DynamoDB index, which cannot be null
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    google_sub: Option<GoogleSubject>,
+    #[serde(default)]
+    email: Option<String>,
     access_token: Option<OAuthAccessToken>,
+    #[serde(default)]
+    revoked_at: Option<i64>,
+    /// State of the authorization this one asks the resources left out of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extended_state: Option<OAuthState>,
 }
 
 impl OAuthInfo {
-    pub fn new(user_id: UserId, state: OAuthState, code: OAuthCode) -> Self {
+    pub fn new(
+        user_id: UserId,
+        state: OAuthState,
+        code: OAuthCode,
+        code_verifier: CodeVerifier,
+    ) -> Self {
         Self {
             user_id,
             created_at: Utc::now().timestamp(),
             state,
-            code,
+            code: Some(code),
+            code_verifier: Some(code_verifier),
+            google_sub: None,
+            email: None,
             access_token: None,
+            revoked_at: None,
+            extended_state: None,
         }
     }
 
+    pub fn with_extended_state(mut self, extended_state: OAuthState) -> Self {
+        self.extended_state = Some(extended_state);
+        self
+    }
+
     pub fn user_id(&self) -> UserId {
         self.user_id.clone()
     }
@@ -89,15 +146,113 @@ impl OAuthInfo {
         self.state.clone()
     }
 
-    pub fn code(&self) -> String {
+    pub fn code(&self) -> Option<OAuthCode> {
         self.code.clone()
     }
 
+    pub fn code_verifier(&self) -> Option<CodeVerifier> {
+        self.code_verifier.clone()
+    }
+
+    pub fn google_sub(&self) -> Option<GoogleSubject> {
+        self.google_sub.clone()
+    }
+
+    pub fn email(&self) -> Option<String> {
+        self.email.clone()
+    }
+
+    pub fn extended_state(&self) -> Option<OAuthState> {
+        self.extended_state.clone()
+    }
+
+    pub fn set_google_account(&mut self, google_sub: GoogleSubject, email: Option<String>) {
+        self.google_sub = Some(google_sub);
+        self.email = email;
+    }
+
+    /// State of each resource granted with the access token, empty until the code is exchanged
+    pub fn granted_resources(&self) -> HashMap<Resource, ResourceState> {
+        self.access_token
+            .as_ref()
+            .map(|a| a.granted_resources.clone())
+            .unwrap_or_default()
+    }
+
+    /// Resources in the given state, sorted
+    pub fn resources_in_state(&self, resource_state: &ResourceState) -> Vec<Resource> {
+        let mut resources: Vec<Resource> = self
+            .granted_resources()
+            .into_iter()
+            .filter(|(_, s)| s == resource_state)
+            .map(|(resource, _)| resource)
+            .collect();
+        resources.sort();
+        resources
+    }
+
+    /// Resources the user gave access to, whatever their progress, sorted
+    pub fn accessible_resources(&self) -> Vec<Resource> {
+        let mut resources: Vec<Resource> = self
+            .granted_resources()
+            .into_iter()
+            .filter(|(_, s)| *s != ResourceState::NotGranted)
+            .map(|(resource, _)| resource)
+            .collect();
+        resources.sort();
+        resources
+    }
+
+    /// Requested resources the user has not given access to yet, sorted
+    pub fn missing_resources(&self, requested_resources: &[Resource]) -> Vec<Resource> {
+        let granted_resources = self.granted_resources();
+        let mut resources: Vec<Resource> = requested_resources
+            .iter()
+            .filter(|resource| {
+                granted_resources
+                    .get(*resource)
+                    .is_none_or(|s| *s == ResourceState::NotGranted)
+            })
+            .cloned()
+            .collect();
+        resources.sort();
+        resources
+    }
+
+    /// Records the requested resources left out of the granted scope as not granted
+    pub fn mark_not_granted(&mut self, requested_resources: &[Resource]) {
+        if let Some(a) = self.access_token.as_mut() {
+            for resource in requested_resources {
+                a.granted_resources
+                    .entry(resource.clone())
+                    .or_insert(ResourceState::NotGranted);
+            }
+        }
+    }
+
+    /// Keeps the resources already initiated or downloaded with the extended authorization
+    /// from being archived again
+    pub fn carry_over_progress(&mut self, extended: &OAuthInfo) {
+        let Some(a) = self.access_token.as_mut() else {
+            return;
+        };
+        for (resource, resource_state) in extended.granted_resources() {
+            if matches!(
+                resource_state,
+                ResourceState::Initiated | ResourceState::Downloaded
+            ) {
+                a.granted_resources.insert(resource, resource_state);
+            }
+        }
+    }
+
     pub fn access_token(&self) -> Option<String> {
         self.access_token.as_ref().map(|a| a.token.clone())
     }
 
     pub fn set_access_token(&mut self, token: AccessToken, expires_in: u32, scope: String) {
+        self.code = None;
+        self.code_verifier = None;
         self.access_token = Some(OAuthAccessToken {
             token,
             expires_at: Utc::now().timestamp() + expires_in as i64,
@@ -105,6 +260,19 @@ impl OAuthInfo {
         });
     }
 
+    /// Marks the authorization as withdrawn by the user, so no more data is fetched with it
+    pub fn revoke(&mut self) {
+        self.revoked_at = Some(Utc::now().timestamp());
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+
+    pub fn has_valid_access_token(&self) -> bool {
+        !self.is_revoked() && self.is_not_expired_access_token().is_some_and(|b| b)
+    }
+
     fn is_not_expired_access_token(&self) -> Option<bool> {
         self.access_token.as_ref().map(|a| !a.is_expired())
     }
@@ -113,37 +281,49 @@ impl OAuthInfo {
         &self,
         resource: &str,
         expected_resource_state: &ResourceState,
-    ) -> Result<bool, String> {
+    ) -> PapiResult<bool> {
         self.access_token
             .as_ref()
-            .ok_or("Access token not found")?
+            .ok_or(PapiError::InvalidState(
+                "Access token not found".to_string(),
+            ))?
             .is_expected_resource_state(resource, expected_resource_state)
     }
 
-    pub fn validate_initalized_access_token(
+    pub fn validate_access_token(
         &self,
-        ready_to_download_resource: &String,
-    ) -> Result<(), String> {
-        if self
-            .is_not_expired_access_token()
-            .is_some_and(|b| b == true)
+        resource: &str,
+        expected_resource_state: &ResourceState,
+    ) -> PapiResult<()> {
+        if self.has_valid_access_token()
             && self
-                .is_expected_resource_state(ready_to_download_resource, &ResourceState::Initiated)
-                .is_ok_and(|b| b == true)
+                .is_expected_resource_state(resource, expected_resource_state)
+                .is_ok_and(|b| b)
         {
             return Ok(());
         }
-        Err("Latest access token expired or resource not in expected state".to_string())
+        Err(PapiError::InvalidState(
+            "Latest access token expired, revoked or resource not in expected state".to_string(),
+        ))
+    }
+
+    pub fn validate_initalized_access_token(
+        &self,
+        ready_to_download_resource: &str,
+    ) -> PapiResult<()> {
+        self.validate_access_token(ready_to_download_resource, &ResourceState::Initiated)
     }
 
     pub fn update_granted_resource_state(
         &mut self,
         resource: &str,
         new_resource_state: ResourceState,
-    ) -> Result<(), String> {
+    ) -> PapiResult<()> {
         match self.access_token.as_mut() {
             Some(a) => a.update_granted_resource_state(resource, new_resource_state),
-            None => Err(format!("Access token not found")),
+            None => Err(PapiError::InvalidState(
+                "Access token not found".to_string(),
+            )),
         }
     }
 
@@ -153,32 +333,143 @@ impl OAuthInfo {
             .map(|a| {
                 a.granted_resources
                     .values()
+                    .filter(|s| **s != ResourceState::NotGranted)
                     .all(|s| *s == ResourceState::Downloaded)
             })
             .unwrap_or(false)
     }
 }
 
+/// What a user authorized, without any secret, as included in their data export
+#[derive(Debug, Serialize)]
+pub struct AuthorizationSummary {
+    created_at: i64,
+    google_account_email: Option<String>,
+    access_expires_at: Option<i64>,
+    granted_resources: HashMap<Resource, ResourceState>,
+    revoked_at: Option<i64>,
+}
+
+impl From<&OAuthInfo> for AuthorizationSummary {
+    fn from(oauth_info: &OAuthInfo) -> Self {
+        Self {
+            created_at: oauth_info.created_at,
+            google_account_email: oauth_info.email.clone(),
+            access_expires_at: oauth_info.access_token.as_ref().map(|a| a.expires_at),
+            granted_resources: oauth_info
+                .access_token
+                .as_ref()
+                .map(|a| a.granted_resources.clone())
+                .unwrap_or_default(),
+            revoked_at: oauth_info.revoked_at,
+        }
+    }
+}
+
+// only the My Activity resources are extracted from the granted scopes
+const MY_ACTIVITY_RESOURCE_PATTERN: &str = r"myactivity\.\w+";
+
+static MY_ACTIVITY_RESOURCE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r"https://www.googleapis.com/auth/dataportability\.({})",
+        MY_ACTIVITY_RESOURCE_PATTERN
+    ))
+    .expect("resource regex is valid")
+});
+
+static MY_ACTIVITY_RESOURCE_NAME_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!("^{}$", MY_ACTIVITY_RESOURCE_PATTERN)).expect("resource regex is valid")
+});
+
+/// Whether the resource would be recognized in the scopes granted by the user
+pub fn is_my_activity_resource(resource: &str) -> bool {
+    MY_ACTIVITY_RESOURCE_NAME_REGEX.is_match(resource)
+}
+
 fn extract_my_activity_resources(scope: &str) -> HashMap<Resource, ResourceState> {
-    let re =
-        Regex::new(r"https://www.googleapis.com/auth/dataportability\.(myactivity\.\w+)").unwrap();
     let mut results = HashMap::new();
 
-    for cap in re.captures_iter(scope) {
+    for cap in MY_ACTIVITY_RESOURCE_REGEX.captures_iter(scope) {
         if let Some(matched) = cap.get(1) {
             results.insert(matched.as_str().to_string(), ResourceState::Granted);
         } else {
-            print!("Failed to extract resource from {}", scope);
+            warn!(scope, "Failed to extract resource from scope");
         }
     }
 
     results
 }
 
-pub type UserStateMap = RwLock<HashMap<UserId, OAuthState>>;
+/// Proof Key for Code Exchange (RFC 7636) generated for each authorization request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pkce {
+    code_verifier: CodeVerifier,
+}
+
+impl Pkce {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self {
+            code_verifier: URL_SAFE_NO_PAD.encode(bytes),
+        }
+    }
 
-const AUTHORIZATION_BASE_URL: &str =
-    "https://accounts.google.com/o/oauth2/v2/auth/oauthchooseaccount";
+    pub fn code_verifier(&self) -> CodeVerifier {
+        self.code_verifier.clone()
+    }
+
+    /// S256 challenge sent in the authorization request
+    pub fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+}
+
+/// Authorization requested by a user whose code has not been posted yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingAuthorization {
+    state: OAuthState,
+    pkce: Pkce,
+    expires_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extended_state: Option<OAuthState>,
+}
+
+impl PendingAuthorization {
+    pub fn new(state: OAuthState, pkce: Pkce, ttl: Duration) -> Self {
+        Self {
+            state,
+            pkce,
+            expires_at: Utc::now().timestamp() + ttl.as_secs() as i64,
+            extended_state: None,
+        }
+    }
+
+    /// Marks the authorization as asking the resources left out of an earlier one
+    pub fn with_extended_state(mut self, extended_state: OAuthState) -> Self {
+        self.extended_state = Some(extended_state);
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < Utc::now().timestamp()
+    }
+
+    pub fn state(&self) -> OAuthState {
+        self.state.clone()
+    }
+
+    pub fn pkce(&self) -> Pkce {
+        self.pkce.clone()
+    }
+
+    pub fn extended_state(&self) -> Option<OAuthState> {
+        self.extended_state.clone()
+    }
+}
+
+// relative to the Google accounts base URL
+const AUTHORIZATION_ENDPOINT: &str = "/o/oauth2/v2/auth/oauthchooseaccount";
 
 pub struct AuthorizationUrl {
     endpoint: String,
@@ -186,15 +477,15 @@ pub struct AuthorizationUrl {
 }
 
 impl AuthorizationUrl {
-    pub fn new(params: AuthorizationParams) -> Self {
+    pub fn new(base_url: &str, params: AuthorizationParams) -> Self {
         Self {
-            endpoint: String::from(AUTHORIZATION_BASE_URL),
+            endpoint: format!("{}{}", base_url, AUTHORIZATION_ENDPOINT),
             params,
         }
     }
 
-    pub fn as_url(&self) -> String {
-        format!("{}?{}", self.endpoint, self.params.as_url())
+    pub fn as_url(&self) -> PapiResult<String> {
+        Ok(format!("{}?{}", self.endpoint, self.params.as_url()?))
     }
 }
 
@@ -203,6 +494,8 @@ pub struct AuthorizationParams {
     state: Option<String>,
     scope: Option<String>,
     redirect_uri: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
     client_id: String,
     access_type: String,
     include_granted_scopes: bool,
@@ -214,12 +507,14 @@ pub struct AuthorizationParams {
 }
 
 impl AuthorizationParams {
-    pub fn default() -> Self {
+    pub fn new(client_id: String) -> Self {
         Self {
             state: None,
             scope: None,
             redirect_uri: None,
-            client_id: env::var("GOOGLE_CLIENT_ID").expect("GOOGLE_CLIENT_ID must be set"),
+            code_challenge: None,
+            code_challenge_method: None,
+            client_id,
             access_type: String::from("offline"),
             include_granted_scopes: true,
             response_type: String::from("code"),
@@ -245,25 +540,15 @@ impl AuthorizationParams {
         self
     }
 
-    pub fn as_url(&self) -> String {
-        serde_json::to_value(&self)
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .iter()
-            .fold(String::new(), |params, (param, value)| match value {
-                Value::String(value) => {
-                    if !params.is_empty() {
-                        return format!("{}&{}={}", params, param, value);
-                    }
-                    format!("{}={}", param, value)
-                }
-                Value::Bool(bool) => format!("{}&{}={}", params, param, bool),
-                _ => params,
-            })
+    pub fn with_code_challenge(mut self, pkce: &Pkce) -> Self {
+        self.code_challenge = Some(pkce.code_challenge());
+        self.code_challenge_method = Some(String::from("S256"));
+        self
     }
 }
 
+impl QueryParams for AuthorizationParams {}
+
 #[derive(Deserialize)]
 pub struct AuthorizationCodeRequestPayload {
     state: String,
@@ -279,3 +564,113 @@ impl AuthorizationCodeRequestPayload {
         self.code.clone()
     }
 }
+
+#[derive(Deserialize)]
+pub struct RevokeAuthorizationQuery {
+    #[serde(default)]
+    delete_data: bool,
+}
+
+impl RevokeAuthorizationQuery {
+    pub fn delete_data(&self) -> bool {
+        self.delete_data
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizationUrlQuery {
+    /// Only asks the requested resources left out of the last authorization
+    #[serde(default)]
+    incremental: bool,
+}
+
+impl AuthorizationUrlQuery {
+    pub fn incremental(&self) -> bool {
+        self.incremental
+    }
+}
+
+// number of users read at once by the admin API, unless asked otherwise
+const DEFAULT_USERS_PAGE_SIZE: usize = 100;
+const MAX_USERS_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct UserSearchQuery {
+    #[serde(default)]
+    search: String,
+    /// Number of users read from the auth DB, of which only the matching ones are returned
+    #[serde(default = "default_users_page_size")]
+    limit: usize,
+    /// Where the page starts, as returned with the previous page
+    cursor: Option<UserId>,
+}
+
+fn default_users_page_size() -> usize {
+    DEFAULT_USERS_PAGE_SIZE
+}
+
+impl UserSearchQuery {
+    pub fn search(&self) -> String {
+        self.search.clone()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.clamp(1, MAX_USERS_PAGE_SIZE)
+    }
+
+    pub fn cursor(&self) -> Option<UserId> {
+        self.cursor.clone()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RetryResourceQuery {
+    /// Only the download is retried if set, otherwise the archive job is initiated again
+    archive_job_id: Option<String>,
+}
+
+impl RetryResourceQuery {
+    pub fn archive_job_id(&self) -> Option<String> {
+        self.archive_job_id.clone()
+    }
+}
+
+/// Summary of what was done to disconnect the Google account of a user
+#[derive(Debug, Serialize)]
+pub struct RevocationReceipt {
+    user_id: UserId,
+    revoked_at: i64,
+    cancelled_jobs: usize,
+    withdrawn_consents: usize,
+    deleted_files: Option<usize>,
+}
+
+impl RevocationReceipt {
+    pub fn new(
+        user_id: UserId,
+        cancelled_jobs: usize,
+        withdrawn_consents: usize,
+        deleted_files: Option<usize>,
+    ) -> Self {
+        Self {
+            user_id,
+            revoked_at: Utc::now().timestamp(),
+            cancelled_jobs,
+            withdrawn_consents,
+            deleted_files,
+        }
+    }
+}
+
+/// The audit events about a user, as returned to them
+#[derive(Debug, Serialize)]
+pub struct UserAuditLog {
+    user_id: UserId,
+    events: Vec<AuditEvent>,
+}
+
+impl UserAuditLog {
+    pub fn new(user_id: UserId, events: Vec<AuditEvent>) -> Self {
+        Self { user_id, events }
+    }
+}
@@ -0,0 +1,138 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    api::types::{Resource, UserId},
+    error::PapiResult,
+};
+
+pub use sqlite::SqliteAuditLog;
+
+mod sqlite;
+
+/// Who performed an audited action
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "name", rename_all = "snake_case")]
+pub enum Actor {
+    /// The user the data belongs to
+    User,
+    /// The background jobs, acting on the authorization granted by the user
+    System,
+    /// An operator, named after their admin API key, or `cli` for the administrative commands
+    Admin(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// The user consented on Google's side and sent the authorization code
+    AuthorizationGranted,
+    TokenExchanged,
+    ArchiveInitiated,
+    DataDownloaded,
+    /// Data of the user was read through the API by someone else than the user
+    DataRead,
+    DataExported,
+    ResourceRetried,
+    AuthorizationReset,
+    AuthorizationRevoked,
+    UserErased,
+    /// An operator was refused an action by the role of their admin API key
+    AdminActionDenied,
+    /// An action of an operator failed, possibly after changing some data
+    AdminActionFailed,
+}
+
+/// Record of an action on the data or the authorization of a user, or of an admin action on no
+/// user in particular
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    id: String,
+    occurred_at: i64,
+    user_id: Option<UserId>,
+    actor: Actor,
+    action: AuditAction,
+    resource: Option<Resource>,
+    request_id: Option<String>,
+    details: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(user_id: UserId, actor: Actor, action: AuditAction) -> Self {
+        Self {
+            user_id: Some(user_id),
+            ..Self::without_user(actor, action)
+        }
+    }
+
+    pub fn without_user(actor: Actor, action: AuditAction) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            occurred_at: Utc::now().timestamp(),
+            user_id: None,
+            actor,
+            action,
+            resource: None,
+            request_id: None,
+            details: None,
+        }
+    }
+
+    pub fn with_resource(mut self, resource: Resource) -> Self {
+        self.resource = Some(resource);
+        self
+    }
+
+    /// ID of the HTTP request which performed the action, as found in the logs
+    pub fn with_request_id(mut self, request_id: String) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    pub fn with_details(mut self, details: String) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// Uses the receipt of the action, as returned to the caller, as the details
+    pub fn with_receipt<T: Serialize>(self, receipt: &T) -> Self {
+        match serde_json::to_string(receipt) {
+            Ok(receipt) => self.with_details(receipt),
+            Err(_) => self,
+        }
+    }
+
+    pub fn user_id(&self) -> Option<UserId> {
+        self.user_id.clone()
+    }
+
+    pub fn actor(&self) -> Actor {
+        self.actor.clone()
+    }
+
+    pub fn action(&self) -> AuditAction {
+        self.action
+    }
+}
+
+/// Append-only log of the actions on the data of the users.
+///
+/// Events are kept when a user is erased, as the proof of what was done with their data.
+#[async_trait]
+pub trait AuditLog: Send + Sync {
+    async fn append(&self, event: AuditEvent) -> PapiResult<()>;
+
+    /// Returns the events about a user, oldest first
+    async fn list_user_events(&self, user_id: &UserId) -> PapiResult<Vec<AuditEvent>>;
+}
+
+/// Appends an event about an action already performed, which cannot be undone if the event is
+/// lost, so failures are only logged
+pub async fn record(audit_log: &dyn AuditLog, event: AuditEvent) {
+    if let Err(e) = audit_log.append(event.clone()).await {
+        error!(error = %e, ?event, "Error appending audit event");
+    }
+}
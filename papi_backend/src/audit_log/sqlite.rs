@@ -0,0 +1,99 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+
+use crate::{
+    api::types::UserId,
+    error::{PapiError, PapiResult},
+    sqlite::SqliteDb,
+};
+
+use super::{AuditEvent, AuditLog};
+
+pub struct SqliteAuditLog {
+    db: SqliteDb,
+}
+
+impl SqliteAuditLog {
+    pub fn setup(db_path: &str) -> PapiResult<Self> {
+        Self::new(SqliteDb::open(db_path, "audit log")?)
+    }
+
+    pub fn new(connection: Connection) -> PapiResult<Self> {
+        // the triggers make the table append-only, even for someone with access to the DB file
+        let db = SqliteDb::new(
+            connection,
+            "audit log",
+            "CREATE TABLE IF NOT EXISTS audit_events (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT NOT NULL UNIQUE,
+                user_id TEXT NOT NULL,
+                occurred_at INTEGER NOT NULL,
+                payload TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS audit_events_user ON audit_events (user_id, seq);
+            CREATE TRIGGER IF NOT EXISTS audit_events_no_update BEFORE UPDATE ON audit_events
+            BEGIN SELECT RAISE(ABORT, 'audit events cannot be updated'); END;
+            CREATE TRIGGER IF NOT EXISTS audit_events_no_delete BEFORE DELETE ON audit_events
+            BEGIN SELECT RAISE(ABORT, 'audit events cannot be deleted'); END;",
+        )?;
+
+        Ok(Self { db })
+    }
+}
+
+#[async_trait]
+impl AuditLog for SqliteAuditLog {
+    async fn append(&self, event: AuditEvent) -> PapiResult<()> {
+        let payload = serde_json::to_string(&event)
+            .map_err(|e| PapiError::Storage(format!("Failed to serialize audit event: {}", e)))?;
+
+        self.db
+            .with_connection(move |connection| {
+                connection
+                    .execute(
+                        "INSERT INTO audit_events (id, user_id, occurred_at, payload)
+                        VALUES (?1, ?2, ?3, ?4)",
+                        // the events about no user are indexed under an empty user ID
+                        params![
+                            event.id,
+                            event.user_id.unwrap_or_default(),
+                            event.occurred_at,
+                            payload
+                        ],
+                    )
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error inserting audit event: {}", e))
+                    })?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn list_user_events(&self, user_id: &UserId) -> PapiResult<Vec<AuditEvent>> {
+        let user_id = user_id.clone();
+
+        self.db
+            .with_connection(move |connection| {
+                let mut statement = connection
+                    .prepare("SELECT payload FROM audit_events WHERE user_id = ?1 ORDER BY seq")
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error preparing audit events query: {}", e))
+                    })?;
+                let payloads = statement
+                    .query_map(params![user_id], |row| row.get::<_, String>(0))
+                    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error listing audit events: {}", e))
+                    })?;
+                payloads
+                    .iter()
+                    .map(|payload| {
+                        serde_json::from_str(payload).map_err(|e| {
+                            PapiError::Storage(format!("Failed to deserialize audit event: {}", e))
+                        })
+                    })
+                    .collect()
+            })
+            .await
+    }
+}
@@ -0,0 +1,355 @@
+use async_trait::async_trait;
+
+use super::{AuthDbClient, AuthsPage};
+use crate::{
+    api::types::{GoogleSubject, OAuthInfo, UserId},
+    config::StorageConfig,
+    error::{PapiError, PapiResult},
+    metrics::{observe_storage, DYNAMO_DB},
+};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::{
+    error::SdkError,
+    operation::create_table::CreateTableError,
+    types::{
+        AttributeDefinition, AttributeValue, BillingMode, CreateGlobalSecondaryIndexAction,
+        GlobalSecondaryIndex, GlobalSecondaryIndexUpdate, IndexStatus, KeySchemaElement, KeyType,
+        Projection, ProjectionType, ScalarAttributeType,
+    },
+    Client,
+};
+use serde_dynamo::{from_item, to_item};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// Index of the OAuth info by the Google account which granted the authorization
+const GOOGLE_SUB_INDEX_NAME: &str = "google_sub_index";
+
+pub struct DynamoDbAuthDbClient {
+    client: Client,
+    table_name: String,
+}
+
+async fn create_table(
+    client: &Client,
+    table_name: &str,
+    key: &str,
+) -> Result<(), SdkError<CreateTableError>> {
+    // partition key
+    let attr_part = AttributeDefinition::builder()
+        .attribute_name(key)
+        .attribute_type(ScalarAttributeType::S)
+        .build()?;
+
+    let ks = KeySchemaElement::builder()
+        .attribute_name(key)
+        .key_type(KeyType::Hash)
+        .build()?;
+
+    client
+        .create_table()
+        .table_name(table_name)
+        .billing_mode(BillingMode::PayPerRequest)
+        .attribute_definitions(attr_part)
+        .attribute_definitions(google_sub_attribute()?)
+        .key_schema(ks)
+        .global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(GOOGLE_SUB_INDEX_NAME)
+                .key_schema(google_sub_key()?)
+                .projection(google_sub_projection())
+                .build()?,
+        )
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+fn google_sub_attribute() -> Result<AttributeDefinition, aws_sdk_dynamodb::error::BuildError> {
+    AttributeDefinition::builder()
+        .attribute_name("google_sub")
+        .attribute_type(ScalarAttributeType::S)
+        .build()
+}
+
+fn google_sub_key() -> Result<KeySchemaElement, aws_sdk_dynamodb::error::BuildError> {
+    KeySchemaElement::builder()
+        .attribute_name("google_sub")
+        .key_type(KeyType::Hash)
+        .build()
+}
+
+fn google_sub_projection() -> Projection {
+    Projection::builder()
+        .projection_type(ProjectionType::KeysOnly)
+        .build()
+}
+
+/// Adds the Google account index to tables created before it existed
+async fn ensure_google_sub_index(client: &Client, table_name: &str) -> PapiResult<()> {
+    let table = client
+        .describe_table()
+        .table_name(table_name)
+        .send()
+        .await
+        .map_err(|e| PapiError::Storage(format!("Error describing table: {}", e)))?;
+
+    let has_index = table
+        .table()
+        .map(|t| {
+            t.global_secondary_indexes()
+                .iter()
+                .any(|i| i.index_name() == Some(GOOGLE_SUB_INDEX_NAME))
+        })
+        .unwrap_or(false);
+    if has_index {
+        return Ok(());
+    }
+
+    let build_error = |e: aws_sdk_dynamodb::error::BuildError| {
+        PapiError::Storage(format!("Error creating index: {}", e))
+    };
+    client
+        .update_table()
+        .table_name(table_name)
+        .attribute_definitions(google_sub_attribute().map_err(build_error)?)
+        .global_secondary_index_updates(
+            GlobalSecondaryIndexUpdate::builder()
+                .create(
+                    CreateGlobalSecondaryIndexAction::builder()
+                        .index_name(GOOGLE_SUB_INDEX_NAME)
+                        .key_schema(google_sub_key().map_err(build_error)?)
+                        .projection(google_sub_projection())
+                        .build()
+                        .map_err(build_error)?,
+                )
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(|e| PapiError::Storage(format!("Error creating index: {}", e)))?;
+    info!(index = GOOGLE_SUB_INDEX_NAME, "Created index");
+
+    Ok(())
+}
+
+impl DynamoDbAuthDbClient {
+    pub async fn setup(storage_config: &StorageConfig) -> PapiResult<Self> {
+        let table_name = storage_config.dynamo_db_auth_table_name.clone();
+
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .region(Region::new(storage_config.aws_region.clone()))
+            .load()
+            .await;
+        let client = Client::new(&config);
+
+        if !client
+            .list_tables()
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("Error listing tables: {}", e)))?
+            .table_names()
+            .contains(&table_name)
+        {
+            create_table(&client, &table_name, "user_id")
+                .await
+                .map_err(|e| PapiError::Storage(format!("Error creating table: {}", e)))?;
+            info!(table = %table_name, "Created table");
+        } else {
+            ensure_google_sub_index(&client, &table_name).await?;
+        }
+
+        Ok(Self { client, table_name })
+    }
+}
+
+#[async_trait]
+impl AuthDbClient for DynamoDbAuthDbClient {
+    async fn create_auth(&self, oauth_info: OAuthInfo) -> PapiResult<()> {
+        observe_storage(DYNAMO_DB, "create_auth", async {
+            let item = to_item(&oauth_info)
+                .map_err(|e| PapiError::Storage(format!("Failed to serialize OAuthInfo: {}", e)))?;
+
+            self.client
+                .put_item()
+                .table_name(&self.table_name)
+                .item("user_id", AttributeValue::S(oauth_info.user_id()))
+                .set_item(Some(item))
+                .send()
+                .await
+                .map_err(|e| PapiError::Storage(format!("Error inserting oauth info: {}", e)))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn read_last_auth_for_user(&self, user_id: String) -> PapiResult<OAuthInfo> {
+        observe_storage(DYNAMO_DB, "read_last_auth_for_user", async {
+            // TODO: make sure that there isn't a better way to query the DB
+            let query_output = self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .key_condition_expression("user_id = :user_id")
+                .expression_attribute_values(":user_id", AttributeValue::S(user_id.clone()))
+                .scan_index_forward(false)
+                .limit(1)
+                .send()
+                .await
+                .map_err(|e| PapiError::Storage(format!("Error querying DB: {}", e)))?;
+
+            let item = query_output
+                .items
+                .unwrap_or_default()
+                .into_iter()
+                .next()
+                .ok_or(PapiError::NotFound("No OAuth info found".to_string()))?;
+
+            from_item(item)
+                .map_err(|e| PapiError::Storage(format!("Failed to deserialize OAuthInfo: {}", e)))
+        })
+        .await
+    }
+
+    async fn update_auth_for_user(&self, user_id: String, oauth_info: OAuthInfo) -> PapiResult<()> {
+        observe_storage(DYNAMO_DB, "update_auth_for_user", async {
+            let item = to_item(&oauth_info)
+                .map_err(|e| PapiError::Storage(format!("Failed to serialize OAuthInfo: {}", e)))?;
+
+            self.client
+                .put_item()
+                .table_name(&self.table_name)
+                .item("user_id", AttributeValue::S(user_id))
+                .set_item(Some(item))
+                .send()
+                .await
+                .map_err(|e| PapiError::Storage(format!("Error updating OAuth info: {}", e)))?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn find_users_by_google_sub(
+        &self,
+        google_sub: &GoogleSubject,
+    ) -> PapiResult<HashSet<UserId>> {
+        observe_storage(DYNAMO_DB, "find_users_by_google_sub", async {
+            let mut pages = self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .index_name(GOOGLE_SUB_INDEX_NAME)
+                .key_condition_expression("google_sub = :google_sub")
+                .expression_attribute_values(":google_sub", AttributeValue::S(google_sub.clone()))
+                .into_paginator()
+                .items()
+                .send();
+
+            let mut user_ids = HashSet::new();
+            while let Some(item) = pages.next().await {
+                let item =
+                    item.map_err(|e| PapiError::Storage(format!("Error querying DB: {}", e)))?;
+                if let Some(Ok(user_id)) = item.get("user_id").map(|user_id| user_id.as_s()) {
+                    user_ids.insert(user_id.clone());
+                }
+            }
+            Ok(user_ids)
+        })
+        .await
+    }
+
+    async fn delete_auth_for_user(&self, user_id: &UserId) -> PapiResult<usize> {
+        observe_storage(DYNAMO_DB, "delete_auth_for_user", async {
+            let mut pages = self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .key_condition_expression("user_id = :user_id")
+                .expression_attribute_values(":user_id", AttributeValue::S(user_id.clone()))
+                .projection_expression("user_id")
+                .into_paginator()
+                .items()
+                .send();
+
+            let mut deleted = 0;
+            while let Some(item) = pages.next().await {
+                item.map_err(|e| PapiError::Storage(format!("Error querying DB: {}", e)))?;
+                self.client
+                    .delete_item()
+                    .table_name(&self.table_name)
+                    .key("user_id", AttributeValue::S(user_id.clone()))
+                    .send()
+                    .await
+                    .map_err(|e| PapiError::Storage(format!("Error deleting OAuth info: {}", e)))?;
+                deleted += 1;
+            }
+
+            Ok(deleted)
+        })
+        .await
+    }
+
+    async fn check_reachable(&self) -> PapiResult<()> {
+        let table = self
+            .client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("Error describing table: {}", e)))?;
+
+        // the index added to an existing table is only queryable once it is backfilled
+        let index_status = table
+            .table()
+            .and_then(|t| {
+                t.global_secondary_indexes()
+                    .iter()
+                    .find(|i| i.index_name() == Some(GOOGLE_SUB_INDEX_NAME))
+            })
+            .and_then(|i| i.index_status());
+        if index_status != Some(&IndexStatus::Active) {
+            return Err(PapiError::Unavailable(format!(
+                "Index {} is not active yet: {:?}",
+                GOOGLE_SUB_INDEX_NAME, index_status
+            )));
+        }
+        Ok(())
+    }
+
+    async fn list_auths(&self, limit: usize, cursor: Option<UserId>) -> PapiResult<AuthsPage> {
+        observe_storage(DYNAMO_DB, "list_auths", async {
+            let output = self
+                .client
+                .scan()
+                .table_name(&self.table_name)
+                .limit(limit as i32)
+                .set_exclusive_start_key(cursor.map(|cursor| {
+                    HashMap::from([("user_id".to_string(), AttributeValue::S(cursor))])
+                }))
+                .send()
+                .await
+                .map_err(|e| PapiError::Storage(format!("Error scanning DB: {}", e)))?;
+
+            let auths = output
+                .items()
+                .iter()
+                .map(|item| {
+                    from_item(item.clone()).map_err(|e| {
+                        PapiError::Storage(format!("Failed to deserialize OAuthInfo: {}", e))
+                    })
+                })
+                .collect::<PapiResult<Vec<OAuthInfo>>>()?;
+            // the scan goes on as long as DynamoDB returns the key it stopped at
+            let next_cursor = output
+                .last_evaluated_key()
+                .and_then(|key| key.get("user_id"))
+                .and_then(|user_id| user_id.as_s().ok())
+                .cloned();
+            Ok(AuthsPage { auths, next_cursor })
+        })
+        .await
+    }
+}
@@ -0,0 +1,98 @@
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use super::{AuthDbClient, AuthsPage};
+use crate::{
+    api::types::{GoogleSubject, OAuthInfo, UserId},
+    error::{PapiError, PapiResult},
+};
+
+/// Keeps the OAuth info in memory, only meant for tests and local development
+#[derive(Default)]
+pub struct InMemoryAuthDbClient {
+    auths: RwLock<HashMap<UserId, OAuthInfo>>,
+}
+
+impl InMemoryAuthDbClient {
+    fn write(&self, user_id: String, oauth_info: OAuthInfo) -> PapiResult<()> {
+        self.auths
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .insert(user_id, oauth_info);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuthDbClient for InMemoryAuthDbClient {
+    async fn create_auth(&self, oauth_info: OAuthInfo) -> PapiResult<()> {
+        self.write(oauth_info.user_id(), oauth_info)
+    }
+
+    async fn read_last_auth_for_user(&self, user_id: String) -> PapiResult<OAuthInfo> {
+        self.auths
+            .read()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .get(&user_id)
+            .cloned()
+            .ok_or(PapiError::NotFound("No OAuth info found".to_string()))
+    }
+
+    async fn update_auth_for_user(&self, user_id: String, oauth_info: OAuthInfo) -> PapiResult<()> {
+        self.write(user_id, oauth_info)
+    }
+
+    async fn find_users_by_google_sub(
+        &self,
+        google_sub: &GoogleSubject,
+    ) -> PapiResult<HashSet<UserId>> {
+        Ok(self
+            .auths
+            .read()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .values()
+            .filter(|oauth_info| oauth_info.google_sub().as_ref() == Some(google_sub))
+            .map(|oauth_info| oauth_info.user_id())
+            .collect())
+    }
+
+    async fn delete_auth_for_user(&self, user_id: &UserId) -> PapiResult<usize> {
+        Ok(self
+            .auths
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .remove(user_id)
+            .map_or(0, |_| 1))
+    }
+
+    async fn check_reachable(&self) -> PapiResult<()> {
+        self.auths
+            .read()
+            .map(|_| ())
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))
+    }
+
+    async fn list_auths(&self, limit: usize, cursor: Option<UserId>) -> PapiResult<AuthsPage> {
+        let auths = self
+            .auths
+            .read()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?;
+        let mut user_ids: Vec<&UserId> = auths
+            .keys()
+            .filter(|user_id| cursor.as_ref().is_none_or(|cursor| *user_id > cursor))
+            .collect();
+        user_ids.sort();
+        let next_cursor = (user_ids.len() > limit).then(|| user_ids[limit - 1].clone());
+        Ok(AuthsPage {
+            auths: user_ids
+                .into_iter()
+                .take(limit)
+                .map(|user_id| auths[user_id].clone())
+                .collect(),
+            next_cursor,
+        })
+    }
+}
@@ -0,0 +1,160 @@
+use clap::Subcommand;
+
+use crate::{
+    admin,
+    api::types::{Resource, UserId},
+    audit_log::{record, Actor, AuditAction, AuditEvent},
+    config::{AdminRole, Config},
+    erasure::{erase_user, UserDataStores},
+    error::{PapiError, PapiResult},
+};
+
+/// Administrative operations, run against the same stores as the server
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Runs the HTTP server and the worker pool
+    Serve,
+    /// Lists the users with the state of the resources they authorized
+    ListUsers,
+    /// Shows the authorizations granted by a user
+    AuthHistory {
+        #[arg(long)]
+        user_id: UserId,
+    },
+    /// Initiates the archive job of a resource again
+    RetriggerInitiation {
+        #[arg(long)]
+        user_id: UserId,
+        #[arg(long)]
+        resource: Resource,
+    },
+    /// Downloads the archive of a completed archive job again
+    RetriggerDownload {
+        #[arg(long)]
+        user_id: UserId,
+        #[arg(long)]
+        resource: Resource,
+        #[arg(long)]
+        archive_job_id: String,
+    },
+    /// Resets the authorization of a user on Google's side, stopping their archive jobs
+    ResetAuthorization {
+        #[arg(long)]
+        user_id: UserId,
+    },
+    /// Deletes all the data held for a user and prints the deletion receipt
+    #[command(alias = "purge-user")]
+    EraseUser {
+        #[arg(long)]
+        user_id: UserId,
+    },
+    /// Moves the files of the requested resources stored with the legacy flat keys under the
+    /// per-user prefixes
+    MigrateStorageKeys,
+    /// Generates a key of the admin API, printing it along with its `admin_api_keys` entry
+    CreateAdminApiKey {
+        #[arg(long)]
+        name: String,
+        #[arg(long, value_enum)]
+        role: AdminRole,
+    },
+}
+
+pub async fn run(command: Command, config: &Config, stores: &UserDataStores) -> PapiResult<()> {
+    match command {
+        Command::Serve => Err(PapiError::InvalidRequest(
+            "The server is not an administrative command".to_string(),
+        )),
+        Command::ListUsers => {
+            let users = admin::list_users(stores).await?;
+            admin::record_reads(stores, users.iter().map(|user| user.user_id()), |user_id| {
+                audit_event(user_id, AuditAction::DataRead).with_details("user list".to_string())
+            })
+            .await?;
+            print_json(&users)
+        }
+        Command::AuthHistory { user_id } => {
+            let history = admin::authorization_history(stores, &user_id).await?;
+            stores
+                .audit_log
+                .append(
+                    audit_event(&user_id, AuditAction::DataRead)
+                        .with_details("authorization history".to_string()),
+                )
+                .await?;
+            print_json(&history)
+        }
+        Command::RetriggerInitiation { user_id, resource } => {
+            let receipt = admin::retrigger_archive_initiation(stores, &user_id, &resource).await?;
+            record(
+                stores.audit_log.as_ref(),
+                audit_event(&user_id, AuditAction::ResourceRetried)
+                    .with_resource(resource)
+                    .with_receipt(&receipt),
+            )
+            .await;
+            print_json(&receipt)
+        }
+        Command::RetriggerDownload {
+            user_id,
+            resource,
+            archive_job_id,
+        } => {
+            let receipt =
+                admin::retrigger_download(stores, &user_id, &resource, archive_job_id).await?;
+            record(
+                stores.audit_log.as_ref(),
+                audit_event(&user_id, AuditAction::ResourceRetried)
+                    .with_resource(resource)
+                    .with_receipt(&receipt),
+            )
+            .await;
+            print_json(&receipt)
+        }
+        Command::ResetAuthorization { user_id } => {
+            admin::reset_authorization(stores, &user_id).await?;
+            record(
+                stores.audit_log.as_ref(),
+                audit_event(&user_id, AuditAction::AuthorizationReset),
+            )
+            .await;
+            print_json(&serde_json::json!({ "user_id": user_id, "reset": true }))
+        }
+        Command::EraseUser { user_id } => {
+            let receipt = erase_user(stores, &user_id).await?;
+            record(
+                stores.audit_log.as_ref(),
+                audit_event(&user_id, AuditAction::UserErased).with_receipt(&receipt),
+            )
+            .await;
+            print_json(&receipt)
+        }
+        Command::MigrateStorageKeys => {
+            let migrated = stores
+                .papi_line_client
+                .migrate_legacy_keys(&config.google.requested_resources)
+                .await?;
+            print_json(&serde_json::json!({ "migrated_files": migrated }))
+        }
+        Command::CreateAdminApiKey { name, role } => {
+            // only the digest is configured, the key itself is shown once and never stored
+            let (api_key, sha256) = admin::generate_api_key();
+            print_json(&serde_json::json!({
+                "api_key": api_key,
+                "admin_api_keys_entry": format!("{}:{}:{}", name, role.as_str(), sha256),
+            }))
+        }
+    }
+}
+
+/// Audit event of a command, the operator running it not being known to the backend
+fn audit_event(user_id: &UserId, action: AuditAction) -> AuditEvent {
+    AuditEvent::new(user_id.clone(), Actor::Admin("cli".to_string()), action)
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> PapiResult<()> {
+    let json = serde_json::to_string_pretty(value)
+        .map_err(|e| PapiError::InvalidRequest(format!("Failed to serialize output: {}", e)))?;
+    println!("{}", json);
+    Ok(())
+}
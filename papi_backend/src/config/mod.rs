@@ -0,0 +1,931 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Args, Parser, ValueEnum};
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+use tokio::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    api::types::{is_my_activity_resource, Resource},
+    cli::Command,
+    consent_ledger::ProcessingPurpose,
+    error::{PapiError, PapiResult},
+    job_queue::JobQueueSettings,
+    worker_pool::StageConcurrency,
+};
+
+const DEFAULT_HTTPS_PORT: u16 = 8443;
+const DEFAULT_REQUESTED_RESOURCES: [&str; 2] = ["myactivity.search", "myactivity.shopping"];
+const DEFAULT_JOB_QUEUE_DB_PATH: &str = "papi_jobs.db";
+const DEFAULT_AUDIT_LOG_DB_PATH: &str = "papi_audit.db";
+const DEFAULT_CONSENT_LEDGER_DB_PATH: &str = "papi_consents.db";
+const DEFAULT_SESSION_DB_PATH: &str = "papi_sessions.db";
+const DEFAULT_PRIVACY_NOTICE_VERSION: &str = "1";
+const DEFAULT_OAUTH_STATE_TTL_SECS: u64 = 10 * 60;
+const DEFAULT_GOOGLE_JWKS_URI: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const DEFAULT_GOOGLE_ACCOUNTS_BASE_URL: &str = "https://accounts.google.com";
+const DEFAULT_GOOGLE_OAUTH2_BASE_URL: &str = "https://oauth2.googleapis.com";
+const DEFAULT_DATA_PORTABILITY_BASE_URL: &str = "https://dataportability.googleapis.com";
+const DEFAULT_ARCHIVE_POLL_INTERVAL_SECS: u64 = 10;
+const DEFAULT_ARCHIVE_POLLING_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_SESSION_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+const MIN_SESSION_SECRET_LENGTH: usize = 32;
+const SSE_CUSTOMER_KEY_LENGTH: usize = 32;
+
+/// What to do when a user connects a different Google account than before, or a Google account
+/// already connected by another user
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum AccountSwitchingPolicy {
+    /// Refuse the authorization, keeping the Google account originally connected
+    Reject,
+    /// Accept the authorization, the new Google account replaces the previous one
+    Allow,
+}
+
+/// `SameSite` attribute of the session cookie
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum SessionCookieSameSite {
+    Strict,
+    /// Sent by the frontend as long as it is served from the same site, e.g. another subdomain
+    Lax,
+    /// Sent with requests from any site, only protected by the check of the `Origin` header
+    None,
+}
+
+/// Where the pending authorizations are kept until the OAuth callback
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum OAuthStateStoreBackend {
+    /// Shared between instances and kept across restarts
+    DynamoDb,
+    /// Only suitable for a single instance, pending authorizations are lost on restart
+    Memory,
+}
+
+/// How the files stored in S3 are encrypted at rest
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum S3Encryption {
+    /// SSE-S3, the keys are managed by S3
+    S3Managed,
+    /// SSE-C, the key is provided with every request and never stored by S3
+    CustomerProvided,
+}
+
+/// How the log lines are written to stderr
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// One JSON object per line, including the fields of the enclosing spans
+    Json,
+    /// Human readable, for local development
+    Text,
+}
+
+/// What an admin API key allows, each role including the permissions of the previous ones
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// Read-only access to the users, their jobs and the aggregate stats
+    Viewer,
+    /// Can also retry failed resources and revoke authorizations
+    Operator,
+}
+
+impl AdminRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminRole::Viewer => "viewer",
+            AdminRole::Operator => "operator",
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "pAPI backend")]
+pub struct Cli {
+    #[command(flatten)]
+    config: RawConfig,
+    /// Runs the server if omitted
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    pub fn command(&self) -> Command {
+        self.command.clone().unwrap_or(Command::Serve)
+    }
+}
+
+/// Every setting can be passed as a CLI flag, an environment variable or a key of the TOML
+/// configuration file, in this order of precedence.
+#[derive(Args, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    /// Path to a TOML configuration file
+    #[arg(long = "config", env = "PAPI_CONFIG_FILE")]
+    #[serde(skip)]
+    config_file: Option<PathBuf>,
+
+    #[arg(long, env = "HTTPS_PORT")]
+    https_port: Option<u16>,
+    /// Plain HTTP port, only bound if set
+    #[arg(long, env = "HTTP_PORT")]
+    http_port: Option<u16>,
+    #[arg(long, env = "CERT_FILE_PATH")]
+    cert_file_path: Option<String>,
+    #[arg(long, env = "KEY_FILE_PATH")]
+    key_file_path: Option<String>,
+    /// Origins allowed to call the API with the session cookie, at least one is required
+    #[arg(long, env = "CORS_ALLOWED_ORIGINS", value_delimiter = ',')]
+    cors_allowed_origins: Option<Vec<String>>,
+
+    /// Secret used to sign the session tokens, at least 32 bytes long
+    #[arg(long, env = "SESSION_SECRET", hide_env_values = true)]
+    session_secret: Option<String>,
+    #[arg(long, env = "SESSION_TTL_SECS")]
+    session_ttl_secs: Option<u64>,
+    #[arg(long, env = "SESSION_COOKIE_SAME_SITE", value_enum)]
+    session_cookie_same_site: Option<SessionCookieSameSite>,
+    /// Keys of the admin API as `name:role:sha256`, the admin API is disabled if empty
+    #[arg(
+        long,
+        env = "ADMIN_API_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    admin_api_keys: Option<Vec<String>>,
+
+    #[arg(long, env = "GOOGLE_CLIENT_ID")]
+    google_client_id: Option<String>,
+    #[arg(long, env = "GOOGLE_CLIENT_SECRET", hide_env_values = true)]
+    google_client_secret: Option<String>,
+    /// URI of the frontend callback component triggered by Google after the OAuth flow terminates
+    #[arg(long, env = "REDIRECT_URI")]
+    redirect_uri: Option<String>,
+    /// Data Portability resources requested to the user
+    #[arg(long, env = "REQUESTED_RESOURCES", value_delimiter = ',')]
+    requested_resources: Option<Vec<Resource>>,
+    /// JWKS used to verify the signature of Google ID tokens
+    #[arg(long, env = "GOOGLE_JWKS_URI")]
+    google_jwks_uri: Option<String>,
+    /// Base URL of the Google authorization page, only meant to be changed for testing
+    #[arg(long, env = "GOOGLE_ACCOUNTS_BASE_URL")]
+    google_accounts_base_url: Option<String>,
+    /// Base URL of the Google token and revocation endpoints, only meant to be changed for testing
+    #[arg(long, env = "GOOGLE_OAUTH2_BASE_URL")]
+    google_oauth2_base_url: Option<String>,
+    /// Base URL of the Data Portability API, only meant to be changed for testing
+    #[arg(long, env = "DATA_PORTABILITY_BASE_URL")]
+    data_portability_base_url: Option<String>,
+    /// Time between two checks of the state of an archive job
+    #[arg(long, env = "ARCHIVE_POLL_INTERVAL_SECS")]
+    archive_poll_interval_secs: Option<u64>,
+    /// Time after which an archive job still not ready is given up
+    #[arg(long, env = "ARCHIVE_POLLING_TIMEOUT_SECS")]
+    archive_polling_timeout_secs: Option<u64>,
+    #[arg(long, env = "ACCOUNT_SWITCHING_POLICY", value_enum)]
+    account_switching_policy: Option<AccountSwitchingPolicy>,
+    /// Time a user has to complete the authorization after requesting the authorization URL
+    #[arg(long, env = "OAUTH_STATE_TTL_SECS")]
+    oauth_state_ttl_secs: Option<u64>,
+    /// Version of the privacy notice shown to the users, changing it asks them to consent again
+    #[arg(long, env = "PRIVACY_NOTICE_VERSION")]
+    privacy_notice_version: Option<String>,
+    /// Purposes of processing described in the privacy notice
+    #[arg(long, env = "PROCESSING_PURPOSES", value_enum, value_delimiter = ',')]
+    processing_purposes: Option<Vec<ProcessingPurpose>>,
+
+    #[arg(long, env = "AWS_REGION")]
+    aws_region: Option<String>,
+    #[arg(long, env = "DYNAMO_DB_AUTH_TABLE_NAME")]
+    dynamo_db_auth_table_name: Option<String>,
+    #[arg(long, env = "OAUTH_STATE_STORE", value_enum)]
+    oauth_state_store: Option<OAuthStateStoreBackend>,
+    /// Only required when the OAuth state store is DynamoDB
+    #[arg(long, env = "DYNAMO_DB_OAUTH_STATE_TABLE_NAME")]
+    dynamo_db_oauth_state_table_name: Option<String>,
+    #[arg(long, env = "S3_BUCKET_NAME")]
+    s3_bucket_name: Option<String>,
+    #[arg(long, env = "S3_ENCRYPTION", value_enum)]
+    s3_encryption: Option<S3Encryption>,
+    /// Base64 encoded 256-bit key, only required when the S3 encryption is customer provided
+    #[arg(long, env = "S3_SSE_CUSTOMER_KEY", hide_env_values = true)]
+    s3_sse_customer_key: Option<String>,
+    /// Days after which the raw archives downloaded from Google are deleted, kept forever if unset
+    #[arg(long, env = "RAW_ARCHIVE_RETENTION_DAYS")]
+    raw_archive_retention_days: Option<i32>,
+    /// SQLite database backing the persistent job queue
+    #[arg(long, env = "JOB_QUEUE_DB_PATH")]
+    job_queue_db_path: Option<String>,
+    /// SQLite database of the append-only audit log
+    #[arg(long, env = "AUDIT_LOG_DB_PATH")]
+    audit_log_db_path: Option<String>,
+    /// SQLite database of the consents given by the users
+    #[arg(long, env = "CONSENT_LEDGER_DB_PATH")]
+    consent_ledger_db_path: Option<String>,
+    /// SQLite database of the sessions revoked before they expire
+    #[arg(long, env = "SESSION_DB_PATH")]
+    session_db_path: Option<String>,
+
+    #[arg(long, env = "JOB_QUEUE_VISIBILITY_TIMEOUT_SECS")]
+    job_queue_visibility_timeout_secs: Option<u64>,
+    #[arg(long, env = "JOB_QUEUE_MAX_ATTEMPTS")]
+    job_queue_max_attempts: Option<u32>,
+    /// Delay before a failed job is retried, multiplied by the number of attempts
+    #[arg(long, env = "JOB_QUEUE_RETRY_DELAY_SECS")]
+    job_queue_retry_delay_secs: Option<u64>,
+    #[arg(long, env = "JOB_QUEUE_CAPACITY")]
+    job_queue_capacity: Option<usize>,
+    #[arg(long, env = "JOB_QUEUE_MAX_IN_FLIGHT_PER_USER")]
+    job_queue_max_in_flight_per_user: Option<u32>,
+    #[arg(long, env = "TOKEN_EXCHANGE_CONCURRENCY")]
+    token_exchange_concurrency: Option<usize>,
+    #[arg(long, env = "ARCHIVE_INITIATION_CONCURRENCY")]
+    archive_initiation_concurrency: Option<usize>,
+    #[arg(long, env = "DATA_DOWNLOAD_CONCURRENCY")]
+    data_download_concurrency: Option<usize>,
+
+    /// Time given to the HTTP requests and the jobs being processed to finish on shutdown
+    #[arg(long, env = "SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+
+    /// Log filter directives, e.g. `info` or `info,personal_api=debug`
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+    /// OTLP/HTTP endpoint the traces are exported to, e.g. `http://localhost:4318/v1/traces`
+    #[arg(long, env = "OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+}
+
+impl RawConfig {
+    fn from_file(path: &PathBuf) -> PapiResult<Self> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            PapiError::Config(format!(
+                "Could not read config file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        toml::from_str(&content).map_err(|e| {
+            PapiError::Config(format!(
+                "Could not parse config file {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Fills the settings missing in `self` with the ones of `other`
+    fn or(self, other: Self) -> Self {
+        Self {
+            config_file: self.config_file,
+            https_port: self.https_port.or(other.https_port),
+            http_port: self.http_port.or(other.http_port),
+            cert_file_path: self.cert_file_path.or(other.cert_file_path),
+            key_file_path: self.key_file_path.or(other.key_file_path),
+            cors_allowed_origins: self.cors_allowed_origins.or(other.cors_allowed_origins),
+            session_secret: self.session_secret.or(other.session_secret),
+            session_ttl_secs: self.session_ttl_secs.or(other.session_ttl_secs),
+            session_cookie_same_site: self
+                .session_cookie_same_site
+                .or(other.session_cookie_same_site),
+            admin_api_keys: self.admin_api_keys.or(other.admin_api_keys),
+            google_client_id: self.google_client_id.or(other.google_client_id),
+            google_client_secret: self.google_client_secret.or(other.google_client_secret),
+            redirect_uri: self.redirect_uri.or(other.redirect_uri),
+            requested_resources: self.requested_resources.or(other.requested_resources),
+            google_jwks_uri: self.google_jwks_uri.or(other.google_jwks_uri),
+            google_accounts_base_url: self
+                .google_accounts_base_url
+                .or(other.google_accounts_base_url),
+            google_oauth2_base_url: self.google_oauth2_base_url.or(other.google_oauth2_base_url),
+            data_portability_base_url: self
+                .data_portability_base_url
+                .or(other.data_portability_base_url),
+            archive_poll_interval_secs: self
+                .archive_poll_interval_secs
+                .or(other.archive_poll_interval_secs),
+            archive_polling_timeout_secs: self
+                .archive_polling_timeout_secs
+                .or(other.archive_polling_timeout_secs),
+            account_switching_policy: self
+                .account_switching_policy
+                .or(other.account_switching_policy),
+            oauth_state_ttl_secs: self.oauth_state_ttl_secs.or(other.oauth_state_ttl_secs),
+            privacy_notice_version: self.privacy_notice_version.or(other.privacy_notice_version),
+            processing_purposes: self.processing_purposes.or(other.processing_purposes),
+            aws_region: self.aws_region.or(other.aws_region),
+            dynamo_db_auth_table_name: self
+                .dynamo_db_auth_table_name
+                .or(other.dynamo_db_auth_table_name),
+            oauth_state_store: self.oauth_state_store.or(other.oauth_state_store),
+            dynamo_db_oauth_state_table_name: self
+                .dynamo_db_oauth_state_table_name
+                .or(other.dynamo_db_oauth_state_table_name),
+            s3_bucket_name: self.s3_bucket_name.or(other.s3_bucket_name),
+            s3_encryption: self.s3_encryption.or(other.s3_encryption),
+            s3_sse_customer_key: self.s3_sse_customer_key.or(other.s3_sse_customer_key),
+            raw_archive_retention_days: self
+                .raw_archive_retention_days
+                .or(other.raw_archive_retention_days),
+            job_queue_db_path: self.job_queue_db_path.or(other.job_queue_db_path),
+            audit_log_db_path: self.audit_log_db_path.or(other.audit_log_db_path),
+            consent_ledger_db_path: self.consent_ledger_db_path.or(other.consent_ledger_db_path),
+            session_db_path: self.session_db_path.or(other.session_db_path),
+            job_queue_visibility_timeout_secs: self
+                .job_queue_visibility_timeout_secs
+                .or(other.job_queue_visibility_timeout_secs),
+            job_queue_max_attempts: self.job_queue_max_attempts.or(other.job_queue_max_attempts),
+            job_queue_retry_delay_secs: self
+                .job_queue_retry_delay_secs
+                .or(other.job_queue_retry_delay_secs),
+            job_queue_capacity: self.job_queue_capacity.or(other.job_queue_capacity),
+            job_queue_max_in_flight_per_user: self
+                .job_queue_max_in_flight_per_user
+                .or(other.job_queue_max_in_flight_per_user),
+            token_exchange_concurrency: self
+                .token_exchange_concurrency
+                .or(other.token_exchange_concurrency),
+            archive_initiation_concurrency: self
+                .archive_initiation_concurrency
+                .or(other.archive_initiation_concurrency),
+            data_download_concurrency: self
+                .data_download_concurrency
+                .or(other.data_download_concurrency),
+            shutdown_timeout_secs: self.shutdown_timeout_secs.or(other.shutdown_timeout_secs),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            otlp_endpoint: self.otlp_endpoint.or(other.otlp_endpoint),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub https_port: u16,
+    pub http_port: Option<u16>,
+    pub cert_file_path: String,
+    pub key_file_path: String,
+    pub cors_allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub secret: String,
+    pub ttl: Duration,
+    pub cookie_same_site: SessionCookieSameSite,
+}
+
+/// An admin API key, only its SHA-256 digest is configured
+#[derive(Debug, Clone)]
+pub struct AdminApiKey {
+    pub name: String,
+    pub role: AdminRole,
+    pub sha256: String,
+}
+
+impl AdminApiKey {
+    fn parse(entry: &str) -> PapiResult<Self> {
+        let invalid =
+            |reason: &str| PapiError::Config(format!("admin API key '{}' {}", entry, reason));
+        let [name, role, sha256] = entry.trim().splitn(3, ':').collect::<Vec<&str>>()[..] else {
+            return Err(invalid("must be formatted as name:role:sha256"));
+        };
+        if name.is_empty() {
+            return Err(invalid("must have a name"));
+        }
+        let role = AdminRole::from_str(role, true)
+            .map_err(|_| invalid("must have the viewer or operator role"))?;
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid(
+                "must end with the hex encoded SHA-256 digest of the key",
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            role,
+            sha256: sha256.to_lowercase(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    pub api_keys: Vec<AdminApiKey>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GoogleConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub requested_resources: Vec<Resource>,
+    pub jwks_uri: String,
+    pub accounts_base_url: String,
+    pub oauth2_base_url: String,
+    pub data_portability_base_url: String,
+    pub archive_poll_interval: Duration,
+    pub archive_polling_timeout: Duration,
+    pub account_switching_policy: AccountSwitchingPolicy,
+    pub oauth_state_ttl: Duration,
+}
+
+/// What the users are asked to consent to
+#[derive(Debug, Clone)]
+pub struct ConsentConfig {
+    pub privacy_notice_version: String,
+    pub purposes: Vec<ProcessingPurpose>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub aws_region: String,
+    pub dynamo_db_auth_table_name: String,
+    pub oauth_state_store: OAuthStateStoreBackend,
+    pub dynamo_db_oauth_state_table_name: String,
+    pub s3_bucket_name: String,
+    pub s3_encryption: S3Encryption,
+    pub s3_sse_customer_key: String,
+    pub raw_archive_retention_days: Option<i32>,
+    pub job_queue_db_path: String,
+    pub audit_log_db_path: String,
+    pub consent_ledger_db_path: String,
+    pub session_db_path: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub http: HttpConfig,
+    pub session: SessionConfig,
+    pub admin: AdminConfig,
+    pub google: GoogleConfig,
+    pub consent: ConsentConfig,
+    pub storage: StorageConfig,
+    pub job_queue: JobQueueSettings,
+    pub concurrency: StageConcurrency,
+    pub shutdown_timeout: Duration,
+    pub telemetry: TelemetryConfig,
+}
+
+impl Config {
+    /// Loads the configuration from CLI flags, environment variables and the TOML configuration
+    /// file, reporting all missing or invalid settings at once
+    pub fn load(cli: Cli) -> PapiResult<Self> {
+        let cli = cli.config;
+        let raw = match &cli.config_file {
+            Some(path) => {
+                let file = RawConfig::from_file(path)?;
+                cli.or(file)
+            }
+            None => cli,
+        };
+        Self::try_from(raw)
+    }
+}
+
+impl TryFrom<RawConfig> for Config {
+    type Error = PapiError;
+
+    fn try_from(raw: RawConfig) -> PapiResult<Self> {
+        let mut errors = vec![];
+        let mut required = |value: Option<String>, name: &str| {
+            value.filter(|v| !v.is_empty()).unwrap_or_else(|| {
+                errors.push(format!("{} must be set", name));
+                String::new()
+            })
+        };
+
+        let http = HttpConfig {
+            https_port: raw.https_port.unwrap_or(DEFAULT_HTTPS_PORT),
+            http_port: raw.http_port,
+            cert_file_path: required(raw.cert_file_path, "cert_file_path"),
+            key_file_path: required(raw.key_file_path, "key_file_path"),
+            cors_allowed_origins: raw
+                .cors_allowed_origins
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|origin| !origin.trim().is_empty())
+                .collect(),
+        };
+        let session = SessionConfig {
+            secret: required(raw.session_secret, "session_secret"),
+            ttl: Duration::from_secs(raw.session_ttl_secs.unwrap_or(DEFAULT_SESSION_TTL_SECS)),
+            cookie_same_site: raw
+                .session_cookie_same_site
+                .unwrap_or(SessionCookieSameSite::Lax),
+        };
+        let google = GoogleConfig {
+            client_id: required(raw.google_client_id, "google_client_id"),
+            client_secret: required(raw.google_client_secret, "google_client_secret"),
+            redirect_uri: required(raw.redirect_uri, "redirect_uri"),
+            requested_resources: raw
+                .requested_resources
+                .unwrap_or_else(|| DEFAULT_REQUESTED_RESOURCES.map(|r| r.to_string()).to_vec()),
+            jwks_uri: raw
+                .google_jwks_uri
+                .unwrap_or(DEFAULT_GOOGLE_JWKS_URI.to_string()),
+            accounts_base_url: raw
+                .google_accounts_base_url
+                .unwrap_or(DEFAULT_GOOGLE_ACCOUNTS_BASE_URL.to_string()),
+            oauth2_base_url: raw
+                .google_oauth2_base_url
+                .unwrap_or(DEFAULT_GOOGLE_OAUTH2_BASE_URL.to_string()),
+            data_portability_base_url: raw
+                .data_portability_base_url
+                .unwrap_or(DEFAULT_DATA_PORTABILITY_BASE_URL.to_string()),
+            archive_poll_interval: Duration::from_secs(
+                raw.archive_poll_interval_secs
+                    .unwrap_or(DEFAULT_ARCHIVE_POLL_INTERVAL_SECS),
+            ),
+            archive_polling_timeout: Duration::from_secs(
+                raw.archive_polling_timeout_secs
+                    .unwrap_or(DEFAULT_ARCHIVE_POLLING_TIMEOUT_SECS),
+            ),
+            account_switching_policy: raw
+                .account_switching_policy
+                .unwrap_or(AccountSwitchingPolicy::Reject),
+            oauth_state_ttl: Duration::from_secs(
+                raw.oauth_state_ttl_secs
+                    .unwrap_or(DEFAULT_OAUTH_STATE_TTL_SECS),
+            ),
+        };
+        let consent = ConsentConfig {
+            privacy_notice_version: raw
+                .privacy_notice_version
+                .unwrap_or(DEFAULT_PRIVACY_NOTICE_VERSION.to_string()),
+            purposes: raw
+                .processing_purposes
+                .unwrap_or(vec![ProcessingPurpose::Archiving]),
+        };
+        let oauth_state_store = raw
+            .oauth_state_store
+            .unwrap_or(OAuthStateStoreBackend::DynamoDb);
+        let s3_encryption = raw.s3_encryption.unwrap_or(S3Encryption::S3Managed);
+        let storage = StorageConfig {
+            aws_region: required(raw.aws_region, "aws_region"),
+            dynamo_db_auth_table_name: required(
+                raw.dynamo_db_auth_table_name,
+                "dynamo_db_auth_table_name",
+            ),
+            oauth_state_store,
+            dynamo_db_oauth_state_table_name: match oauth_state_store {
+                OAuthStateStoreBackend::DynamoDb => required(
+                    raw.dynamo_db_oauth_state_table_name,
+                    "dynamo_db_oauth_state_table_name",
+                ),
+                OAuthStateStoreBackend::Memory => {
+                    raw.dynamo_db_oauth_state_table_name.unwrap_or_default()
+                }
+            },
+            s3_bucket_name: required(raw.s3_bucket_name, "s3_bucket_name"),
+            s3_encryption,
+            s3_sse_customer_key: match s3_encryption {
+                S3Encryption::CustomerProvided => {
+                    required(raw.s3_sse_customer_key, "s3_sse_customer_key")
+                }
+                S3Encryption::S3Managed => raw.s3_sse_customer_key.unwrap_or_default(),
+            },
+            raw_archive_retention_days: raw.raw_archive_retention_days,
+            job_queue_db_path: raw
+                .job_queue_db_path
+                .unwrap_or(DEFAULT_JOB_QUEUE_DB_PATH.to_string()),
+            audit_log_db_path: raw
+                .audit_log_db_path
+                .unwrap_or(DEFAULT_AUDIT_LOG_DB_PATH.to_string()),
+            consent_ledger_db_path: raw
+                .consent_ledger_db_path
+                .unwrap_or(DEFAULT_CONSENT_LEDGER_DB_PATH.to_string()),
+            session_db_path: raw
+                .session_db_path
+                .unwrap_or(DEFAULT_SESSION_DB_PATH.to_string()),
+        };
+
+        let defaults = JobQueueSettings::default();
+        let job_queue = JobQueueSettings {
+            visibility_timeout: raw
+                .job_queue_visibility_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.visibility_timeout),
+            max_attempts: raw.job_queue_max_attempts.unwrap_or(defaults.max_attempts),
+            retry_delay: raw
+                .job_queue_retry_delay_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.retry_delay),
+            capacity: raw.job_queue_capacity.unwrap_or(defaults.capacity),
+            max_in_flight_per_user: raw
+                .job_queue_max_in_flight_per_user
+                .unwrap_or(defaults.max_in_flight_per_user),
+        };
+
+        let defaults = StageConcurrency::default();
+        let concurrency = StageConcurrency {
+            token_exchange: raw
+                .token_exchange_concurrency
+                .unwrap_or(defaults.token_exchange),
+            archive_initiation: raw
+                .archive_initiation_concurrency
+                .unwrap_or(defaults.archive_initiation),
+            data_download: raw
+                .data_download_concurrency
+                .unwrap_or(defaults.data_download),
+        };
+
+        let admin = AdminConfig {
+            api_keys: raw
+                .admin_api_keys
+                .unwrap_or_default()
+                .iter()
+                .filter(|entry| !entry.trim().is_empty())
+                .filter_map(|entry| {
+                    AdminApiKey::parse(entry)
+                        .map_err(|e| errors.push(e.to_string()))
+                        .ok()
+                })
+                .collect(),
+        };
+        let telemetry = TelemetryConfig {
+            log_level: raw.log_level.unwrap_or(DEFAULT_LOG_LEVEL.to_string()),
+            log_format: raw.log_format.unwrap_or(LogFormat::Json),
+            otlp_endpoint: raw.otlp_endpoint.filter(|endpoint| !endpoint.is_empty()),
+        };
+
+        // the session cookie is sent along with credentialed cross-origin requests, which must
+        // not be accepted from any origin
+        if http.cors_allowed_origins.is_empty() {
+            errors.push("cors_allowed_origins must list the origins of the frontend".to_string());
+        }
+        if !session.secret.is_empty() && session.secret.len() < MIN_SESSION_SECRET_LENGTH {
+            errors.push(format!(
+                "session_secret must be at least {} bytes long",
+                MIN_SESSION_SECRET_LENGTH
+            ));
+        }
+        if session.ttl.is_zero() {
+            errors.push("session_ttl_secs must be greater than 0".to_string());
+        }
+        if google.oauth_state_ttl.is_zero() {
+            errors.push("oauth_state_ttl_secs must be greater than 0".to_string());
+        }
+        if google.archive_poll_interval.is_zero() {
+            errors.push("archive_poll_interval_secs must be greater than 0".to_string());
+        }
+        if google.archive_polling_timeout.is_zero() {
+            errors.push("archive_polling_timeout_secs must be greater than 0".to_string());
+        }
+        if google.requested_resources.is_empty() {
+            errors.push("requested_resources must not be empty".to_string());
+        }
+        let unknown_resources: Vec<&str> = google
+            .requested_resources
+            .iter()
+            .filter(|resource| !is_my_activity_resource(resource))
+            .map(|resource| resource.as_str())
+            .collect();
+        if !unknown_resources.is_empty() {
+            errors.push(format!(
+                "requested_resources must be My Activity resources (myactivity.*), not: {}",
+                unknown_resources.join(", ")
+            ));
+        }
+        if consent.privacy_notice_version.is_empty() {
+            errors.push("privacy_notice_version must not be empty".to_string());
+        }
+        // the backend itself downloads and stores the data, which requires consent to archiving
+        if !consent.purposes.contains(&ProcessingPurpose::Archiving) {
+            errors.push("processing_purposes must include archiving".to_string());
+        }
+        if storage.s3_encryption == S3Encryption::CustomerProvided
+            && !storage.s3_sse_customer_key.is_empty()
+            && STANDARD
+                .decode(&storage.s3_sse_customer_key)
+                .map_or(true, |key| key.len() != SSE_CUSTOMER_KEY_LENGTH)
+        {
+            errors.push(format!(
+                "s3_sse_customer_key must be a base64 encoded {} bytes key",
+                SSE_CUSTOMER_KEY_LENGTH
+            ));
+        }
+        if storage
+            .raw_archive_retention_days
+            .is_some_and(|days| days <= 0)
+        {
+            errors.push("raw_archive_retention_days must be greater than 0".to_string());
+        }
+        if job_queue.visibility_timeout.is_zero() {
+            errors.push("job_queue_visibility_timeout_secs must be greater than 0".to_string());
+        }
+        if job_queue.retry_delay.is_zero() {
+            errors.push("job_queue_retry_delay_secs must be greater than 0".to_string());
+        }
+        if job_queue.max_attempts == 0 || job_queue.max_in_flight_per_user == 0 {
+            errors.push(
+                "job_queue_max_attempts and job_queue_max_in_flight_per_user must be greater than 0"
+                    .to_string(),
+            );
+        }
+        if concurrency.token_exchange == 0
+            || concurrency.archive_initiation == 0
+            || concurrency.data_download == 0
+        {
+            errors.push("stage concurrencies must be greater than 0".to_string());
+        }
+
+        if let Err(e) = EnvFilter::try_new(&telemetry.log_level) {
+            errors.push(format!("log_level is invalid: {}", e));
+        }
+
+        if !errors.is_empty() {
+            return Err(PapiError::Config(format!(
+                "Invalid configuration: {}",
+                errors.join(", ")
+            )));
+        }
+
+        Ok(Self {
+            http,
+            session,
+            admin,
+            google,
+            consent,
+            storage,
+            job_queue,
+            concurrency,
+            shutdown_timeout: Duration::from_secs(
+                raw.shutdown_timeout_secs
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            ),
+            telemetry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the raw configuration is read from TOML rather than parsed from the CLI, which would also
+    // read the environment variables of the caller
+    const REQUIRED_SETTINGS: [&str; 11] = [
+        r#"cert_file_path = "cert.pem""#,
+        r#"key_file_path = "key.pem""#,
+        r#"session_secret = "a-unit-test-session-secret-of-32-bytes""#,
+        r#"google_client_id = "client-id""#,
+        r#"google_client_secret = "client-secret""#,
+        r#"redirect_uri = "http://localhost:3000/auth/callback""#,
+        r#"cors_allowed_origins = ["http://localhost:3000"]"#,
+        r#"aws_region = "eu-west-1""#,
+        r#"dynamo_db_auth_table_name = "auth""#,
+        r#"oauth_state_store = "memory""#,
+        r#"s3_bucket_name = "bucket""#,
+    ];
+
+    /// Loads the settings, each replacing the required one of the same key, if any
+    fn load(settings: &[&str]) -> PapiResult<Config> {
+        let key = |setting: &str| setting.split('=').next().unwrap().trim().to_string();
+        let mut lines: Vec<&str> = REQUIRED_SETTINGS
+            .iter()
+            .filter(|required| settings.iter().all(|s| key(s) != key(required)))
+            .copied()
+            .collect();
+        lines.extend(settings);
+        Config::try_from(toml::from_str::<RawConfig>(&lines.join("\n")).unwrap())
+    }
+
+    /// Message listing all the invalid settings
+    fn config_errors(settings: &[&str]) -> String {
+        match load(settings) {
+            Err(PapiError::Config(message)) => message,
+            Err(e) => panic!("expected a configuration error, got {}", e),
+            Ok(_) => panic!("expected a configuration error"),
+        }
+    }
+
+    #[test]
+    fn defaults_are_used_for_the_optional_settings() {
+        let config = load(&[]).unwrap();
+        assert_eq!(config.http.https_port, DEFAULT_HTTPS_PORT);
+        assert_eq!(config.session.cookie_same_site, SessionCookieSameSite::Lax);
+        assert_eq!(config.consent.purposes, vec![ProcessingPurpose::Archiving]);
+        assert_eq!(config.storage.s3_encryption, S3Encryption::S3Managed);
+        assert_eq!(config.storage.raw_archive_retention_days, None);
+        assert_eq!(
+            config.job_queue.visibility_timeout,
+            JobQueueSettings::default().visibility_timeout
+        );
+        assert_eq!(
+            config.job_queue.retry_delay,
+            JobQueueSettings::default().retry_delay
+        );
+    }
+
+    #[test]
+    fn all_missing_settings_are_reported_at_once() {
+        let errors = match Config::try_from(RawConfig::default()) {
+            Err(PapiError::Config(message)) => message,
+            _ => panic!("expected a configuration error"),
+        };
+        for name in [
+            "cert_file_path",
+            "key_file_path",
+            "session_secret",
+            "google_client_id",
+            "google_client_secret",
+            "redirect_uri",
+            "aws_region",
+            "dynamo_db_auth_table_name",
+            "dynamo_db_oauth_state_table_name",
+            "s3_bucket_name",
+        ] {
+            assert!(
+                errors.contains(&format!("{} must be set", name)),
+                "{} not reported in: {}",
+                name,
+                errors
+            );
+        }
+        assert!(errors.contains("cors_allowed_origins must list the origins of the frontend"));
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let cases = [
+            (
+                r#"session_secret = "too-short""#,
+                "session_secret must be at least 32 bytes long",
+            ),
+            ("session_ttl_secs = 0", "session_ttl_secs must be greater than 0"),
+            (
+                "archive_poll_interval_secs = 0",
+                "archive_poll_interval_secs must be greater than 0",
+            ),
+            (
+                "archive_polling_timeout_secs = 0",
+                "archive_polling_timeout_secs must be greater than 0",
+            ),
+            (
+                r#"requested_resources = ["myactivity.search", "chrome.history"]"#,
+                "requested_resources must be My Activity resources (myactivity.*), not: chrome.history",
+            ),
+            (
+                r#"processing_purposes = ["analysis"]"#,
+                "processing_purposes must include archiving",
+            ),
+            (
+                "raw_archive_retention_days = 0",
+                "raw_archive_retention_days must be greater than 0",
+            ),
+            (
+                "job_queue_visibility_timeout_secs = 0",
+                "job_queue_visibility_timeout_secs must be greater than 0",
+            ),
+            (
+                "job_queue_retry_delay_secs = 0",
+                "job_queue_retry_delay_secs must be greater than 0",
+            ),
+            (
+                "job_queue_max_attempts = 0",
+                "job_queue_max_attempts and job_queue_max_in_flight_per_user must be greater than 0",
+            ),
+            (r#"log_level = "info,=""#, "log_level is invalid"),
+        ];
+        for (setting, error) in cases {
+            let errors = config_errors(&[setting]);
+            assert!(errors.contains(error), "{}: {}", setting, errors);
+        }
+    }
+
+    #[test]
+    fn customer_provided_encryption_requires_a_valid_key() {
+        let encryption = r#"s3_encryption = "customer_provided""#;
+        assert!(config_errors(&[encryption]).contains("s3_sse_customer_key must be set"));
+        assert!(
+            config_errors(&[encryption, r#"s3_sse_customer_key = "c2hvcnQ=""#])
+                .contains("s3_sse_customer_key must be a base64 encoded 32 bytes key")
+        );
+
+        let key = STANDARD.encode([7u8; SSE_CUSTOMER_KEY_LENGTH]);
+        let key_setting = format!(r#"s3_sse_customer_key = "{}""#, key);
+        let config = load(&[encryption, &key_setting]).unwrap();
+        assert_eq!(config.storage.s3_sse_customer_key, key);
+    }
+}
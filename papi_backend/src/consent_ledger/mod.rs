@@ -0,0 +1,344 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::types::{OAuthInfo, OAuthState, Resource, UserId},
+    config::ConsentConfig,
+    error::{PapiError, PapiResult},
+};
+
+pub use sqlite::SqliteConsentLedger;
+
+mod sqlite;
+
+/// What the data of the users is processed for, as described in the privacy notice
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ProcessingPurpose {
+    /// Downloading the granted resources from Google and storing them for the user
+    Archiving,
+    /// Deriving insights from the stored data, by the pipeline consuming it
+    Analysis,
+}
+
+/// What a user consented to with an authorization, identified by the OAuth state of the
+/// authorization
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsentRecord {
+    consent_id: OAuthState,
+    user_id: UserId,
+    privacy_notice_version: String,
+    purposes: Vec<ProcessingPurpose>,
+    requested_resources: Vec<Resource>,
+    /// Only known once the authorization code is exchanged, as users can leave out resources
+    granted_resources: Option<Vec<Resource>>,
+    requested_at: i64,
+    granted_at: Option<i64>,
+    withdrawn_at: Option<i64>,
+}
+
+impl ConsentRecord {
+    /// Consent asked with the privacy notice and purposes currently in effect
+    pub fn new(
+        consent_id: OAuthState,
+        user_id: UserId,
+        consent_config: &ConsentConfig,
+        requested_resources: Vec<Resource>,
+    ) -> Self {
+        Self {
+            consent_id,
+            user_id,
+            privacy_notice_version: consent_config.privacy_notice_version.clone(),
+            purposes: consent_config.purposes.clone(),
+            requested_resources,
+            granted_resources: None,
+            requested_at: Utc::now().timestamp(),
+            granted_at: None,
+            withdrawn_at: None,
+        }
+    }
+
+    pub fn consent_id(&self) -> OAuthState {
+        self.consent_id.clone()
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id.clone()
+    }
+
+    pub fn privacy_notice_version(&self) -> String {
+        self.privacy_notice_version.clone()
+    }
+
+    pub fn is_withdrawn(&self) -> bool {
+        self.withdrawn_at.is_some()
+    }
+
+    fn grant(&mut self, granted_resources: Vec<Resource>) {
+        self.granted_resources = Some(granted_resources);
+        self.granted_at = Some(Utc::now().timestamp());
+    }
+
+    fn withdraw(&mut self) {
+        self.withdrawn_at.get_or_insert(Utc::now().timestamp());
+    }
+
+    /// Whether the consent was given for the privacy notice and purposes currently in effect
+    pub fn is_current(&self, consent_config: &ConsentConfig) -> bool {
+        self.privacy_notice_version == consent_config.privacy_notice_version
+            && consent_config
+                .purposes
+                .iter()
+                .all(|purpose| self.purposes.contains(purpose))
+    }
+
+    /// Fails unless the data of the resource can be processed for the purpose
+    pub fn permits(
+        &self,
+        consent_config: &ConsentConfig,
+        purpose: ProcessingPurpose,
+        resource: &str,
+    ) -> PapiResult<()> {
+        let refused = |reason: String| {
+            PapiError::InvalidState(format!(
+                "Consent {} of user with ID: {} {}",
+                self.consent_id, self.user_id, reason
+            ))
+        };
+        if self.is_withdrawn() {
+            return Err(refused("was withdrawn".to_string()));
+        }
+        if self.privacy_notice_version != consent_config.privacy_notice_version {
+            return Err(refused(format!(
+                "was given for privacy notice {}, the user must consent to {}",
+                self.privacy_notice_version, consent_config.privacy_notice_version
+            )));
+        }
+        if !self.purposes.contains(&purpose) {
+            return Err(refused(format!("does not cover {:?}", purpose)));
+        }
+        if !self
+            .granted_resources
+            .as_ref()
+            .is_some_and(|granted| granted.iter().any(|r| r == resource))
+        {
+            return Err(refused(format!("does not grant {}", resource)));
+        }
+        Ok(())
+    }
+}
+
+/// Where the consents are kept, as proof of what each user agreed to
+#[async_trait]
+pub trait ConsentLedger: Send + Sync {
+    /// Records the consent asked when the user is sent to Google
+    async fn record_request(&self, consent: ConsentRecord) -> PapiResult<()>;
+
+    /// Records the resources the user actually granted
+    async fn record_grant(
+        &self,
+        consent_id: &OAuthState,
+        granted_resources: Vec<Resource>,
+    ) -> PapiResult<()>;
+
+    /// Marks all the consents of a user as withdrawn, returning how many were
+    async fn withdraw_user_consents(&self, user_id: &UserId) -> PapiResult<usize>;
+
+    async fn read_consent(&self, consent_id: &OAuthState) -> PapiResult<ConsentRecord>;
+
+    /// Returns the consents of a user, oldest first
+    async fn list_user_consents(&self, user_id: &UserId) -> PapiResult<Vec<ConsentRecord>>;
+}
+
+/// The ledger along with the privacy notice and purposes currently in effect
+#[derive(Clone, Copy)]
+pub struct ConsentPolicy<'a> {
+    pub consent_ledger: &'a dyn ConsentLedger,
+    pub consent_config: &'a ConsentConfig,
+}
+
+impl ConsentPolicy<'_> {
+    /// Fails unless the authorization comes with a consent permitting the processing of the
+    /// resource for the purpose
+    pub async fn check(
+        &self,
+        oauth_info: &OAuthInfo,
+        purpose: ProcessingPurpose,
+        resource: &str,
+    ) -> PapiResult<()> {
+        match self.consent_ledger.read_consent(&oauth_info.state()).await {
+            Ok(consent) => consent.permits(self.consent_config, purpose, resource),
+            // authorizations granted before the consents were recorded
+            Err(PapiError::NotFound(_)) => Err(PapiError::InvalidState(format!(
+                "No consent recorded for the authorization of user with ID: {}",
+                oauth_info.user_id()
+            ))),
+            Err(e) => Err(e.context("could not read consent")),
+        }
+    }
+}
+
+/// The consents of a user, and whether they must be asked again
+#[derive(Debug, Serialize)]
+pub struct ConsentStatus {
+    privacy_notice_version: String,
+    purposes: Vec<ProcessingPurpose>,
+    /// Set when the latest consent is missing, withdrawn or given for an outdated privacy notice
+    reprompt_required: bool,
+    consents: Vec<ConsentRecord>,
+}
+
+impl ConsentStatus {
+    pub fn new(consent_config: &ConsentConfig, consents: Vec<ConsentRecord>) -> Self {
+        let reprompt_required = !consents
+            .iter()
+            .rev()
+            .find(|consent| consent.granted_at.is_some())
+            .is_some_and(|consent| !consent.is_withdrawn() && consent.is_current(consent_config));
+        Self {
+            privacy_notice_version: consent_config.privacy_notice_version.clone(),
+            purposes: consent_config.purposes.clone(),
+            reprompt_required,
+            consents,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOURCE: &str = "myactivity.search";
+
+    fn consent_config(privacy_notice_version: &str) -> ConsentConfig {
+        ConsentConfig {
+            privacy_notice_version: privacy_notice_version.to_string(),
+            purposes: vec![ProcessingPurpose::Archiving],
+        }
+    }
+
+    fn granted_consent(consent_config: &ConsentConfig) -> ConsentRecord {
+        let mut consent = ConsentRecord::new(
+            "state".to_string(),
+            "user".to_string(),
+            consent_config,
+            vec![RESOURCE.to_string(), "myactivity.shopping".to_string()],
+        );
+        consent.grant(vec![RESOURCE.to_string()]);
+        consent
+    }
+
+    /// Reason given for refusing the processing, or `None` if it is permitted
+    fn refusal(
+        consent: &ConsentRecord,
+        consent_config: &ConsentConfig,
+        purpose: ProcessingPurpose,
+        resource: &str,
+    ) -> Option<String> {
+        match consent.permits(consent_config, purpose, resource) {
+            Ok(()) => None,
+            Err(PapiError::InvalidState(reason)) => Some(reason),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn consent_permits_the_granted_resources_for_the_covered_purposes() {
+        let config = consent_config("1");
+        let consent = granted_consent(&config);
+        assert_eq!(
+            refusal(&consent, &config, ProcessingPurpose::Archiving, RESOURCE),
+            None
+        );
+    }
+
+    #[test]
+    fn consent_refusals_give_the_reason() {
+        let config = consent_config("1");
+        let consent = granted_consent(&config);
+        let requested = ConsentRecord::new(
+            "state".to_string(),
+            "user".to_string(),
+            &config,
+            vec![RESOURCE.to_string()],
+        );
+        let mut withdrawn = granted_consent(&config);
+        withdrawn.withdraw();
+
+        let cases = [
+            (
+                &requested,
+                &config,
+                ProcessingPurpose::Archiving,
+                RESOURCE,
+                "does not grant myactivity.search",
+            ),
+            (
+                &consent,
+                &config,
+                ProcessingPurpose::Archiving,
+                "myactivity.shopping",
+                "does not grant myactivity.shopping",
+            ),
+            (
+                &consent,
+                &config,
+                ProcessingPurpose::Analysis,
+                RESOURCE,
+                "does not cover Analysis",
+            ),
+            (
+                &consent,
+                &consent_config("2"),
+                ProcessingPurpose::Archiving,
+                RESOURCE,
+                "was given for privacy notice 1, the user must consent to 2",
+            ),
+            (
+                &withdrawn,
+                &config,
+                ProcessingPurpose::Archiving,
+                RESOURCE,
+                "was withdrawn",
+            ),
+        ];
+        for (consent, config, purpose, resource, reason) in cases {
+            let refusal = refusal(consent, config, purpose, resource)
+                .unwrap_or_else(|| panic!("{:?} of {} was permitted", purpose, resource));
+            assert!(refusal.ends_with(reason), "{}", refusal);
+        }
+    }
+
+    #[test]
+    fn reprompt_is_required_unless_the_last_granted_consent_is_current() {
+        let config = consent_config("1");
+        assert!(ConsentStatus::new(&config, vec![]).reprompt_required);
+        assert!(!ConsentStatus::new(&config, vec![granted_consent(&config)]).reprompt_required);
+
+        // the consent being asked does not replace the one already granted
+        let requested = ConsentRecord::new(
+            "other-state".to_string(),
+            "user".to_string(),
+            &config,
+            vec![RESOURCE.to_string()],
+        );
+        assert!(
+            !ConsentStatus::new(&config, vec![granted_consent(&config), requested])
+                .reprompt_required
+        );
+
+        assert!(
+            ConsentStatus::new(&consent_config("2"), vec![granted_consent(&config)])
+                .reprompt_required
+        );
+        let mut withdrawn = granted_consent(&config);
+        withdrawn.withdraw();
+        assert!(ConsentStatus::new(&config, vec![withdrawn]).reprompt_required);
+        let mut analysis = config.clone();
+        analysis.purposes.push(ProcessingPurpose::Analysis);
+        assert!(ConsentStatus::new(&analysis, vec![granted_consent(&config)]).reprompt_required);
+    }
+}
@@ -0,0 +1,173 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    api::types::{OAuthState, Resource, UserId},
+    error::{PapiError, PapiResult},
+    sqlite::SqliteDb,
+};
+
+use super::{ConsentLedger, ConsentRecord};
+
+pub struct SqliteConsentLedger {
+    db: SqliteDb,
+}
+
+impl SqliteConsentLedger {
+    pub fn setup(db_path: &str) -> PapiResult<Self> {
+        Self::new(SqliteDb::open(db_path, "consent ledger")?)
+    }
+
+    pub fn new(connection: Connection) -> PapiResult<Self> {
+        let db = SqliteDb::new(
+            connection,
+            "consent ledger",
+            "CREATE TABLE IF NOT EXISTS consents (
+                consent_id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                requested_at INTEGER NOT NULL,
+                payload TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS consents_user ON consents (user_id, requested_at);",
+        )?;
+
+        Ok(Self { db })
+    }
+}
+
+fn serialize(consent: &ConsentRecord) -> PapiResult<String> {
+    serde_json::to_string(consent)
+        .map_err(|e| PapiError::Storage(format!("Failed to serialize consent: {}", e)))
+}
+
+fn deserialize(payload: &str) -> PapiResult<ConsentRecord> {
+    serde_json::from_str(payload)
+        .map_err(|e| PapiError::Storage(format!("Failed to deserialize consent: {}", e)))
+}
+
+fn read(connection: &Connection, consent_id: &str) -> PapiResult<ConsentRecord> {
+    let payload: Option<String> = connection
+        .query_row(
+            "SELECT payload FROM consents WHERE consent_id = ?1",
+            params![consent_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| PapiError::Storage(format!("Error reading consent: {}", e)))?;
+    match payload {
+        Some(payload) => deserialize(&payload),
+        None => Err(PapiError::NotFound(format!(
+            "Consent {} not found",
+            consent_id
+        ))),
+    }
+}
+
+fn write(connection: &Connection, consent: &ConsentRecord) -> PapiResult<()> {
+    connection
+        .execute(
+            "UPDATE consents SET payload = ?2 WHERE consent_id = ?1",
+            params![consent.consent_id, serialize(consent)?],
+        )
+        .map_err(|e| PapiError::Storage(format!("Error updating consent: {}", e)))?;
+    Ok(())
+}
+
+fn list(connection: &Connection, user_id: &str) -> PapiResult<Vec<ConsentRecord>> {
+    let mut statement = connection
+        .prepare("SELECT payload FROM consents WHERE user_id = ?1 ORDER BY requested_at, rowid")
+        .map_err(|e| PapiError::Storage(format!("Error preparing consents query: {}", e)))?;
+    let payloads = statement
+        .query_map(params![user_id], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| PapiError::Storage(format!("Error listing consents: {}", e)))?;
+    payloads
+        .iter()
+        .map(|payload| deserialize(payload))
+        .collect()
+}
+
+#[async_trait]
+impl ConsentLedger for SqliteConsentLedger {
+    async fn record_request(&self, consent: ConsentRecord) -> PapiResult<()> {
+        let payload = serialize(&consent)?;
+
+        self.db
+            .with_connection(move |connection| {
+                connection
+                    .execute(
+                        "INSERT INTO consents (consent_id, user_id, requested_at, payload)
+                        VALUES (?1, ?2, ?3, ?4)",
+                        params![
+                            consent.consent_id,
+                            consent.user_id,
+                            consent.requested_at,
+                            payload
+                        ],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error inserting consent: {}", e)))?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn record_grant(
+        &self,
+        consent_id: &OAuthState,
+        granted_resources: Vec<Resource>,
+    ) -> PapiResult<()> {
+        let consent_id = consent_id.clone();
+
+        self.db
+            .with_connection(move |connection| {
+                let tx = connection.transaction().map_err(|e| {
+                    PapiError::Storage(format!("Error starting transaction: {}", e))
+                })?;
+                let mut consent = read(&tx, &consent_id)?;
+                consent.grant(granted_resources);
+                write(&tx, &consent)?;
+                tx.commit()
+                    .map_err(|e| PapiError::Storage(format!("Error committing transaction: {}", e)))
+            })
+            .await
+    }
+
+    async fn withdraw_user_consents(&self, user_id: &UserId) -> PapiResult<usize> {
+        let user_id = user_id.clone();
+
+        self.db
+            .with_connection(move |connection| {
+                let tx = connection.transaction().map_err(|e| {
+                    PapiError::Storage(format!("Error starting transaction: {}", e))
+                })?;
+                let mut withdrawn = 0;
+                for mut consent in list(&tx, &user_id)? {
+                    if consent.is_withdrawn() {
+                        continue;
+                    }
+                    consent.withdraw();
+                    write(&tx, &consent)?;
+                    withdrawn += 1;
+                }
+                tx.commit().map_err(|e| {
+                    PapiError::Storage(format!("Error committing transaction: {}", e))
+                })?;
+                Ok(withdrawn)
+            })
+            .await
+    }
+
+    async fn read_consent(&self, consent_id: &OAuthState) -> PapiResult<ConsentRecord> {
+        let consent_id = consent_id.clone();
+        self.db
+            .with_connection(move |connection| read(connection, &consent_id))
+            .await
+    }
+
+    async fn list_user_consents(&self, user_id: &UserId) -> PapiResult<Vec<ConsentRecord>> {
+        let user_id = user_id.clone();
+        self.db
+            .with_connection(move |connection| list(connection, &user_id))
+            .await
+    }
+}
@@ -0,0 +1,197 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    api::types::{RevocationReceipt, UserId},
+    audit_log::AuditLog,
+    auth_db_client::AuthDbClient,
+    consent_ledger::ConsentLedger,
+    error::{PapiError, PapiResult},
+    job_queue::JobQueue,
+    oauth_client::OAuthClient,
+    oauth_state_store::OAuthStateStore,
+    papi_line_client::PapiLineClient,
+    session::SessionStore,
+    worker_pool::UserLocks,
+};
+
+/// Proof that all the data held for a user has been deleted
+#[derive(Debug, Serialize)]
+pub struct ErasureReceipt {
+    receipt_id: String,
+    user_id: UserId,
+    erased_at: i64,
+    deleted_jobs: usize,
+    deleted_pending_authorizations: usize,
+    deleted_auth_records: usize,
+    /// Consents are kept as proof of what the user agreed to, but marked as withdrawn
+    withdrawn_consents: usize,
+    /// Events of the append-only audit log, kept as proof of what was done with the data of the
+    /// user. The erasure itself is recorded there as well
+    kept_audit_events: usize,
+    deleted_files: usize,
+}
+
+/// Everything holding data of a user
+pub struct UserDataStores {
+    pub auth_db_client: Arc<dyn AuthDbClient>,
+    pub oauth_client: Arc<OAuthClient>,
+    pub papi_line_client: Arc<PapiLineClient>,
+    pub job_queue: Arc<dyn JobQueue>,
+    pub oauth_state_store: Arc<dyn OAuthStateStore>,
+    pub audit_log: Arc<dyn AuditLog>,
+    pub consent_ledger: Arc<dyn ConsentLedger>,
+    pub session_store: Arc<dyn SessionStore>,
+    pub user_locks: Arc<UserLocks>,
+}
+
+/// Disconnects the Google account of a user and marks their authorization as revoked, deleting
+/// the stored files if requested
+pub async fn revoke_authorization(
+    stores: &UserDataStores,
+    user_id: &UserId,
+    delete_data: bool,
+) -> PapiResult<RevocationReceipt> {
+    // no new work is started for the user from now on
+    let cancelled_jobs = stores
+        .job_queue
+        .cancel_user_jobs(user_id)
+        .await
+        .map_err(|e| e.context("could not cancel jobs"))?;
+
+    // waits for the jobs of the user already running, which store their results under the lock
+    let _user_lock = stores.user_locks.lock(user_id).await;
+    let mut oauth_info = stores
+        .auth_db_client
+        .read_last_auth_for_user(user_id.clone())
+        .await
+        .map_err(|e| e.context("could not read last auth for user"))?;
+
+    stores
+        .oauth_client
+        .disconnect(&oauth_info)
+        .await
+        .map_err(|e| e.context("could not disconnect Google account"))?;
+
+    if !oauth_info.is_revoked() {
+        oauth_info.revoke();
+        stores
+            .auth_db_client
+            .update_auth_for_user(user_id.clone(), oauth_info)
+            .await
+            .map_err(|e| e.context("could not store revoked OAuth info"))?;
+    }
+    let withdrawn_consents = stores
+        .consent_ledger
+        .withdraw_user_consents(user_id)
+        .await
+        .map_err(|e| e.context("could not withdraw consents"))?;
+
+    // last, once no job of the user can store files anymore
+    let deleted_files = if delete_data {
+        Some(
+            stores
+                .papi_line_client
+                .delete_user_files(user_id)
+                .await
+                .map_err(|e| e.context("could not delete stored data"))?,
+        )
+    } else {
+        None
+    };
+
+    info!(
+        cancelled_jobs,
+        withdrawn_consents,
+        ?deleted_files,
+        "Revoked authorization"
+    );
+
+    Ok(RevocationReceipt::new(
+        user_id.clone(),
+        cancelled_jobs,
+        withdrawn_consents,
+        deleted_files,
+    ))
+}
+
+/// Deletes all the data held for a user, after disconnecting their Google account.
+///
+/// Erasing a user without any data succeeds, so that an interrupted erasure can be run again.
+pub async fn erase_user(stores: &UserDataStores, user_id: &UserId) -> PapiResult<ErasureReceipt> {
+    // the session tokens stay valid until they expire, so they are revoked for the user ID not to
+    // be used again
+    stores
+        .session_store
+        .revoke_user_sessions(user_id)
+        .await
+        .map_err(|e| e.context("could not revoke sessions"))?;
+    // no new work is started for the user from now on
+    let deleted_jobs = stores
+        .job_queue
+        .purge_user_jobs(user_id)
+        .await
+        .map_err(|e| e.context("could not delete jobs"))?;
+    let deleted_pending_authorizations = stores
+        .oauth_state_store
+        .remove_user(user_id)
+        .await
+        .map_err(|e| e.context("could not delete pending authorizations"))?;
+
+    // waits for the jobs of the user already running, which store their results under the lock
+    let _user_lock = stores.user_locks.lock(user_id).await;
+    match stores
+        .auth_db_client
+        .read_last_auth_for_user(user_id.clone())
+        .await
+    {
+        Ok(oauth_info) => stores
+            .oauth_client
+            .disconnect(&oauth_info)
+            .await
+            .map_err(|e| e.context("could not disconnect Google account"))?,
+        Err(PapiError::NotFound(_)) => {}
+        Err(e) => return Err(e.context("could not read last auth for user")),
+    }
+    let deleted_auth_records = stores
+        .auth_db_client
+        .delete_auth_for_user(user_id)
+        .await
+        .map_err(|e| e.context("could not delete authorization history"))?;
+    let withdrawn_consents = stores
+        .consent_ledger
+        .withdraw_user_consents(user_id)
+        .await
+        .map_err(|e| e.context("could not withdraw consents"))?;
+    let kept_audit_events = stores
+        .audit_log
+        .list_user_events(user_id)
+        .await
+        .map_err(|e| e.context("could not read audit log"))?
+        .len();
+
+    // last, once no job of the user can store files anymore
+    let deleted_files = stores
+        .papi_line_client
+        .delete_user_files(user_id)
+        .await
+        .map_err(|e| e.context("could not delete stored files"))?;
+
+    let receipt = ErasureReceipt {
+        receipt_id: Uuid::new_v4().to_string(),
+        user_id: user_id.clone(),
+        erased_at: Utc::now().timestamp(),
+        deleted_jobs,
+        deleted_pending_authorizations,
+        deleted_auth_records,
+        withdrawn_consents,
+        kept_audit_events,
+        deleted_files,
+    };
+    info!(user_id = %user_id, ?receipt, "Erased all data of user");
+
+    Ok(receipt)
+}
@@ -0,0 +1,134 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde_json::json;
+use thiserror::Error;
+use tracing::error;
+
+pub type PapiResult<T> = Result<T, PapiError>;
+
+#[derive(Debug, Error)]
+pub enum PapiError {
+    /// The request is malformed
+    #[error("{0}")]
+    InvalidRequest(String),
+    /// The caller could not be identified
+    #[error("{0}")]
+    Unauthorized(String),
+    /// The caller is identified but not allowed to perform the action
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    /// The request or job does not match the current state of the user's authorization
+    #[error("{0}")]
+    InvalidState(String),
+    /// The backend cannot accept more work for now
+    #[error("{0}")]
+    Unavailable(String),
+    /// A call to an external service failed but might succeed if retried
+    #[error("{0}")]
+    UpstreamTransient(String),
+    /// An external service rejected the call, retrying it will not help
+    #[error("{0}")]
+    UpstreamPermanent(String),
+    #[error("{0}")]
+    Storage(String),
+    #[error("{0}")]
+    Config(String),
+}
+
+impl PapiError {
+    /// Prefixes the message with the given context, keeping the category of the error
+    pub fn context(self, context: &str) -> Self {
+        let wrap = |message: String| format!("{}: {}", context, message);
+        match self {
+            PapiError::InvalidRequest(m) => PapiError::InvalidRequest(wrap(m)),
+            PapiError::Unauthorized(m) => PapiError::Unauthorized(wrap(m)),
+            PapiError::Forbidden(m) => PapiError::Forbidden(wrap(m)),
+            PapiError::NotFound(m) => PapiError::NotFound(wrap(m)),
+            PapiError::InvalidState(m) => PapiError::InvalidState(wrap(m)),
+            PapiError::Unavailable(m) => PapiError::Unavailable(wrap(m)),
+            PapiError::UpstreamTransient(m) => PapiError::UpstreamTransient(wrap(m)),
+            PapiError::UpstreamPermanent(m) => PapiError::UpstreamPermanent(wrap(m)),
+            PapiError::Storage(m) => PapiError::Storage(wrap(m)),
+            PapiError::Config(m) => PapiError::Config(wrap(m)),
+        }
+    }
+
+    /// Maps a non-successful response of an external service to the matching category
+    pub fn from_upstream_status(status: u16, message: String) -> Self {
+        match status {
+            408 | 429 | 500..=599 => PapiError::UpstreamTransient(message),
+            _ => PapiError::UpstreamPermanent(message),
+        }
+    }
+
+    pub fn category(&self) -> &'static str {
+        match self {
+            PapiError::InvalidRequest(_)
+            | PapiError::Unauthorized(_)
+            | PapiError::Forbidden(_)
+            | PapiError::NotFound(_)
+            | PapiError::InvalidState(_) => "client_error",
+            PapiError::Unavailable(_) => "unavailable",
+            PapiError::UpstreamTransient(_) => "upstream_transient",
+            PapiError::UpstreamPermanent(_) => "upstream_permanent",
+            PapiError::Storage(_) => "storage",
+            PapiError::Config(_) => "config",
+        }
+    }
+
+    /// Whether the failed operation might succeed when attempted again later
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            PapiError::Unavailable(_) | PapiError::UpstreamTransient(_) | PapiError::Storage(_)
+        )
+    }
+}
+
+impl From<reqwest::Error> for PapiError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() {
+            return PapiError::UpstreamTransient(e.to_string());
+        }
+        match e.status() {
+            Some(status) => PapiError::from_upstream_status(status.as_u16(), e.to_string()),
+            None => PapiError::UpstreamPermanent(e.to_string()),
+        }
+    }
+}
+
+impl ResponseError for PapiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PapiError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            PapiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            PapiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            PapiError::NotFound(_) => StatusCode::NOT_FOUND,
+            PapiError::InvalidState(_) => StatusCode::CONFLICT,
+            PapiError::Unavailable(_) | PapiError::UpstreamTransient(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            PapiError::UpstreamPermanent(_) => StatusCode::BAD_GATEWAY,
+            PapiError::Storage(_) | PapiError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // internal details are only logged, not leaked to the caller
+        let message = match self {
+            PapiError::Storage(_) | PapiError::Config(_) => {
+                error!(error = %self, "Internal error");
+                "Internal server error".to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": {
+                "category": self.category(),
+                "message": message,
+            }
+        }))
+    }
+}
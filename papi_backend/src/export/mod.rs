@@ -0,0 +1,120 @@
+use async_zip::{base::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use chrono::Utc;
+use futures::io::AsyncWriteExt;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tracing::warn;
+
+use crate::{
+    admin::{read_authorization_history, AuthorizationHistory},
+    api::types::UserId,
+    erasure::UserDataStores,
+    error::{PapiError, PapiResult},
+    papi_line_client::PapiLineClient,
+};
+use records::normalize_my_activity;
+
+mod records;
+
+// name of the files of the archives holding the activity records
+const MY_ACTIVITY_FILE_NAME: &str = "MyActivity.json";
+
+/// Everything held for a user, except for the content of their files which is streamed
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    exported_at: i64,
+    #[serde(flatten)]
+    authorization_history: AuthorizationHistory,
+    #[serde(skip)]
+    file_keys: Vec<String>,
+}
+
+impl UserExport {
+    /// Collects what has to be exported, so that missing data is reported before streaming starts
+    pub async fn prepare(stores: &UserDataStores, user_id: &UserId) -> PapiResult<Self> {
+        let authorization_history = read_authorization_history(stores, user_id)
+            .await
+            .map_err(|e| e.context("could not read authorization history"))?;
+        let file_keys = stores
+            .papi_line_client
+            .list_user_files(user_id)
+            .await
+            .map_err(|e| e.context("could not list stored files"))?;
+
+        Ok(Self {
+            exported_at: Utc::now().timestamp(),
+            authorization_history,
+            file_keys,
+        })
+    }
+
+    /// Writes the export as a ZIP archive, copying one stored file at a time, along with the
+    /// normalized records of each activity file
+    pub async fn write_zip<W: AsyncWrite + Unpin>(
+        self,
+        stores: Arc<UserDataStores>,
+        writer: W,
+    ) -> PapiResult<()> {
+        let zip_error = |e: async_zip::error::ZipError| {
+            PapiError::Storage(format!("could not write export archive: {}", e))
+        };
+        let io_error = |e: std::io::Error| {
+            PapiError::Storage(format!("could not write export archive: {}", e))
+        };
+
+        let mut zip = ZipFileWriter::with_tokio(writer);
+
+        let summary = serde_json::to_vec_pretty(&self).map_err(|e| {
+            PapiError::Storage(format!("Failed to serialize authorization history: {}", e))
+        })?;
+        zip.write_entry_whole(
+            ZipEntryBuilder::new("authorization_history.json".into(), Compression::Deflate),
+            &summary,
+        )
+        .await
+        .map_err(zip_error)?;
+
+        for key in &self.file_keys {
+            let file = stores.papi_line_client.get_file(key).await?;
+            let entry = ZipEntryBuilder::new(
+                format!("files/{}", PapiLineClient::user_file_path(key)).into(),
+                Compression::Deflate,
+            );
+            let mut entry_writer = zip.write_entry_stream(entry).await.map_err(zip_error)?;
+            futures::io::copy(file.into_async_read().compat(), &mut entry_writer)
+                .await
+                .map_err(io_error)?;
+            entry_writer.close().await.map_err(zip_error)?;
+
+            let path = PapiLineClient::user_file_path(key);
+            if !path.ends_with(&format!("/{}", MY_ACTIVITY_FILE_NAME)) {
+                continue;
+            }
+            let file = stores.papi_line_client.get_file(key).await?;
+            let content = file
+                .collect()
+                .await
+                .map_err(|e| PapiError::Storage(format!("could not read file {}: {}", key, e)))?
+                .into_bytes();
+            let records = match normalize_my_activity(&content) {
+                Ok(records) => records,
+                // the file itself is exported as is
+                Err(e) => {
+                    warn!(key = %key, error = %e, "Skipping records of unreadable activity file");
+                    continue;
+                }
+            };
+            zip.write_entry_whole(
+                ZipEntryBuilder::new(format!("records/{}l", path).into(), Compression::Deflate),
+                &records,
+            )
+            .await
+            .map_err(zip_error)?;
+        }
+
+        let mut writer = zip.close().await.map_err(zip_error)?;
+        writer.close().await.map_err(io_error)
+    }
+}
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{PapiError, PapiResult};
+
+/// Activity record of a My Activity file, keeping the fields the data pipeline normalizes and
+/// setting the missing ones to null
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ActivityRecord {
+    header: Option<String>,
+    title: Option<String>,
+    title_url: Option<String>,
+    subtitles: Option<Vec<NamedItem>>,
+    description: Option<String>,
+    time: Option<String>,
+    details: Option<Vec<NamedItem>>,
+    location_infos: Option<Vec<LocationInfo>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NamedItem {
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LocationInfo {
+    name: Option<String>,
+    url: Option<String>,
+    source: Option<String>,
+    source_url: Option<String>,
+}
+
+/// Normalizes the records of a My Activity file, returned as JSON Lines
+pub fn normalize_my_activity(content: &[u8]) -> PapiResult<Vec<u8>> {
+    let records: Vec<ActivityRecord> = serde_json::from_slice(content)
+        .map_err(|e| PapiError::InvalidState(format!("Invalid activity file: {}", e)))?;
+    let mut lines = vec![];
+    for record in records {
+        serde_json::to_writer(&mut lines, &record).map_err(|e| {
+            PapiError::Storage(format!("Failed to serialize activity record: {}", e))
+        })?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
@@ -0,0 +1,62 @@
+use serde::Serialize;
+use std::{collections::BTreeMap, future::Future};
+use tokio::time::{timeout, Duration};
+
+use crate::{erasure::UserDataStores, error::PapiResult, worker_pool::WorkerStatus};
+
+// a dependency slower than this is considered unreachable
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const OK: &str = "ok";
+
+/// Outcome of the readiness checks, with the error of every failed check
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, String>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+}
+
+/// Checks whether the instance can serve traffic, i.e. its storage can be reached and the worker
+/// pool is processing jobs
+pub async fn check_readiness(stores: &UserDataStores, worker_status: &WorkerStatus) -> Readiness {
+    let (auth_db, oauth_state_store, file_store) = futures::join!(
+        check(stores.auth_db_client.check_reachable()),
+        check(stores.oauth_state_store.check_reachable()),
+        check(stores.papi_line_client.check_reachable()),
+    );
+
+    let checks = BTreeMap::from([
+        ("auth_db", auth_db),
+        ("oauth_state_store", oauth_state_store),
+        ("file_store", file_store),
+        // the server only starts once the whole configuration has been validated
+        ("config", OK.to_string()),
+        (
+            "worker_pool",
+            if worker_status.is_running() {
+                OK.to_string()
+            } else {
+                "not running".to_string()
+            },
+        ),
+    ]);
+
+    Readiness {
+        ready: checks.values().all(|status| status == OK),
+        checks,
+    }
+}
+
+async fn check(reachable: impl Future<Output = PapiResult<()>>) -> String {
+    match timeout(CHECK_TIMEOUT, reachable).await {
+        Ok(Ok(())) => OK.to_string(),
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => format!("timed out after {:?}", CHECK_TIMEOUT),
+    }
+}
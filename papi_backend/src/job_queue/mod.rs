@@ -0,0 +1,308 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::{
+    api::types::{OAuthInfo, Resource, UserId},
+    error::PapiResult,
+};
+
+pub use sqlite::SqliteJobQueue;
+
+mod sqlite;
+
+pub type JobId = String;
+
+pub type Receipt = String;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobKind {
+    TokenExchange,
+    ArchiveInitiation,
+    ArchivePolling,
+    DataDownload,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::TokenExchange => "token_exchange",
+            JobKind::ArchiveInitiation => "archive_initiation",
+            JobKind::ArchivePolling => "archive_polling",
+            JobKind::DataDownload => "data_download",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitiationInfo {
+    user_id: UserId,
+    resource: Resource,
+}
+
+impl InitiationInfo {
+    pub fn new(user_id: UserId, resource: Resource) -> Self {
+        Self { user_id, resource }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id.clone()
+    }
+
+    pub fn resource(&self) -> Resource {
+        self.resource.clone()
+    }
+}
+
+/// A check of the state of an archive job, enqueued again until the archive is ready
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollingInfo {
+    user_id: UserId,
+    resource: Resource,
+    archive_job_id: String,
+    // jobs enqueued before the start of the polling was recorded do not have one
+    #[serde(default)]
+    polling_started_at: Option<i64>,
+}
+
+impl PollingInfo {
+    pub fn new(user_id: UserId, resource: Resource, archive_job_id: String) -> Self {
+        Self {
+            user_id,
+            resource,
+            archive_job_id,
+            polling_started_at: Some(Utc::now().timestamp_millis()),
+        }
+    }
+
+    /// Milliseconds since the first check of the archive job, if known
+    pub fn polling_duration(&self) -> Option<i64> {
+        self.polling_started_at
+            .map(|started_at| Utc::now().timestamp_millis() - started_at)
+    }
+
+    /// Counts the polling of the jobs enqueued before its start was recorded from now on, so that
+    /// they are given up eventually as well
+    pub fn record_polling_start(&mut self) {
+        self.polling_started_at
+            .get_or_insert_with(|| Utc::now().timestamp_millis());
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id.clone()
+    }
+
+    pub fn resource(&self) -> Resource {
+        self.resource.clone()
+    }
+
+    pub fn archive_job_id(&self) -> String {
+        self.archive_job_id.clone()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadInfo {
+    user_id: UserId,
+    resource: Resource,
+    // jobs enqueued before the archive job ID was recorded do not have one
+    #[serde(default)]
+    archive_job_id: Option<String>,
+    download_url: Result<String, String>,
+}
+
+impl DownloadInfo {
+    pub fn new(
+        user_id: UserId,
+        resource: Resource,
+        archive_job_id: String,
+        download_url: Result<String, String>,
+    ) -> Self {
+        Self {
+            user_id,
+            resource,
+            archive_job_id: Some(archive_job_id),
+            download_url,
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        self.user_id.clone()
+    }
+
+    pub fn resource(&self) -> Resource {
+        self.resource.clone()
+    }
+
+    pub fn archive_job_id(&self) -> Option<String> {
+        self.archive_job_id.clone()
+    }
+
+    pub fn download_url(&self) -> Result<String, String> {
+        self.download_url.clone()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Job {
+    TokenExchange(OAuthInfo),
+    ArchiveInitiation(InitiationInfo),
+    ArchivePolling(PollingInfo),
+    DataDownload(DownloadInfo),
+}
+
+impl Job {
+    pub fn kind(&self) -> JobKind {
+        match self {
+            Job::TokenExchange(_) => JobKind::TokenExchange,
+            Job::ArchiveInitiation(_) => JobKind::ArchiveInitiation,
+            Job::ArchivePolling(_) => JobKind::ArchivePolling,
+            Job::DataDownload(_) => JobKind::DataDownload,
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        match self {
+            Job::TokenExchange(oauth_info) => oauth_info.user_id(),
+            Job::ArchiveInitiation(initiation_info) => initiation_info.user_id(),
+            Job::ArchivePolling(polling_info) => polling_info.user_id(),
+            Job::DataDownload(download_info) => download_info.user_id(),
+        }
+    }
+}
+
+/// A job handed out by [`JobQueue::receive`].
+///
+/// The job stays invisible to other consumers until either the visibility timeout expires or
+/// the job is acknowledged or rejected using its receipt.
+#[derive(Debug, Clone)]
+pub struct ReceivedJob {
+    id: JobId,
+    receipt: Receipt,
+    attempts: u32,
+    job: Job,
+}
+
+impl ReceivedJob {
+    pub fn id(&self) -> JobId {
+        self.id.clone()
+    }
+
+    pub fn receipt(&self) -> Receipt {
+        self.receipt.clone()
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn job(&self) -> &Job {
+        &self.job
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting to be received, possibly after a backoff
+    Pending,
+    /// Received and not yet acknowledged
+    InFlight,
+    DeadLettered,
+}
+
+/// A job still in the queue, as shown to the operators
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRecord {
+    pub id: JobId,
+    pub kind: String,
+    pub state: JobState,
+    pub attempts: u32,
+    pub created_at: i64,
+    pub visible_at: i64,
+    pub last_error: Option<String>,
+}
+
+/// Number of jobs of a kind in a state
+#[derive(Debug, Clone, Serialize)]
+pub struct JobCount {
+    pub kind: String,
+    pub state: JobState,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobQueueSettings {
+    /// How long a received job stays hidden from other consumers before it is delivered again,
+    /// unless its consumer extends it
+    pub visibility_timeout: Duration,
+    /// Number of deliveries after which a job is moved to the dead letter queue
+    pub max_attempts: u32,
+    /// Delay before a rejected job becomes visible again, multiplied by the number of attempts
+    pub retry_delay: Duration,
+    /// Maximum number of pending jobs per kind, enqueueing beyond it fails
+    pub capacity: usize,
+    /// Maximum number of jobs of the same kind a single user can have in flight at once
+    pub max_in_flight_per_user: u32,
+}
+
+impl Default for JobQueueSettings {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: Duration::from_secs(30 * 60),
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(30),
+            capacity: 10_000,
+            max_in_flight_per_user: 1,
+        }
+    }
+}
+
+/// Persistent queue connecting the HTTP handlers with the background work.
+///
+/// Delivery is at-least-once: a job is only removed once acknowledged, so handlers must tolerate
+/// processing the same job more than once.
+#[async_trait]
+pub trait JobQueue: Send + Sync {
+    async fn enqueue(&self, job: Job) -> PapiResult<JobId>;
+
+    /// Enqueues a job that only becomes visible once the delay has elapsed
+    async fn enqueue_after(&self, job: Job, delay: Duration) -> PapiResult<JobId>;
+
+    /// Returns a visible job of the given kind, if any.
+    ///
+    /// Jobs of users with the fewest jobs in flight are handed out first, so that a single user
+    /// with many pending jobs cannot starve the others.
+    async fn receive(&self, kind: JobKind) -> PapiResult<Option<ReceivedJob>>;
+
+    /// Keeps a job being processed hidden from other consumers for another visibility timeout
+    async fn extend_visibility(&self, job: &ReceivedJob) -> PapiResult<()>;
+
+    /// Removes a successfully processed job from the queue
+    async fn ack(&self, job: &ReceivedJob) -> PapiResult<()>;
+
+    /// Makes a failed job visible again after a backoff, or dead-letters it once it ran out of attempts
+    async fn nack(&self, job: &ReceivedJob, error: &str) -> PapiResult<()>;
+
+    /// Makes a job whose processing was interrupted visible again right away, without counting
+    /// the attempt
+    async fn release(&self, job: &ReceivedJob) -> PapiResult<()>;
+
+    /// Moves a job that cannot succeed to the dead letter queue, regardless of its attempts
+    async fn dead_letter(&self, job: &ReceivedJob, error: &str) -> PapiResult<()>;
+
+    /// Removes all the pending and in-flight jobs of a user, returning how many were removed.
+    ///
+    /// Jobs already being processed are not interrupted, but can no longer be acknowledged.
+    async fn cancel_user_jobs(&self, user_id: &UserId) -> PapiResult<usize>;
+
+    /// Removes all the jobs of a user, dead-lettered ones included, returning how many were removed
+    async fn purge_user_jobs(&self, user_id: &UserId) -> PapiResult<usize>;
+
+    /// Returns the jobs of a user still in the queue, dead-lettered ones included, oldest first
+    async fn list_user_jobs(&self, user_id: &UserId) -> PapiResult<Vec<JobRecord>>;
+
+    /// Counts the jobs in the queue by kind and state
+    async fn count_jobs(&self) -> PapiResult<Vec<JobCount>>;
+}
@@ -0,0 +1,626 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use tokio::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    api::types::UserId,
+    error::{PapiError, PapiResult},
+    sqlite::SqliteDb,
+};
+
+use super::{
+    Job, JobCount, JobId, JobKind, JobQueue, JobQueueSettings, JobRecord, JobState, ReceivedJob,
+};
+
+// state of a job as derived from its columns, `?1` being the current time
+const JOB_STATE_SQL: &str = "CASE
+    WHEN dead_lettered_at IS NOT NULL THEN 'dead_lettered'
+    WHEN receipt IS NOT NULL AND visible_at > ?1 THEN 'in_flight'
+    ELSE 'pending'
+END";
+
+fn job_state(state: &str) -> JobState {
+    match state {
+        "dead_lettered" => JobState::DeadLettered,
+        "in_flight" => JobState::InFlight,
+        _ => JobState::Pending,
+    }
+}
+
+pub struct SqliteJobQueue {
+    db: SqliteDb,
+    settings: JobQueueSettings,
+}
+
+impl SqliteJobQueue {
+    pub fn setup(db_path: &str, settings: JobQueueSettings) -> PapiResult<Self> {
+        Self::new(SqliteDb::open(db_path, "job queue")?, settings)
+    }
+
+    pub fn new(connection: Connection, settings: JobQueueSettings) -> PapiResult<Self> {
+        let db = SqliteDb::new(
+            connection,
+            "job queue",
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                receipt TEXT,
+                visible_at INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                last_error TEXT,
+                dead_lettered_at INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS jobs_visible ON jobs (kind, dead_lettered_at, visible_at);
+            CREATE INDEX IF NOT EXISTS jobs_user ON jobs (kind, user_id);",
+        )?;
+
+        Ok(Self { db, settings })
+    }
+}
+
+#[async_trait]
+impl JobQueue for SqliteJobQueue {
+    async fn enqueue(&self, job: Job) -> PapiResult<JobId> {
+        self.enqueue_after(job, Duration::ZERO).await
+    }
+
+    async fn enqueue_after(&self, job: Job, delay: Duration) -> PapiResult<JobId> {
+        let capacity = self.settings.capacity;
+        let payload = serde_json::to_string(&job)
+            .map_err(|e| PapiError::Storage(format!("Failed to serialize job: {}", e)))?;
+
+        self.db
+            .with_connection(move |connection| {
+                let pending: i64 = connection
+                    .query_row(
+                        "SELECT COUNT(*) FROM jobs WHERE kind = ?1 AND dead_lettered_at IS NULL",
+                        params![job.kind().as_str()],
+                        |row| row.get(0),
+                    )
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error counting pending jobs: {}", e))
+                    })?;
+                if pending as usize >= capacity {
+                    return Err(PapiError::Unavailable(format!(
+                        "Job queue '{}' is full ({} pending jobs)",
+                        job.kind().as_str(),
+                        pending
+                    )));
+                }
+
+                let id = Uuid::new_v4().to_string();
+                let now = Utc::now().timestamp_millis();
+                connection
+                    .execute(
+                        "INSERT INTO jobs (id, kind, user_id, payload, visible_at, created_at)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            id,
+                            job.kind().as_str(),
+                            job.user_id(),
+                            payload,
+                            now + delay.as_millis() as i64,
+                            now
+                        ],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error inserting job: {}", e)))?;
+
+                Ok(id)
+            })
+            .await
+    }
+
+    async fn receive(&self, kind: JobKind) -> PapiResult<Option<ReceivedJob>> {
+        let settings = self.settings.clone();
+
+        self.db
+            .with_connection(move |connection| {
+                let tx = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error starting transaction: {}", e))
+                    })?;
+
+                loop {
+                    let now = Utc::now().timestamp_millis();
+                    let Some((id, payload, attempts)) = tx
+                        .query_row(
+                            "WITH in_flight AS (
+                                SELECT user_id, COUNT(*) AS jobs FROM jobs
+                                WHERE kind = ?1 AND dead_lettered_at IS NULL
+                                    AND receipt IS NOT NULL AND visible_at > ?2
+                                GROUP BY user_id
+                            )
+                            SELECT j.id, j.payload, j.attempts FROM jobs j
+                            LEFT JOIN in_flight f ON f.user_id = j.user_id
+                            WHERE j.kind = ?1 AND j.dead_lettered_at IS NULL AND j.visible_at <= ?2
+                                AND COALESCE(f.jobs, 0) < ?3
+                            ORDER BY COALESCE(f.jobs, 0), j.created_at LIMIT 1",
+                            params![kind.as_str(), now, settings.max_in_flight_per_user],
+                            |row| {
+                                Ok((
+                                    row.get::<_, String>(0)?,
+                                    row.get::<_, String>(1)?,
+                                    row.get::<_, u32>(2)?,
+                                ))
+                            },
+                        )
+                        .optional()
+                        .map_err(|e| PapiError::Storage(format!("Error selecting job: {}", e)))?
+                    else {
+                        tx.commit().map_err(|e| {
+                            PapiError::Storage(format!("Error committing transaction: {}", e))
+                        })?;
+                        return Ok(None);
+                    };
+
+                    // the job was already delivered as many times as allowed but never acknowledged,
+                    // most likely because its consumer crashed while processing it
+                    if attempts >= settings.max_attempts {
+                        tx.execute(
+                            "UPDATE jobs SET dead_lettered_at = ?2, receipt = NULL,
+                            last_error = COALESCE(last_error, 'visibility timeout expired')
+                            WHERE id = ?1",
+                            params![id, now],
+                        )
+                        .map_err(|e| {
+                            PapiError::Storage(format!("Error dead-lettering job: {}", e))
+                        })?;
+                        warn!(job_id = %id, attempts, "Dead-lettered job");
+                        continue;
+                    }
+
+                    // a payload that cannot be decoded, e.g. written by an incompatible version,
+                    // would otherwise be selected again on every call and block the jobs behind it
+                    let job: Job = match serde_json::from_str(&payload) {
+                        Ok(job) => job,
+                        Err(e) => {
+                            tx.execute(
+                                "UPDATE jobs SET dead_lettered_at = ?2, receipt = NULL,
+                                last_error = ?3 WHERE id = ?1",
+                                params![id, now, format!("Failed to deserialize job: {}", e)],
+                            )
+                            .map_err(|e| {
+                                PapiError::Storage(format!("Error dead-lettering job: {}", e))
+                            })?;
+                            warn!(job_id = %id, error = %e, "Dead-lettered undecodable job");
+                            continue;
+                        }
+                    };
+                    let receipt = Uuid::new_v4().to_string();
+                    let attempts = attempts + 1;
+                    tx.execute(
+                        "UPDATE jobs SET attempts = ?2, receipt = ?3, visible_at = ?4 WHERE id = ?1",
+                        params![
+                            id,
+                            attempts,
+                            receipt,
+                            now + settings.visibility_timeout.as_millis() as i64
+                        ],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error updating job: {}", e)))?;
+                    tx.commit().map_err(|e| {
+                        PapiError::Storage(format!("Error committing transaction: {}", e))
+                    })?;
+
+                    return Ok(Some(ReceivedJob {
+                        id,
+                        receipt,
+                        attempts,
+                        job,
+                    }));
+                }
+            })
+            .await
+    }
+
+    async fn extend_visibility(&self, job: &ReceivedJob) -> PapiResult<()> {
+        let (id, receipt) = (job.id(), job.receipt());
+        let visibility_timeout = self.settings.visibility_timeout.as_millis() as i64;
+
+        self.db
+            .with_connection(move |connection| {
+                let updated = connection
+                    .execute(
+                        "UPDATE jobs SET visible_at = ?3 WHERE id = ?1 AND receipt = ?2",
+                        params![
+                            id,
+                            receipt,
+                            Utc::now().timestamp_millis() + visibility_timeout
+                        ],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error extending job: {}", e)))?;
+                if updated == 0 {
+                    return Err(PapiError::InvalidState(format!(
+                        "Job with ID {} was redelivered before being extended",
+                        id
+                    )));
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn ack(&self, job: &ReceivedJob) -> PapiResult<()> {
+        let (id, receipt) = (job.id(), job.receipt());
+
+        self.db
+            .with_connection(move |connection| {
+                let deleted = connection
+                    .execute(
+                        "DELETE FROM jobs WHERE id = ?1 AND receipt = ?2",
+                        params![id, receipt],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error deleting job: {}", e)))?;
+                if deleted == 0 {
+                    return Err(PapiError::InvalidState(format!(
+                        "Job with ID {} was redelivered before being acknowledged",
+                        id
+                    )));
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn nack(&self, job: &ReceivedJob, error: &str) -> PapiResult<()> {
+        if job.attempts() >= self.settings.max_attempts {
+            return self.dead_letter(job, error).await;
+        }
+
+        let (id, receipt, attempts) = (job.id(), job.receipt(), job.attempts());
+        let error = error.to_string();
+        let backoff = self.settings.retry_delay.as_millis() as i64 * attempts as i64;
+
+        self.db
+            .with_connection(move |connection| {
+                let updated = connection
+                    .execute(
+                        "UPDATE jobs SET visible_at = ?3, receipt = NULL, last_error = ?4
+                        WHERE id = ?1 AND receipt = ?2",
+                        params![id, receipt, Utc::now().timestamp_millis() + backoff, error],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error updating job: {}", e)))?;
+                if updated == 0 {
+                    return Err(PapiError::InvalidState(format!(
+                        "Job with ID {} was redelivered before being rejected",
+                        id
+                    )));
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    async fn release(&self, job: &ReceivedJob) -> PapiResult<()> {
+        let (id, receipt) = (job.id(), job.receipt());
+
+        self.db.with_connection(move |connection| {
+            let updated = connection
+                .execute(
+                    "UPDATE jobs SET visible_at = ?3, receipt = NULL, attempts = MAX(attempts - 1, 0)
+                    WHERE id = ?1 AND receipt = ?2",
+                    params![id, receipt, Utc::now().timestamp_millis()],
+                )
+                .map_err(|e| PapiError::Storage(format!("Error releasing job: {}", e)))?;
+            if updated == 0 {
+                return Err(PapiError::InvalidState(format!(
+                    "Job with ID {} was redelivered before being released",
+                    id
+                )));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn dead_letter(&self, job: &ReceivedJob, error: &str) -> PapiResult<()> {
+        let (id, receipt, attempts) = (job.id(), job.receipt(), job.attempts());
+        let error = error.to_string();
+
+        self.db
+            .with_connection(move |connection| {
+                let updated = connection
+                    .execute(
+                        "UPDATE jobs SET dead_lettered_at = ?3, receipt = NULL, last_error = ?4
+                        WHERE id = ?1 AND receipt = ?2",
+                        params![id, receipt, Utc::now().timestamp_millis(), error],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error dead-lettering job: {}", e)))?;
+                if updated == 0 {
+                    return Err(PapiError::InvalidState(format!(
+                        "Job with ID {} was redelivered before being dead-lettered",
+                        id
+                    )));
+                }
+                warn!(job_id = %id, attempts, "Dead-lettered job");
+                Ok(())
+            })
+            .await
+    }
+
+    async fn cancel_user_jobs(&self, user_id: &UserId) -> PapiResult<usize> {
+        let user_id = user_id.clone();
+
+        self.db
+            .with_connection(move |connection| {
+                connection
+                    .execute(
+                        "DELETE FROM jobs WHERE user_id = ?1 AND dead_lettered_at IS NULL",
+                        params![user_id],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error cancelling jobs: {}", e)))
+            })
+            .await
+    }
+
+    async fn purge_user_jobs(&self, user_id: &UserId) -> PapiResult<usize> {
+        let user_id = user_id.clone();
+
+        self.db
+            .with_connection(move |connection| {
+                connection
+                    .execute("DELETE FROM jobs WHERE user_id = ?1", params![user_id])
+                    .map_err(|e| PapiError::Storage(format!("Error purging jobs: {}", e)))
+            })
+            .await
+    }
+
+    async fn list_user_jobs(&self, user_id: &UserId) -> PapiResult<Vec<JobRecord>> {
+        let user_id = user_id.clone();
+
+        self.db
+            .with_connection(move |connection| {
+                let mut statement = connection
+                    .prepare(&format!(
+                        "SELECT id, kind, {} AS state, attempts, created_at, visible_at, last_error
+                        FROM jobs WHERE user_id = ?2 ORDER BY created_at",
+                        JOB_STATE_SQL
+                    ))
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error preparing jobs query: {}", e))
+                    })?;
+                let jobs = statement
+                    .query_map(params![Utc::now().timestamp_millis(), user_id], |row| {
+                        Ok(JobRecord {
+                            id: row.get(0)?,
+                            kind: row.get(1)?,
+                            state: job_state(&row.get::<_, String>(2)?),
+                            attempts: row.get(3)?,
+                            created_at: row.get(4)?,
+                            visible_at: row.get(5)?,
+                            last_error: row.get(6)?,
+                        })
+                    })
+                    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| PapiError::Storage(format!("Error listing jobs: {}", e)))?;
+                Ok(jobs)
+            })
+            .await
+    }
+
+    async fn count_jobs(&self) -> PapiResult<Vec<JobCount>> {
+        self.db
+            .with_connection(move |connection| {
+                let mut statement = connection
+                    .prepare(&format!(
+                        "SELECT kind, {} AS state, COUNT(*) FROM jobs
+                        GROUP BY kind, state ORDER BY kind, state",
+                        JOB_STATE_SQL
+                    ))
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error preparing jobs query: {}", e))
+                    })?;
+                let counts = statement
+                    .query_map(params![Utc::now().timestamp_millis()], |row| {
+                        Ok(JobCount {
+                            kind: row.get(0)?,
+                            state: job_state(&row.get::<_, String>(1)?),
+                            count: row.get::<_, i64>(2)? as usize,
+                        })
+                    })
+                    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| PapiError::Storage(format!("Error counting jobs: {}", e)))?;
+                Ok(counts)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job_queue::InitiationInfo;
+
+    fn queue() -> SqliteJobQueue {
+        SqliteJobQueue::setup(":memory:", JobQueueSettings::default()).unwrap()
+    }
+
+    fn initiation(user_id: &str) -> Job {
+        Job::ArchiveInitiation(InitiationInfo::new(
+            user_id.to_string(),
+            "myactivity.search".to_string(),
+        ))
+    }
+
+    #[actix_web::test]
+    async fn unacknowledged_jobs_are_redelivered_after_the_visibility_timeout() {
+        let queue = SqliteJobQueue::setup(
+            ":memory:",
+            JobQueueSettings {
+                visibility_timeout: Duration::from_millis(100),
+                ..JobQueueSettings::default()
+            },
+        )
+        .unwrap();
+        queue.enqueue(initiation("user")).await.unwrap();
+        let received = queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .is_none());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let redelivered = queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(redelivered.id(), received.id());
+        assert_eq!(redelivered.attempts(), 2);
+        // only the last delivery can be acknowledged
+        assert!(matches!(
+            queue.ack(&received).await,
+            Err(PapiError::InvalidState(_))
+        ));
+        queue.ack(&redelivered).await.unwrap();
+        assert!(queue
+            .list_user_jobs(&"user".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_web::test]
+    async fn rejected_jobs_are_dead_lettered_after_the_maximum_attempts() {
+        let queue = SqliteJobQueue::setup(
+            ":memory:",
+            JobQueueSettings {
+                max_attempts: 2,
+                retry_delay: Duration::ZERO,
+                ..JobQueueSettings::default()
+            },
+        )
+        .unwrap();
+        queue.enqueue(initiation("user")).await.unwrap();
+
+        for _ in 0..2 {
+            let received = queue
+                .receive(JobKind::ArchiveInitiation)
+                .await
+                .unwrap()
+                .unwrap();
+            queue.nack(&received, "Google is down").await.unwrap();
+        }
+
+        assert!(queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .is_none());
+        let jobs = queue.list_user_jobs(&"user".to_string()).await.unwrap();
+        assert_eq!(jobs[0].state, JobState::DeadLettered);
+        assert_eq!(jobs[0].last_error.as_deref(), Some("Google is down"));
+    }
+
+    #[actix_web::test]
+    async fn jobs_never_acknowledged_are_dead_lettered_after_the_maximum_attempts() {
+        let queue = SqliteJobQueue::setup(
+            ":memory:",
+            JobQueueSettings {
+                visibility_timeout: Duration::from_millis(50),
+                max_attempts: 1,
+                ..JobQueueSettings::default()
+            },
+        )
+        .unwrap();
+        queue.enqueue(initiation("user")).await.unwrap();
+        queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the consumer crashed, the job is not delivered again
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .is_none());
+        let jobs = queue.list_user_jobs(&"user".to_string()).await.unwrap();
+        assert_eq!(jobs[0].state, JobState::DeadLettered);
+        assert_eq!(
+            jobs[0].last_error.as_deref(),
+            Some("visibility timeout expired")
+        );
+    }
+
+    #[actix_web::test]
+    async fn extended_jobs_stay_hidden_past_the_visibility_timeout() {
+        let queue = SqliteJobQueue::setup(
+            ":memory:",
+            JobQueueSettings {
+                visibility_timeout: Duration::from_millis(200),
+                ..JobQueueSettings::default()
+            },
+        )
+        .unwrap();
+        queue.enqueue(initiation("user")).await.unwrap();
+        let received = queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        queue.extend_visibility(&received).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(queue
+            .receive(JobKind::ArchiveInitiation)
+            .await
+            .unwrap()
+            .is_none());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let redelivered = queue.receive(JobKind::ArchiveInitiation).await.unwrap();
+        assert_eq!(redelivered.unwrap().id(), received.id());
+        // the previous consumer can no longer extend it
+        assert!(matches!(
+            queue.extend_visibility(&received).await,
+            Err(PapiError::InvalidState(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn undecodable_jobs_are_dead_lettered_without_blocking_the_queue() {
+        let queue = queue();
+        queue
+            .db
+            .with_connection(|connection| {
+                connection
+                    .execute(
+                        "INSERT INTO jobs (id, kind, user_id, payload, visible_at, created_at)
+                        VALUES ('poison', 'archive_initiation', 'user', '{\"Unknown\":{}}', 0, 0)",
+                        [],
+                    )
+                    .map_err(|e| PapiError::Storage(e.to_string()))
+            })
+            .await
+            .unwrap();
+        let id = queue.enqueue(initiation("user")).await.unwrap();
+
+        let received = queue.receive(JobKind::ArchiveInitiation).await.unwrap();
+        assert_eq!(received.unwrap().id(), id);
+
+        let jobs = queue.list_user_jobs(&"user".to_string()).await.unwrap();
+        let poison = jobs.iter().find(|job| job.id == "poison").unwrap();
+        assert_eq!(poison.state, JobState::DeadLettered);
+        assert!(poison
+            .last_error
+            .as_deref()
+            .unwrap()
+            .starts_with("Failed to deserialize job"));
+    }
+}
@@ -0,0 +1,72 @@
+use audit_log::{AuditLog, SqliteAuditLog};
+use auth_db_client::DynamoDbAuthDbClient;
+use config::{Config, OAuthStateStoreBackend};
+use consent_ledger::{ConsentLedger, SqliteConsentLedger};
+use erasure::UserDataStores;
+use error::PapiResult;
+use job_queue::{JobQueue, SqliteJobQueue};
+use oauth_client::OAuthClient;
+use oauth_state_store::{DynamoDbStateStore, InMemoryStateStore, OAuthStateStore};
+use papi_line_client::PapiLineClient;
+use session::{SessionStore, SqliteSessionStore};
+use std::sync::Arc;
+use worker_pool::UserLocks;
+
+pub mod admin;
+pub mod api;
+pub mod audit_log;
+pub mod auth_db_client;
+pub mod cli;
+pub mod config;
+pub mod consent_ledger;
+pub mod erasure;
+pub mod error;
+pub mod export;
+pub mod health;
+pub mod job_queue;
+pub mod metrics;
+pub mod oauth_client;
+pub mod oauth_state_store;
+pub mod papi_line_client;
+pub mod query_params;
+pub mod session;
+pub(crate) mod sqlite;
+pub mod telemetry;
+pub mod worker_pool;
+
+/// Sets up everything holding data, shared between the HTTP handlers, the worker pool and the CLI
+pub async fn setup_stores(config: &Config) -> PapiResult<UserDataStores> {
+    let oauth_state_store: Arc<dyn OAuthStateStore> = match config.storage.oauth_state_store {
+        OAuthStateStoreBackend::DynamoDb => {
+            Arc::new(DynamoDbStateStore::setup(&config.storage).await?)
+        }
+        OAuthStateStoreBackend::Memory => Arc::new(InMemoryStateStore::default()),
+    };
+
+    // jobs survive restarts as they are persisted until acknowledged by the worker pool
+    let job_queue: Arc<dyn JobQueue> = Arc::new(SqliteJobQueue::setup(
+        &config.storage.job_queue_db_path,
+        config.job_queue.clone(),
+    )?);
+
+    let audit_log: Arc<dyn AuditLog> =
+        Arc::new(SqliteAuditLog::setup(&config.storage.audit_log_db_path)?);
+    let consent_ledger: Arc<dyn ConsentLedger> = Arc::new(SqliteConsentLedger::setup(
+        &config.storage.consent_ledger_db_path,
+    )?);
+
+    let session_store: Arc<dyn SessionStore> =
+        Arc::new(SqliteSessionStore::setup(&config.storage.session_db_path)?);
+
+    Ok(UserDataStores {
+        oauth_client: Arc::new(OAuthClient::new(config.google.clone())),
+        papi_line_client: Arc::new(PapiLineClient::setup(&config.storage).await?),
+        auth_db_client: Arc::new(DynamoDbAuthDbClient::setup(&config.storage).await?),
+        job_queue,
+        oauth_state_store,
+        audit_log,
+        consent_ledger,
+        session_store,
+        user_locks: Arc::new(UserLocks::default()),
+    })
+}
@@ -1,52 +1,52 @@
 use actix_cors::Cors;
-use actix_web::{web::Data, App, HttpServer};
-use api::{
-    auth_config,
-    handlers::{handle_data_archive, handle_data_download},
-    types::{OAuthInfo, UserStateMap},
-};
-use auth_db_client::AuthDbClient;
+use actix_web::{App, HttpServer};
+use clap::Parser;
 use dotenv::dotenv;
-use oauth_client::OAuthClient;
-use papi_line_client::PapiLineClient;
+use personal_api::{
+    api::AppState,
+    cli::{self, Command},
+    config::{Cli, Config, HttpConfig},
+    error::{PapiError, PapiResult},
+    setup_stores,
+    telemetry::Telemetry,
+    worker_pool::{JobContext, WorkerPool},
+};
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use std::{env, fs::File, io::BufReader};
+use std::{fs::File, io::BufReader};
 use tokio::{
-    select, signal,
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    join, select,
+    signal::{
+        self,
+        unix::{signal as unix_signal, SignalKind},
+    },
 };
+use tracing::{error, info};
+use tracing_actix_web::TracingLogger;
 
-mod api;
-mod auth_db_client;
-mod oauth_client;
-mod papi_line_client;
-
-const REQUESTED_RESOURCES: [&str; 2] = ["myactivity.search", "myactivity.shopping"];
-
-fn load_certs() -> Result<ServerConfig, String> {
+fn load_certs(http_config: &HttpConfig) -> PapiResult<ServerConfig> {
     let cert_file = &mut BufReader::new(
-        File::open(env::var("CERT_FILE_PATH").map_err(|_| "CERT_FILE_PATH must be set")?)
-            .map_err(|e| e.to_string())?,
+        File::open(&http_config.cert_file_path).map_err(|e| PapiError::Config(e.to_string()))?,
     );
     let key_file = &mut BufReader::new(
-        File::open(env::var("KEY_FILE_PATH").map_err(|_| "KEY_FILE_PATH must be set")?)
-            .map_err(|e| e.to_string())?,
+        File::open(&http_config.key_file_path).map_err(|e| PapiError::Config(e.to_string()))?,
     );
 
     let cert_chain = certs(cert_file)
-        .map_err(|e| e.to_string())?
+        .map_err(|e| PapiError::Config(e.to_string()))?
         .into_iter()
         .map(Certificate)
         .collect();
     let mut keys: Vec<PrivateKey> = pkcs8_private_keys(key_file)
-        .map_err(|e| e.to_string())?
+        .map_err(|e| PapiError::Config(e.to_string()))?
         .into_iter()
         .map(PrivateKey)
         .collect();
 
     if keys.is_empty() {
-        return Err("Could not locate PKCS 8 private keys.".to_string());
+        return Err(PapiError::Config(
+            "Could not locate PKCS 8 private keys.".to_string(),
+        ));
     }
 
     let config = ServerConfig::builder()
@@ -54,88 +54,113 @@ fn load_certs() -> Result<ServerConfig, String> {
         .with_no_client_auth();
     config
         .with_single_cert(cert_chain, keys.remove(0))
-        .map_err(|e| e.to_string())
+        .map_err(|e| PapiError::Config(e.to_string()))
+}
+
+/// Resolves on the first SIGINT (Ctrl-C) or SIGTERM (e.g. `docker stop`)
+async fn shutdown_signal() -> PapiResult<()> {
+    let mut sigterm = unix_signal(SignalKind::terminate())
+        .map_err(|e| PapiError::Config(format!("Could not listen for SIGTERM: {}", e)))?;
+    select! {
+        res = signal::ctrl_c() => {
+            res.map_err(|e| PapiError::Config(format!("Could not listen for SIGINT: {}", e)))
+        }
+        _ = sigterm.recv() => Ok(()),
+    }
 }
 
 #[actix_web::main]
-async fn main() -> Result<(), String> {
+async fn main() -> PapiResult<()> {
     dotenv().ok();
 
-    // to create a self-signed temporary cert for testing:
-    // `openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=localhost'`
-    // these are stored in the root cargo directory as "key.pem" and "cert.pem"
-    let tls_config = load_certs()?;
+    let cli = Cli::parse();
+    let command = cli.command();
+
+    // the configuration is loaded once and validated as a whole before anything else starts
+    let config = Config::load(cli)?;
+    let _telemetry = Telemetry::init(&config.telemetry)?;
 
-    // app state initialized inside the closure passed to HttpServer::new is local to the worker thread and may become de-synced if modified
-    // to achieve globally shared state, it must be created outside of the closure passed to HttpServer::new and moved/cloned in
-    let authorizations = Data::new(UserStateMap::default());
+    let stores = setup_stores(&config).await?;
 
-    let (authorization_tx, mut authorization_rx): (
-        UnboundedSender<OAuthInfo>,
-        UnboundedReceiver<OAuthInfo>,
-    ) = tokio::sync::mpsc::unbounded_channel();
-    let authorization_tx = Data::new(authorization_tx);
+    if !matches!(command, Command::Serve) {
+        return cli::run(command, &config, &stores)
+            .await
+            .inspect_err(|e| error!(error = %e, "Command failed"));
+    }
 
-    let (download_info_tx, mut download_info_rx): (
-        UnboundedSender<(String, String, Result<String, String>)>,
-        UnboundedReceiver<(String, String, Result<String, String>)>,
-    ) = tokio::sync::mpsc::unbounded_channel();
+    // to create a self-signed temporary cert for testing:
+    // `openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=localhost'`
+    // these are stored in the root cargo directory as "key.pem" and "cert.pem"
+    let tls_config = load_certs(&config.http)?;
 
-    let oauth_client = OAuthClient::new(download_info_tx);
-    let papi_line_client = PapiLineClient::setup().await?;
-    let auth_db_client = AuthDbClient::setup().await?;
+    let worker_pool = WorkerPool::new(
+        JobContext::new(config.clone(), &stores),
+        config.concurrency.clone(),
+    );
+    let config_cl = config.clone();
+    let app_state = AppState::new(stores, config.clone()).with_worker_status(worker_pool.status());
+    info!(
+        https_port = config.http.https_port,
+        http_port = config.http.http_port,
+        "Starting server"
+    );
+    // Start a number of HTTP workers equal to the number of physical CPUs in the system
+    let mut server = HttpServer::new(move || {
+        let cors = Cors::default()
+            .allowed_methods(vec!["GET", "POST", "DELETE"])
+            .allow_any_header()
+            // the session cookie is sent along with cross-origin requests
+            .supports_credentials();
+        let cors = config_cl
+            .http
+            .cors_allowed_origins
+            .iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin));
 
-    let authorizations_cl = Data::clone(&authorizations);
+        App::new()
+            .wrap(cors)
+            .wrap(TracingLogger::default())
+            .configure(|cfg| app_state.configure(cfg))
+    })
+    .bind_rustls(("0.0.0.0", config.http.https_port), tls_config)
+    .map_err(|e| {
+        PapiError::Config(format!(
+            "Could not bind port {}: {}",
+            config.http.https_port, e
+        ))
+    })?;
+    // TODO: remove in production
+    if let Some(http_port) = config.http.http_port {
+        server = server
+            .bind(("0.0.0.0", http_port))
+            .map_err(|e| PapiError::Config(format!("Could not bind port {}: {}", http_port, e)))?;
+    }
+    // shutdown is coordinated below rather than by the signal handlers of actix
+    let server = server
+        .disable_signals()
+        .shutdown_timeout(config.shutdown_timeout.as_secs())
+        .run();
+    let server_handle = server.handle();
     tokio::spawn(async move {
-        println!("Starting server...");
-        // Start a number of HTTP workers equal to the number of physical CPUs in the system
-        HttpServer::new(move || {
-            App::new()
-                .wrap(
-                    Cors::default()
-                        // TODO: limit origin
-                        .allow_any_origin()
-                        .allowed_methods(vec!["GET", "POST"])
-                        .allow_any_header(),
-                )
-                .app_data(Data::clone(&authorizations_cl))
-                .app_data(Data::clone(&authorization_tx))
-                .configure(auth_config)
-        })
-        .bind_rustls(("0.0.0.0", 8443), tls_config)
-        .unwrap()
-        // TODO: remove in production
-        .bind(("0.0.0.0", 8080))
-        .unwrap()
-        .run()
-        .await
-        .unwrap();
+        if let Err(e) = server.await {
+            error!(error = %e, "Server stopped with error");
+        }
     });
 
-    loop {
-        select! {
-            Some(oauth_info) = authorization_rx.recv() => {
-                if let Err(e) = handle_data_archive(&auth_db_client, &oauth_client, oauth_info).await {
-                    println!("Error handling data archive: {:?}", e);
-                }
-            },
-            Some((user_id, ready_to_download_resource, resource_res)) = download_info_rx.recv() => {
-                if let Err(e) = handle_data_download(
-                    &auth_db_client,
-                    &papi_line_client,
-                    &oauth_client,
-                    user_id,
-                    ready_to_download_resource,
-                    resource_res).await {
-                        println!("Error handling data download: {:?}", e);
-                    }
-            },
-            _ = signal::ctrl_c() => {
-                println!("Shutting down server...");
-                break;
-            }
-        }
-    }
+    // no new jobs are received once `run` is dropped
+    let res = select! {
+        _ = worker_pool.run() => Ok(()),
+        res = shutdown_signal() => res,
+    };
+    info!(timeout = ?config.shutdown_timeout, "Shutting down server");
+
+    // the HTTP server stops accepting new requests, in particular new authorizations, while the
+    // pending archive polls stay in the job queue and resume after the restart
+    join!(
+        server_handle.stop(true),
+        worker_pool.drain(config.shutdown_timeout)
+    );
+    info!("Server stopped");
 
-    Ok(())
+    res
 }
@@ -0,0 +1,193 @@
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::{future::Future, sync::LazyLock};
+use tokio::time::Instant;
+
+use crate::{
+    error::{PapiError, PapiResult},
+    job_queue::{JobKind, JobQueue, JobState},
+};
+
+const NAMESPACE: &str = "papi";
+
+pub const S3: &str = "s3";
+pub const DYNAMO_DB: &str = "dynamo_db";
+
+/// Metrics of the whole process, exposed on `/metrics` in the Prometheus text format
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Steps of the authorization flow reached by the users, by outcome
+    pub authorization_attempts: IntCounterVec,
+    pub token_exchanges: IntCounterVec,
+    /// Time spent processing a job of the queue, by kind and outcome
+    pub job_duration: HistogramVec,
+    /// Archive jobs still being polled, counted from the queue when the metrics are scraped, as
+    /// the polls are jobs that any replica can process
+    pub archives_in_flight: IntGauge,
+    /// Time between the initiation of an archive job and its completion, by resource and outcome
+    pub archive_job_duration: HistogramVec,
+    pub archive_polls: IntCounterVec,
+    pub download_bytes: IntCounterVec,
+    pub extracted_files: IntCounterVec,
+    pub storage_duration: HistogramVec,
+    pub storage_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self {
+            authorization_attempts: IntCounterVec::new(
+                opts("authorization_attempts_total", "Authorization attempts"),
+                &["outcome"],
+            )
+            .unwrap(),
+            token_exchanges: IntCounterVec::new(
+                opts(
+                    "token_exchanges_total",
+                    "Authorization codes exchanged for access tokens",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            job_duration: HistogramVec::new(
+                histogram_opts("job_duration_seconds", "Duration of the queued jobs")
+                    .buckets(exponential_buckets(0.01, 4.0, 10).unwrap()),
+                &["kind", "outcome"],
+            )
+            .unwrap(),
+            archives_in_flight: IntGauge::with_opts(opts(
+                "archives_in_flight",
+                "Archive jobs not completed yet",
+            ))
+            .unwrap(),
+            archive_job_duration: HistogramVec::new(
+                // Google takes from minutes to days to prepare an archive
+                histogram_opts(
+                    "archive_job_duration_seconds",
+                    "Duration of the archive jobs",
+                )
+                .buckets(exponential_buckets(10.0, 4.0, 10).unwrap()),
+                &["resource", "outcome"],
+            )
+            .unwrap(),
+            archive_polls: IntCounterVec::new(
+                opts(
+                    "archive_polls_total",
+                    "Checks of the state of the archive jobs",
+                ),
+                &["resource"],
+            )
+            .unwrap(),
+            download_bytes: IntCounterVec::new(
+                opts("download_bytes_total", "Bytes of the downloaded archives"),
+                &["resource"],
+            )
+            .unwrap(),
+            extracted_files: IntCounterVec::new(
+                opts("extracted_files_total", "Files extracted from the archives"),
+                &["resource"],
+            )
+            .unwrap(),
+            storage_duration: HistogramVec::new(
+                histogram_opts(
+                    "storage_operation_duration_seconds",
+                    "Latency of the S3 and DynamoDB operations",
+                )
+                .buckets(exponential_buckets(0.005, 2.0, 12).unwrap()),
+                &["backend", "operation"],
+            )
+            .unwrap(),
+            storage_errors: IntCounterVec::new(
+                opts("storage_errors_total", "Failed S3 and DynamoDB operations"),
+                &["backend", "operation"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 10] = [
+            Box::new(metrics.authorization_attempts.clone()),
+            Box::new(metrics.token_exchanges.clone()),
+            Box::new(metrics.job_duration.clone()),
+            Box::new(metrics.archives_in_flight.clone()),
+            Box::new(metrics.archive_job_duration.clone()),
+            Box::new(metrics.archive_polls.clone()),
+            Box::new(metrics.download_bytes.clone()),
+            Box::new(metrics.extracted_files.clone()),
+            Box::new(metrics.storage_duration.clone()),
+            Box::new(metrics.storage_errors.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    /// Renders all the metrics in the Prometheus text format
+    pub fn encode(&self) -> PapiResult<String> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| PapiError::Storage(format!("could not encode metrics: {}", e)))?;
+        String::from_utf8(buffer)
+            .map_err(|e| PapiError::Storage(format!("could not encode metrics: {}", e)))
+    }
+}
+
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace(NAMESPACE)
+}
+
+fn histogram_opts(name: &str, help: &str) -> HistogramOpts {
+    HistogramOpts::new(name, help).namespace(NAMESPACE)
+}
+
+/// Records the latency of a storage operation, and whether it failed
+pub async fn observe_storage<T>(
+    backend: &str,
+    operation: &str,
+    operation_future: impl Future<Output = PapiResult<T>>,
+) -> PapiResult<T> {
+    let start = Instant::now();
+    let res = operation_future.await;
+    METRICS
+        .storage_duration
+        .with_label_values(&[backend, operation])
+        .observe(start.elapsed().as_secs_f64());
+    if res.is_err() {
+        METRICS
+            .storage_errors
+            .with_label_values(&[backend, operation])
+            .inc();
+    }
+    res
+}
+
+/// Sets the gauges derived from the queue, which is shared by all the replicas
+pub async fn observe_queue(job_queue: &dyn JobQueue) -> PapiResult<()> {
+    let archives_in_flight: usize = job_queue
+        .count_jobs()
+        .await?
+        .iter()
+        .filter(|count| {
+            count.kind == JobKind::ArchivePolling.as_str() && count.state != JobState::DeadLettered
+        })
+        .map(|count| count.count)
+        .sum();
+    METRICS.archives_in_flight.set(archives_in_flight as i64);
+    Ok(())
+}
+
+/// Label of the outcome of an operation that might be retried
+pub fn outcome<T>(res: &PapiResult<T>) -> &'static str {
+    match res {
+        Ok(_) => "success",
+        Err(e) if e.is_retryable() => "retryable_error",
+        Err(_) => "error",
+    }
+}
@@ -0,0 +1,131 @@
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::{
+    sync::{Mutex, RwLock},
+    time::Instant,
+};
+
+use super::check_status;
+use crate::{
+    api::types::GoogleSubject,
+    error::{PapiError, PapiResult},
+};
+
+const GOOGLE_ID_TOKEN_ISSUERS: [&str; 2] = ["https://accounts.google.com", "accounts.google.com"];
+
+// tokens signed with unknown keys cannot make the keys be fetched more often than this
+const MIN_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Claims of a Google ID token that identify the account which granted the authorization
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    sub: GoogleSubject,
+    email: Option<String>,
+}
+
+impl IdTokenClaims {
+    pub fn sub(&self) -> GoogleSubject {
+        self.sub.clone()
+    }
+
+    pub fn email(&self) -> Option<String> {
+        self.email.clone()
+    }
+}
+
+/// Verifies the signature of Google ID tokens against the keys published at the JWKS URI.
+///
+/// The keys are cached and only fetched again when a token is signed with an unknown key, as
+/// Google rotates them periodically, at most once per minute.
+pub struct IdTokenVerifier {
+    client: Client,
+    jwks_uri: String,
+    audience: String,
+    jwks: RwLock<JwkSet>,
+    /// When the keys were last fetched, held while they are fetched
+    refreshed_at: Mutex<Option<Instant>>,
+}
+
+impl IdTokenVerifier {
+    pub fn new(client: Client, jwks_uri: String, audience: String) -> Self {
+        Self {
+            client,
+            jwks_uri,
+            audience,
+            jwks: RwLock::new(JwkSet { keys: vec![] }),
+            refreshed_at: Mutex::new(None),
+        }
+    }
+
+    pub async fn verify(&self, id_token: &str) -> PapiResult<IdTokenClaims> {
+        let header = decode_header(id_token)
+            .map_err(|e| PapiError::UpstreamPermanent(format!("Invalid ID token: {}", e)))?;
+        let kid = header.kid.ok_or(PapiError::UpstreamPermanent(
+            "ID token has no key ID".to_string(),
+        ))?;
+
+        let jwk = match self.find_key(&kid).await {
+            Some(jwk) => jwk,
+            None => {
+                self.refresh_stale_keys().await?;
+                self.find_key(&kid)
+                    .await
+                    .ok_or(PapiError::UpstreamPermanent(format!(
+                        "ID token signed with unknown key: {}",
+                        kid
+                    )))?
+            }
+        };
+        let decoding_key = DecodingKey::from_jwk(&jwk)
+            .map_err(|e| PapiError::UpstreamPermanent(format!("Invalid JWK: {}", e)))?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.audience]);
+        validation.set_issuer(&GOOGLE_ID_TOKEN_ISSUERS);
+
+        decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| PapiError::UpstreamPermanent(format!("Invalid ID token: {}", e)))
+    }
+
+    async fn find_key(&self, kid: &str) -> Option<Jwk> {
+        self.jwks.read().await.find(kid).cloned()
+    }
+
+    /// Fetches the keys unless they were fetched less than a minute ago, so that the tokens
+    /// arriving at the same time with an unknown key only fetch them once
+    async fn refresh_stale_keys(&self) -> PapiResult<()> {
+        let mut refreshed_at = self.refreshed_at.lock().await;
+        if refreshed_at.is_some_and(|at| at.elapsed() < MIN_JWKS_REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        // failed attempts count as well, not to hammer the JWKS URI while it fails
+        *refreshed_at = Some(Instant::now());
+        self.refresh_keys().await
+    }
+
+    async fn refresh_keys(&self) -> PapiResult<()> {
+        let response = self
+            .client
+            .get(&self.jwks_uri)
+            .send()
+            .await
+            .map_err(|e| PapiError::from(e).context("Error fetching JWKS"))?;
+
+        let response = check_status(response).await?;
+        let jwks: JwkSet = response
+            .json()
+            .await
+            .map_err(|e| PapiError::from(e).context("Error parsing JWKS"))?;
+
+        *self.jwks.write().await = jwks;
+
+        Ok(())
+    }
+}
@@ -1,164 +1,265 @@
-use actix_web::Result;
-use reqwest::Client;
-use std::env;
-use tokio::{
-    sync::mpsc::UnboundedSender,
-    time::{interval, Duration, Instant},
-};
+use id_token::IdTokenVerifier;
+use reqwest::{Client, Response};
+use tracing::{debug, error, info, instrument, warn};
 use types::{
-    AccessTokenParams, AccessTokenResponsePayload, AccessTokenUrl, GetArchiveStateParams,
+    AccessTokenParams, AccessTokenResponsePayload, GetArchiveStateParams,
     GetArchiveStateResponsePayload, GetArchiveStateUrl, InitiateArchiveParams,
     InitiateArchiveResponsePayload, InitiateArchiveUrl, ResetAuthorizationParams,
-    ResetAuthorizationResponsePayload, ResetAuthorizationUrl,
+    ResetAuthorizationResponsePayload, ResetAuthorizationUrl, RevokeTokenParams,
+    ACCESS_TOKEN_ENDPOINT, REVOKE_TOKEN_ENDPOINT,
 };
 
 use crate::{
-    api::types::{OAuthInfo, ResourceState},
-    REQUESTED_RESOURCES,
+    api::types::OAuthInfo,
+    config::GoogleConfig,
+    error::{PapiError, PapiResult},
+    metrics::METRICS,
 };
 
+mod id_token;
 mod types;
 
+/// States of an archive job that will never complete
+const ARCHIVE_FAILED_STATES: [&str; 2] = ["FAILED", "CANCELLED"];
+
+/// Makes a failure following the exchange of the single-use authorization code final, as
+/// exchanging the code again on retry could only be refused by Google
+pub fn code_already_exchanged(e: PapiError) -> PapiError {
+    if e.is_retryable() {
+        PapiError::UpstreamPermanent(format!(
+            "Authorization code already exchanged, the user must authorize again: {}",
+            e
+        ))
+    } else {
+        e
+    }
+}
+
 pub struct OAuthClient {
     client: Client,
-    download_info_tx: UnboundedSender<(String, String, Result<String, String>)>,
+    google_config: GoogleConfig,
+    id_token_verifier: IdTokenVerifier,
 }
 
 impl OAuthClient {
-    pub fn new(
-        download_info_tx: UnboundedSender<(String, String, Result<String, String>)>,
-    ) -> Self {
+    pub fn new(google_config: GoogleConfig) -> Self {
+        let client = Client::new();
+        let id_token_verifier = IdTokenVerifier::new(
+            Client::clone(&client),
+            google_config.jwks_uri.clone(),
+            google_config.client_id.clone(),
+        );
         Self {
-            client: Client::new(),
-            download_info_tx,
+            client,
+            google_config,
+            id_token_verifier,
         }
     }
 
     pub async fn convert_authorization_to_access_token(
         &self,
         oauth_info: &mut OAuthInfo,
-    ) -> Result<(), String> {
-        let user_id = oauth_info.user_id();
+    ) -> PapiResult<()> {
         let oauth_state = oauth_info.state();
-        let oauth_code = oauth_info.code();
+        let oauth_code = oauth_info.code().ok_or(PapiError::InvalidState(
+            "Authorization code already exchanged".to_string(),
+        ))?;
 
-        println!(
-            "Authorization code for client ID {}: {:?}",
-            user_id, oauth_code
-        );
+        let mut params = AccessTokenParams::new(
+            self.google_config.client_id.clone(),
+            self.google_config.client_secret.clone(),
+        )
+        .with_code(oauth_code)
+        .with_redirect_uri(self.google_config.redirect_uri.clone())
+        .with_state(oauth_state);
+        // the code verifier proves that this backend is the one that requested the authorization code
+        if let Some(code_verifier) = oauth_info.code_verifier() {
+            params = params.with_code_verifier(code_verifier);
+        }
 
-        let params = AccessTokenParams::default()
-            .with_code(oauth_code)
-            .with_redirect_uri(env::var("REDIRECT_URI").expect("REDIRECT_URI must be set"))
-            .with_state(oauth_state);
-        let access_token_url = AccessTokenUrl::new(params).as_url();
-
-        println!(
-            "Converting auth code to access token for client ID: {}",
-            user_id
-        );
+        debug!("Converting authorization code to access token");
 
         let response = self
             .client
-            .post(access_token_url)
-            .header("Content-Length", 0) // otherwise the server returns 411
+            .post(format!(
+                "{}{}",
+                self.google_config.oauth2_base_url, ACCESS_TOKEN_ENDPOINT
+            ))
+            .form(&params)
             .send()
             .await
-            .map_err(|e| format!("Error requesting access token: {}", e))?;
+            .map_err(|e| PapiError::from(e).context("Error requesting access token"))?;
 
-        if !response.status().is_success() {
-            return Err(response
-                .text()
-                .await
-                .map_err(|e| format!("Error reading response: {}", e))?);
-        }
-        let response: AccessTokenResponsePayload = response
-            .json()
-            .await
-            .map_err(|e| format!("Error parsing access token response payload: {}", e))?;
+        let response = check_status(response).await?;
+        let response: AccessTokenResponsePayload = response.json().await.map_err(|e| {
+            code_already_exchanged(
+                PapiError::from(e).context("Error parsing access token response payload"),
+            )
+        })?;
 
-        println!("Granted access token: {:?}", response);
         let access_token = response.access_token();
         let expires_in = response.expires_in();
         let scope = response.scope();
 
+        // the ID token tells which Google account granted the authorization
+        let id_token = response.id_token().ok_or(PapiError::UpstreamPermanent(
+            "ID token missing from access token response".to_string(),
+        ))?;
+        let claims = self
+            .id_token_verifier
+            .verify(&id_token)
+            .await
+            .map_err(|e| code_already_exchanged(e.context("Error verifying ID token")))?;
+
         oauth_info.set_access_token(access_token, expires_in, scope);
+        oauth_info.set_google_account(claims.sub(), claims.email());
+        info!(expires_in, "Granted access token");
 
         Ok(())
     }
 
-    pub fn initiate_data_archives(&self, oauth_info: &mut OAuthInfo) -> Result<(), String> {
-        for resource in REQUESTED_RESOURCES {
-            let oauth_client = Client::clone(&self.client);
-            let download_info_tx = self.download_info_tx.clone();
-            let user_id = oauth_info.user_id();
-            let access_token = oauth_info
-                .access_token()
-                .ok_or("Access token not found".to_string())?;
-            tokio::spawn(async move {
-                match initiate_data_archive(
-                    oauth_client.clone(),
-                    resource.to_string(),
-                    access_token.clone(),
-                )
-                .await
-                {
-                    Ok((resource, job_id)) => {
-                        let res =
-                            poll_archive_state(oauth_client.clone(), job_id.clone(), access_token)
-                                .await;
-                        download_info_tx
-                            .send((user_id, resource, res))
-                            .map_err(|e| format!("Error sending download info: {}", e))
-                    }
-                    Err(e) => download_info_tx
-                        .send((user_id, resource.to_string(), Err(e)))
-                        .map_err(|e| format!("Error sending download info: {}", e)),
-                }
-            });
-            oauth_info.update_granted_resource_state(resource, ResourceState::Initiated)?;
-        }
+    pub async fn initiate_data_archive(
+        &self,
+        oauth_info: &OAuthInfo,
+        resource: &str,
+    ) -> PapiResult<String> {
+        let access_token = oauth_info.access_token().ok_or(PapiError::InvalidState(
+            "Access token not found".to_string(),
+        ))?;
 
-        Ok(())
+        let (_, job_id) = initiate_data_archive(
+            Client::clone(&self.client),
+            &self.google_config.data_portability_base_url,
+            resource.to_string(),
+            access_token,
+        )
+        .await?;
+
+        Ok(job_id)
     }
 
-    pub async fn reset_authorization(&self, oauth_info: &OAuthInfo) -> Result<(), String> {
+    /// Checks the state of an archive job once, returning its download URL once it is ready
+    pub async fn poll_archive_state(
+        &self,
+        oauth_info: &OAuthInfo,
+        resource: &str,
+        archive_job_id: String,
+    ) -> PapiResult<Option<String>> {
+        let access_token = oauth_info.access_token().ok_or(PapiError::InvalidState(
+            "Access token not found".to_string(),
+        ))?;
+
+        poll_archive_state(
+            Client::clone(&self.client),
+            &self.google_config.data_portability_base_url,
+            resource,
+            archive_job_id,
+            access_token,
+        )
+        .await
+    }
+
+    pub async fn reset_authorization(&self, oauth_info: &OAuthInfo) -> PapiResult<()> {
         let params = ResetAuthorizationParams::default();
-        let reset_authorization_url = ResetAuthorizationUrl::new(params).as_url();
+        let reset_authorization_url =
+            ResetAuthorizationUrl::new(&self.google_config.data_portability_base_url, params)
+                .as_url()?;
 
         let response = self
             .client
             .post(reset_authorization_url)
-            .bearer_auth(
-                oauth_info
-                    .access_token()
-                    .ok_or("Access token not found".to_string())?,
-            )
+            .bearer_auth(oauth_info.access_token().ok_or(PapiError::InvalidState(
+                "Access token not found".to_string(),
+            ))?)
             .header("Content-Length", 0) // otherwise the server returns 411
             .send()
             .await
-            .unwrap();
+            .map_err(|e| PapiError::from(e).context("Error resetting authorization"))?;
 
-        let _: ResetAuthorizationResponsePayload = response.json().await.unwrap();
+        let response = check_status(response).await?;
+        let _: ResetAuthorizationResponsePayload = response.json().await.map_err(|e| {
+            PapiError::from(e).context("Error parsing reset authorization response payload")
+        })?;
 
-        println!(
-            "Reset authorization for client ID: {}",
-            oauth_info.user_id()
-        );
+        info!(user_id = %oauth_info.user_id(), "Reset authorization");
+
+        Ok(())
+    }
+
+    /// Stops any archive job and revokes the grant of a still valid access token.
+    ///
+    /// Google rejects calls with a token that is already expired or revoked, which is fine when
+    /// disconnecting the account.
+    pub async fn disconnect(&self, oauth_info: &OAuthInfo) -> PapiResult<()> {
+        if !oauth_info.has_valid_access_token() {
+            return Ok(());
+        }
+        ignore_upstream_rejection(
+            self.reset_authorization(oauth_info).await,
+            "authorization reset",
+        )?;
+        ignore_upstream_rejection(self.revoke_token(oauth_info).await, "token revocation")
+    }
+
+    /// Revokes the access token and the grant it belongs to
+    pub async fn revoke_token(&self, oauth_info: &OAuthInfo) -> PapiResult<()> {
+        let params = RevokeTokenParams::new(oauth_info.access_token().ok_or(
+            PapiError::InvalidState("Access token not found".to_string()),
+        )?);
+
+        let response = self
+            .client
+            .post(format!(
+                "{}{}",
+                self.google_config.oauth2_base_url, REVOKE_TOKEN_ENDPOINT
+            ))
+            .form(&params)
+            .send()
+            .await
+            .map_err(|e| PapiError::from(e).context("Error revoking token"))?;
+
+        check_status(response).await?;
+
+        info!(user_id = %oauth_info.user_id(), "Revoked token");
 
         Ok(())
     }
 }
 
+/// Fails with the body of a non-successful response, in the category of its status
+pub(crate) async fn check_status(response: Response) -> PapiResult<Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status().as_u16();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| PapiError::from(e).context("Error reading response"))?;
+    Err(PapiError::from_upstream_status(status, body))
+}
+
+fn ignore_upstream_rejection(res: PapiResult<()>, action: &str) -> PapiResult<()> {
+    match res {
+        Err(PapiError::UpstreamPermanent(e)) => {
+            warn!(action, error = %e, "Ignoring rejected request");
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+#[instrument(skip(oauth_client, base_url, access_token))]
 async fn initiate_data_archive(
     oauth_client: Client,
+    base_url: &str,
     resource: String,
     access_token: String,
-) -> Result<(String, String), String> {
-    println!("Initiating data transfer for resource: {}", resource);
+) -> PapiResult<(String, String)> {
+    debug!("Initiating data transfer");
 
     let params = InitiateArchiveParams::default().with_resources(resource.to_string());
-    let initiate_archive_url = InitiateArchiveUrl::new(params).as_url();
+    let initiate_archive_url = InitiateArchiveUrl::new(base_url, params).as_url()?;
 
     let response = oauth_client
         .post(initiate_archive_url)
@@ -166,68 +267,76 @@ async fn initiate_data_archive(
         .header("Content-Length", 0) // otherwise the server returns 411
         .send()
         .await
-        .map_err(|e| format!("Error initiating data transfer: {}", e))?;
+        .map_err(|e| PapiError::from(e).context("Error initiating data transfer"))?;
 
-    let response: InitiateArchiveResponsePayload = response
-        .json()
-        .await
-        .map_err(|e| format!("Error parsing initiate archive response payload: {}", e))?;
+    let response = check_status(response).await?;
+    let response: InitiateArchiveResponsePayload = response.json().await.map_err(|e| {
+        PapiError::from(e).context("Error parsing initiate archive response payload")
+    })?;
 
     let job_id = response.archive_job_id();
 
-    println!("Initiated data transfer with job ID: {}", job_id);
+    info!(archive_job_id = %job_id, "Initiated data transfer");
 
     Ok((resource, job_id))
 }
 
+#[instrument(skip(oauth_client, base_url, access_token))]
 async fn poll_archive_state(
     oauth_client: Client,
+    base_url: &str,
+    resource: &str,
     job_id: String,
     access_token: String,
-) -> Result<String, String> {
+) -> PapiResult<Option<String>> {
     let params = GetArchiveStateParams::default();
-    let poll_archive_state_url = GetArchiveStateUrl::new(job_id.clone(), params).as_url();
-
-    let mut interval = interval(Duration::from_secs(10));
-    let start_polling = Instant::now();
-    loop {
-        // TODO: make sure this polling ends after "enough" retries
-        println!(
-            "Polling state of job ID: {}. Started {:?} ago",
-            job_id,
-            start_polling.elapsed()
-        );
-        interval.tick().await;
+    let poll_archive_state_url =
+        GetArchiveStateUrl::new(base_url, job_id.clone(), params).as_url()?;
 
-        let response = oauth_client
-            .get(poll_archive_state_url.clone())
-            .bearer_auth(access_token.clone())
-            .header("Content-Length", 0) // otherwise the server returns 411
-            .send()
-            .await
-            .map_err(|e| format!("Error plling archive state: {}", e))?;
-
-        match response.json::<GetArchiveStateResponsePayload>().await {
-            Ok(GetArchiveStateResponsePayload::Completed(response)) => {
-                let download_url = response.urls()[0].clone();
-                println!(
-                    "Job with ID {} completed. Download URL: {:?}",
-                    job_id, download_url
-                );
-                return Ok(download_url);
-            }
-            Ok(GetArchiveStateResponsePayload::InProgress(response)) => {
-                println!("Job with ID {} in state: {:?}", job_id, response.state());
-            }
-            Err(e) => {
-                // TODO: distinguish the case in which the server returns an error
-                //       from the ones in which the job has failed
-                //       for now we just assume that each job eventually completes
-                //       some info on the retry logic: https://developers.google.com/data-portability/user-guide/methods#archivejobsretryportabilityarchive
-                let error = format!("Job with ID {} failed: {:?}", job_id, e);
-                println!("{}", error);
-                return Err(error);
-            }
+    debug!("Polling archive state");
+    METRICS.archive_polls.with_label_values(&[resource]).inc();
+
+    let response = oauth_client
+        .get(poll_archive_state_url)
+        .bearer_auth(access_token)
+        .header("Content-Length", 0) // otherwise the server returns 411
+        .send()
+        .await
+        .map_err(|e| PapiError::from(e).context("Error polling archive state"))?;
+
+    // the job is polled again if Google fails to answer, but not if it rejects the request
+    let response = check_status(response).await?;
+    let response: GetArchiveStateResponsePayload = response
+        .json()
+        .await
+        .map_err(|e| PapiError::from(e).context("Error parsing archive state response payload"))?;
+
+    match response {
+        GetArchiveStateResponsePayload::Completed(response) => {
+            let download_url =
+                response
+                    .urls()
+                    .first()
+                    .cloned()
+                    .ok_or(PapiError::UpstreamPermanent(format!(
+                        "Job with ID {} completed without download URLs",
+                        job_id
+                    )))?;
+            info!(state = ?response.state(), "Archive ready for download");
+            Ok(Some(download_url))
+        }
+        // the job would have to be retried on Google's side, see
+        // https://developers.google.com/data-portability/user-guide/methods#archivejobsretryportabilityarchive
+        GetArchiveStateResponsePayload::InProgress(response)
+            if ARCHIVE_FAILED_STATES.contains(&response.state().as_str()) =>
+        {
+            let error = format!("Job with ID {} ended in state {}", job_id, response.state());
+            error!(error = %error, "Archive job failed");
+            Err(PapiError::UpstreamPermanent(error))
+        }
+        GetArchiveStateResponsePayload::InProgress(response) => {
+            debug!(state = ?response.state(), "Archive not ready yet");
+            Ok(None)
         }
     }
 }
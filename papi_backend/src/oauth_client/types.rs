@@ -1,51 +1,38 @@
+use crate::{error::PapiResult, query_params::QueryParams};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
-use std::env;
 
-const ACCESS_TOKEN_ENDPOINT: &str = "https://oauth2.googleapis.com/token";
-const ARCHIVE_BASE_URL: &str = "https://dataportability.googleapis.com/v1beta/";
+// relative to the OAuth 2.0 base URL
+pub const ACCESS_TOKEN_ENDPOINT: &str = "/token";
+pub const REVOKE_TOKEN_ENDPOINT: &str = "/revoke";
+// relative to the Data Portability base URL
+const ARCHIVE_BASE_PATH: &str = "/v1beta/";
 const INITIATE_ARCHIVE_ENDPOINT: &str = "portabilityArchive:initiate";
 const ARCHIVE_JOBS_ENDPOINT: &str = "archiveJobs/";
 const POLL_ARCHIVE_STATE_ENDPOINT: &str = "/portabilityArchiveState";
 const RESET_AUTHORIZATION_ENDPOINT: &str = "authorization:reset";
 
-pub struct AccessTokenUrl {
-    endpoint: String,
-    params: AccessTokenParams,
-}
-
-impl AccessTokenUrl {
-    pub fn new(params: AccessTokenParams) -> Self {
-        Self {
-            endpoint: String::from(ACCESS_TOKEN_ENDPOINT),
-            params,
-        }
-    }
-
-    pub fn as_url(&self) -> String {
-        format!("{}?{}", self.endpoint, self.params.as_url())
-    }
-}
-
+/// Sent as a form-encoded body so that the client secret does not end up in the URL
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AccessTokenParams {
     state: Option<String>,
     code: Option<String>,
     redirect_uri: Option<String>,
+    code_verifier: Option<String>,
     client_id: String,
     client_secret: String,
     grant_type: String,
 }
 
 impl AccessTokenParams {
-    pub fn default() -> Self {
+    pub fn new(client_id: String, client_secret: String) -> Self {
         Self {
             state: None,
             code: None,
             redirect_uri: None,
-            client_id: env::var("GOOGLE_CLIENT_ID").expect("GOOGLE_CLIENT_ID must be set"),
-            client_secret: env::var("GOOGLE_CLIENT_SECRET")
-                .expect("GOOGLE_CLIENT_SECRET must be set"),
+            code_verifier: None,
+            client_id,
+            client_secret,
             grant_type: String::from("authorization_code"),
         }
     }
@@ -65,21 +52,9 @@ impl AccessTokenParams {
         self
     }
 
-    pub fn as_url(&self) -> String {
-        serde_json::to_value(&self)
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .iter()
-            .fold(String::new(), |params, (param, value)| match value {
-                Value::String(value) => {
-                    if !params.is_empty() {
-                        return format!("{}&{}={}", params, param, value);
-                    }
-                    format!("{}={}", param, value)
-                }
-                _ => params,
-            })
+    pub fn with_code_verifier(mut self, code_verifier: String) -> Self {
+        self.code_verifier = Some(code_verifier);
+        self
     }
 }
 
@@ -88,7 +63,8 @@ pub struct AccessTokenResponsePayload {
     access_token: String,
     expires_in: u32,
     scope: String,
-    token_type: String,
+    // only returned when the openid scope is granted
+    id_token: Option<String>,
 }
 
 impl AccessTokenResponsePayload {
@@ -103,6 +79,22 @@ impl AccessTokenResponsePayload {
     pub fn scope(&self) -> String {
         self.scope.clone()
     }
+
+    pub fn id_token(&self) -> Option<String> {
+        self.id_token.clone()
+    }
+}
+
+/// Sent as a form-encoded body, like the access token request
+#[derive(Serialize, Debug)]
+pub struct RevokeTokenParams {
+    token: String,
+}
+
+impl RevokeTokenParams {
+    pub fn new(token: String) -> Self {
+        Self { token }
+    }
 }
 
 pub struct InitiateArchiveUrl {
@@ -111,15 +103,18 @@ pub struct InitiateArchiveUrl {
 }
 
 impl InitiateArchiveUrl {
-    pub fn new(params: InitiateArchiveParams) -> Self {
+    pub fn new(base_url: &str, params: InitiateArchiveParams) -> Self {
         Self {
-            endpoint: String::from(ARCHIVE_BASE_URL) + INITIATE_ARCHIVE_ENDPOINT,
+            endpoint: format!(
+                "{}{}{}",
+                base_url, ARCHIVE_BASE_PATH, INITIATE_ARCHIVE_ENDPOINT
+            ),
             params,
         }
     }
 
-    pub fn as_url(&self) -> String {
-        format!("{}?{}", self.endpoint, self.params.as_url())
+    pub fn as_url(&self) -> PapiResult<String> {
+        Ok(format!("{}?{}", self.endpoint, self.params.as_url()?))
     }
 }
 
@@ -141,25 +136,10 @@ impl InitiateArchiveParams {
         self.resources = Some(resources);
         self
     }
-
-    pub fn as_url(&self) -> String {
-        serde_json::to_value(&self)
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .iter()
-            .fold(String::new(), |params, (param, value)| match value {
-                Value::String(value) => {
-                    if !params.is_empty() {
-                        return format!("{}&{}={}", params, param, value);
-                    }
-                    format!("{}={}", param, value)
-                }
-                _ => params,
-            })
-    }
 }
 
+impl QueryParams for InitiateArchiveParams {}
+
 #[derive(Deserialize, Debug)]
 pub struct InitiateArchiveResponsePayload {
     #[serde(rename = "archiveJobId")]
@@ -178,18 +158,22 @@ pub struct GetArchiveStateUrl {
 }
 
 impl GetArchiveStateUrl {
-    pub fn new(job_id: String, params: GetArchiveStateParams) -> Self {
+    pub fn new(base_url: &str, job_id: String, params: GetArchiveStateParams) -> Self {
         Self {
             endpoint: format!(
-                "{}{}{}{}",
-                ARCHIVE_BASE_URL, ARCHIVE_JOBS_ENDPOINT, job_id, POLL_ARCHIVE_STATE_ENDPOINT
+                "{}{}{}{}{}",
+                base_url,
+                ARCHIVE_BASE_PATH,
+                ARCHIVE_JOBS_ENDPOINT,
+                job_id,
+                POLL_ARCHIVE_STATE_ENDPOINT
             ),
             params,
         }
     }
 
-    pub fn as_url(&self) -> String {
-        format!("{}?{}", self.endpoint, self.params.as_url())
+    pub fn as_url(&self) -> PapiResult<String> {
+        Ok(format!("{}?{}", self.endpoint, self.params.as_url()?))
     }
 }
 
@@ -204,30 +188,15 @@ impl GetArchiveStateParams {
             alt: String::from("json"),
         }
     }
-
-    pub fn as_url(&self) -> String {
-        serde_json::to_value(&self)
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .iter()
-            .fold(String::new(), |params, (param, value)| match value {
-                Value::String(value) => {
-                    if !params.is_empty() {
-                        return format!("{}&{}={}", params, param, value);
-                    }
-                    format!("{}={}", param, value)
-                }
-                _ => params,
-            })
-    }
 }
 
+impl QueryParams for GetArchiveStateParams {}
+
 #[derive(Debug)]
 pub enum GetArchiveStateResponsePayload {
     Completed(ArchiveCompleteResponsePayload),
+    // also matches the failed and cancelled jobs, which only report their state
     InProgress(ArchiveInProgressResponsePayload),
-    // Failed(ArchiveFailedResponsePayload),    // TODO: figure out how the response looks like in case "state" is "FAILED"
 }
 
 impl<'de> Deserialize<'de> for GetArchiveStateResponsePayload {
@@ -283,15 +252,18 @@ pub struct ResetAuthorizationUrl {
 }
 
 impl ResetAuthorizationUrl {
-    pub fn new(params: ResetAuthorizationParams) -> Self {
+    pub fn new(base_url: &str, params: ResetAuthorizationParams) -> Self {
         Self {
-            endpoint: format!("{}{}", ARCHIVE_BASE_URL, RESET_AUTHORIZATION_ENDPOINT),
+            endpoint: format!(
+                "{}{}{}",
+                base_url, ARCHIVE_BASE_PATH, RESET_AUTHORIZATION_ENDPOINT
+            ),
             params,
         }
     }
 
-    pub fn as_url(&self) -> String {
-        format!("{}?{}", self.endpoint, self.params.as_url())
+    pub fn as_url(&self) -> PapiResult<String> {
+        Ok(format!("{}?{}", self.endpoint, self.params.as_url()?))
     }
 }
 
@@ -306,24 +278,9 @@ impl ResetAuthorizationParams {
             alt: String::from("json"),
         }
     }
-
-    pub fn as_url(&self) -> String {
-        serde_json::to_value(&self)
-            .unwrap()
-            .as_object()
-            .unwrap()
-            .iter()
-            .fold(String::new(), |params, (param, value)| match value {
-                Value::String(value) => {
-                    if !params.is_empty() {
-                        return format!("{}&{}={}", params, param, value);
-                    }
-                    format!("{}={}", param, value)
-                }
-                _ => params,
-            })
-    }
 }
 
+impl QueryParams for ResetAuthorizationParams {}
+
 #[derive(Deserialize, Debug)]
 pub struct ResetAuthorizationResponsePayload {}
@@ -0,0 +1,222 @@
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::{
+    client::Waiters,
+    types::{
+        AttributeDefinition, AttributeValue, BillingMode, KeySchemaElement, KeyType, ReturnValue,
+        ScalarAttributeType, TimeToLiveSpecification,
+    },
+    Client,
+};
+use serde_dynamo::{from_item, to_item};
+use tokio::time::Duration;
+use tracing::info;
+
+use super::OAuthStateStore;
+use crate::{
+    api::types::{OAuthState, PendingAuthorization, UserId},
+    config::StorageConfig,
+    error::{PapiError, PapiResult},
+    metrics::{observe_storage, DYNAMO_DB},
+};
+
+const TABLE_CREATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Keeps the pending authorizations in a DynamoDB table shared by all instances.
+///
+/// DynamoDB deletes expired items on its own (with some delay), expiration is checked again on
+/// every read.
+pub struct DynamoDbStateStore {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoDbStateStore {
+    pub async fn setup(storage_config: &StorageConfig) -> PapiResult<Self> {
+        let table_name = storage_config.dynamo_db_oauth_state_table_name.clone();
+
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .region(Region::new(storage_config.aws_region.clone()))
+            .load()
+            .await;
+        let client = Client::new(&config);
+
+        if !client
+            .list_tables()
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("Error listing tables: {}", e)))?
+            .table_names()
+            .contains(&table_name)
+        {
+            create_table(&client, &table_name).await?;
+            info!(table = %table_name, "Created table");
+        }
+
+        Ok(Self { client, table_name })
+    }
+}
+
+async fn create_table(client: &Client, table_name: &str) -> PapiResult<()> {
+    let attribute = |name: &str| {
+        AttributeDefinition::builder()
+            .attribute_name(name)
+            .attribute_type(ScalarAttributeType::S)
+            .build()
+            .map_err(|e| PapiError::Storage(format!("Error creating table: {}", e)))
+    };
+    let key = |name: &str, key_type: KeyType| {
+        KeySchemaElement::builder()
+            .attribute_name(name)
+            .key_type(key_type)
+            .build()
+            .map_err(|e| PapiError::Storage(format!("Error creating table: {}", e)))
+    };
+
+    client
+        .create_table()
+        .table_name(table_name)
+        .billing_mode(BillingMode::PayPerRequest)
+        .attribute_definitions(attribute("user_id")?)
+        .attribute_definitions(attribute("state")?)
+        .key_schema(key("user_id", KeyType::Hash)?)
+        .key_schema(key("state", KeyType::Range)?)
+        .send()
+        .await
+        .map_err(|e| PapiError::Storage(format!("Error creating table: {}", e)))?;
+
+    // the time to live can only be enabled once the table is active
+    client
+        .wait_until_table_exists()
+        .table_name(table_name)
+        .wait(TABLE_CREATION_TIMEOUT)
+        .await
+        .map_err(|e| PapiError::Storage(format!("Error waiting for table: {}", e)))?;
+
+    client
+        .update_time_to_live()
+        .table_name(table_name)
+        .time_to_live_specification(
+            TimeToLiveSpecification::builder()
+                .enabled(true)
+                .attribute_name("expires_at")
+                .build()
+                .map_err(|e| PapiError::Storage(format!("Error enabling time to live: {}", e)))?,
+        )
+        .send()
+        .await
+        .map_err(|e| PapiError::Storage(format!("Error enabling time to live: {}", e)))?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl OAuthStateStore for DynamoDbStateStore {
+    async fn insert(&self, user_id: &UserId, pending: PendingAuthorization) -> PapiResult<()> {
+        observe_storage(DYNAMO_DB, "insert", async {
+            let item = to_item(&pending).map_err(|e| {
+                PapiError::Storage(format!("Failed to serialize pending authorization: {}", e))
+            })?;
+
+            self.client
+                .put_item()
+                .table_name(&self.table_name)
+                .set_item(Some(item))
+                .item("user_id", AttributeValue::S(user_id.clone()))
+                .send()
+                .await
+                .map_err(|e| {
+                    PapiError::Storage(format!("Error inserting pending authorization: {}", e))
+                })?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take(
+        &self,
+        user_id: &UserId,
+        state: &OAuthState,
+    ) -> PapiResult<Option<PendingAuthorization>> {
+        observe_storage(DYNAMO_DB, "take", async {
+            // deleting and returning the item at once guarantees that a state is used only once,
+            // even across instances
+            let output = self
+                .client
+                .delete_item()
+                .table_name(&self.table_name)
+                .key("user_id", AttributeValue::S(user_id.clone()))
+                .key("state", AttributeValue::S(state.clone()))
+                .return_values(ReturnValue::AllOld)
+                .send()
+                .await
+                .map_err(|e| {
+                    PapiError::Storage(format!("Error deleting pending authorization: {}", e))
+                })?;
+
+            let Some(item) = output.attributes else {
+                return Ok(None);
+            };
+            let pending: PendingAuthorization = from_item(item).map_err(|e| {
+                PapiError::Storage(format!(
+                    "Failed to deserialize pending authorization: {}",
+                    e
+                ))
+            })?;
+
+            Ok(Some(pending).filter(|p| !p.is_expired()))
+        })
+        .await
+    }
+
+    async fn remove_user(&self, user_id: &UserId) -> PapiResult<usize> {
+        observe_storage(DYNAMO_DB, "remove_user", async {
+            let mut pages = self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .key_condition_expression("user_id = :user_id")
+                .expression_attribute_values(":user_id", AttributeValue::S(user_id.clone()))
+                .projection_expression("#state")
+                .expression_attribute_names("#state", "state")
+                .into_paginator()
+                .items()
+                .send();
+
+            let mut removed = 0;
+            while let Some(item) = pages.next().await {
+                let item = item.map_err(|e| {
+                    PapiError::Storage(format!("Error querying pending authorizations: {}", e))
+                })?;
+                let Some(state) = item.get("state") else {
+                    continue;
+                };
+                self.client
+                    .delete_item()
+                    .table_name(&self.table_name)
+                    .key("user_id", AttributeValue::S(user_id.clone()))
+                    .key("state", state.clone())
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error deleting pending authorization: {}", e))
+                    })?;
+                removed += 1;
+            }
+
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn check_reachable(&self) -> PapiResult<()> {
+        self.client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("Error describing table: {}", e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::RwLock};
+
+use super::OAuthStateStore;
+use crate::{
+    api::types::{OAuthState, PendingAuthorization, UserId},
+    error::{PapiError, PapiResult},
+};
+
+/// Keeps the pending authorizations in memory, so they are lost on restart and not shared
+/// between instances
+#[derive(Default)]
+pub struct InMemoryStateStore {
+    pending: RwLock<HashMap<(UserId, OAuthState), PendingAuthorization>>,
+}
+
+#[async_trait]
+impl OAuthStateStore for InMemoryStateStore {
+    async fn insert(&self, user_id: &UserId, pending: PendingAuthorization) -> PapiResult<()> {
+        let mut pending_authorizations = self
+            .pending
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?;
+        pending_authorizations.retain(|_, p| !p.is_expired());
+        pending_authorizations.insert((user_id.clone(), pending.state()), pending);
+        Ok(())
+    }
+
+    async fn take(
+        &self,
+        user_id: &UserId,
+        state: &OAuthState,
+    ) -> PapiResult<Option<PendingAuthorization>> {
+        Ok(self
+            .pending
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .remove(&(user_id.clone(), state.clone()))
+            .filter(|p| !p.is_expired()))
+    }
+
+    async fn remove_user(&self, user_id: &UserId) -> PapiResult<usize> {
+        let mut pending_authorizations = self
+            .pending
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?;
+        let count = pending_authorizations.len();
+        pending_authorizations.retain(|(pending_user_id, _), _| pending_user_id != user_id);
+        Ok(count - pending_authorizations.len())
+    }
+
+    async fn check_reachable(&self) -> PapiResult<()> {
+        self.pending
+            .read()
+            .map(|_| ())
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))
+    }
+}
@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+
+use crate::{
+    api::types::{OAuthState, PendingAuthorization, UserId},
+    error::PapiResult,
+};
+
+pub use dynamo_db::DynamoDbStateStore;
+pub use memory::InMemoryStateStore;
+
+mod dynamo_db;
+mod memory;
+
+/// Pending authorizations between the authorization URL being handed out and the callback.
+///
+/// A user can have several pending authorizations at once (e.g. one per browser tab), each one
+/// identified by its OAuth state and discarded once expired.
+#[async_trait]
+pub trait OAuthStateStore: Send + Sync {
+    async fn insert(&self, user_id: &UserId, pending: PendingAuthorization) -> PapiResult<()>;
+
+    /// Removes and returns the pending authorization of the user with the given state, if any and
+    /// not expired, so that each state can be used only once
+    async fn take(
+        &self,
+        user_id: &UserId,
+        state: &OAuthState,
+    ) -> PapiResult<Option<PendingAuthorization>>;
+
+    /// Removes all the pending authorizations of a user, returning how many were removed
+    async fn remove_user(&self, user_id: &UserId) -> PapiResult<usize>;
+
+    /// Fails if the underlying storage cannot be reached
+    async fn check_reachable(&self) -> PapiResult<()>;
+}
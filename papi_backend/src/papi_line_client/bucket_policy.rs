@@ -0,0 +1,181 @@
+use aws_sdk_s3::types::{
+    BucketLifecycleConfiguration, ExpirationStatus, LifecycleExpiration, LifecycleRule,
+    LifecycleRuleFilter, PublicAccessBlockConfiguration, ServerSideEncryption,
+    ServerSideEncryptionByDefault, ServerSideEncryptionConfiguration, ServerSideEncryptionRule,
+    Tag,
+};
+use aws_sdk_s3::Client as S3Client;
+use tracing::info;
+
+use crate::{
+    config::S3Encryption,
+    error::{PapiError, PapiResult},
+};
+
+use super::{DataKind, DATA_KIND_TAG};
+
+const RAW_ARCHIVE_RETENTION_RULE_ID: &str = "papi-raw-archive-retention";
+
+/// Blocks public access and enables S3-managed encryption by default on a newly created bucket
+pub async fn apply_default_policy(client: &S3Client, bucket: &str) -> PapiResult<()> {
+    client
+        .put_public_access_block()
+        .bucket(bucket)
+        .public_access_block_configuration(
+            PublicAccessBlockConfiguration::builder()
+                .block_public_acls(true)
+                .ignore_public_acls(true)
+                .block_public_policy(true)
+                .restrict_public_buckets(true)
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(|e| PapiError::Storage(format!("could not block public access: {}", e)))?;
+
+    let rule = ServerSideEncryptionRule::builder()
+        .apply_server_side_encryption_by_default(
+            ServerSideEncryptionByDefault::builder()
+                .sse_algorithm(ServerSideEncryption::Aes256)
+                .build()
+                .map_err(|e| PapiError::Storage(format!("could not set encryption: {}", e)))?,
+        )
+        .build();
+    client
+        .put_bucket_encryption()
+        .bucket(bucket)
+        .server_side_encryption_configuration(
+            ServerSideEncryptionConfiguration::builder()
+                .rules(rule)
+                .build()
+                .map_err(|e| PapiError::Storage(format!("could not set encryption: {}", e)))?,
+        )
+        .send()
+        .await
+        .map_err(|e| PapiError::Storage(format!("could not set encryption: {}", e)))?;
+
+    Ok(())
+}
+
+/// Fails if the bucket could expose the stored files, reporting all the violations at once
+pub async fn check_bucket_policy(
+    client: &S3Client,
+    bucket: &str,
+    encryption: S3Encryption,
+) -> PapiResult<()> {
+    let mut violations = vec![];
+
+    let public_access_blocked = client
+        .get_public_access_block()
+        .bucket(bucket)
+        .send()
+        .await
+        .ok()
+        .and_then(|output| output.public_access_block_configuration)
+        .is_some_and(|config| {
+            config.block_public_acls() == Some(true)
+                && config.ignore_public_acls() == Some(true)
+                && config.block_public_policy() == Some(true)
+                && config.restrict_public_buckets() == Some(true)
+        });
+    if !public_access_blocked {
+        violations.push("public access is not fully blocked");
+    }
+
+    // with SSE-C every request carries its own key, so the default encryption does not matter
+    if encryption == S3Encryption::S3Managed {
+        let encrypted_by_default = client
+            .get_bucket_encryption()
+            .bucket(bucket)
+            .send()
+            .await
+            .ok()
+            .and_then(|output| output.server_side_encryption_configuration)
+            .is_some_and(|config| {
+                config
+                    .rules()
+                    .iter()
+                    .any(|rule| rule.apply_server_side_encryption_by_default().is_some())
+            });
+        if !encrypted_by_default {
+            violations.push("default encryption is not enabled");
+        }
+    }
+
+    if !violations.is_empty() {
+        return Err(PapiError::Storage(format!(
+            "Bucket {} does not have the expected policy: {}",
+            bucket,
+            violations.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Expires the raw archives after the retention period, leaving the other lifecycle rules untouched
+pub async fn configure_retention(
+    client: &S3Client,
+    bucket: &str,
+    retention_days: Option<i32>,
+) -> PapiResult<()> {
+    // reading fails if the bucket has no lifecycle configuration yet
+    let mut rules = client
+        .get_bucket_lifecycle_configuration()
+        .bucket(bucket)
+        .send()
+        .await
+        .map(|output| output.rules.unwrap_or_default())
+        .unwrap_or_default();
+    let rules_count = rules.len();
+    rules.retain(|rule| rule.id() != Some(RAW_ARCHIVE_RETENTION_RULE_ID));
+
+    if let Some(days) = retention_days {
+        let tag = Tag::builder()
+            .key(DATA_KIND_TAG)
+            .value(DataKind::Raw.as_str())
+            .build()
+            .map_err(|e| PapiError::Storage(format!("could not set retention: {}", e)))?;
+        let rule = LifecycleRule::builder()
+            .id(RAW_ARCHIVE_RETENTION_RULE_ID)
+            .filter(LifecycleRuleFilter::Tag(tag))
+            .expiration(LifecycleExpiration::builder().days(days).build())
+            .status(ExpirationStatus::Enabled)
+            .build()
+            .map_err(|e| PapiError::Storage(format!("could not set retention: {}", e)))?;
+        rules.push(rule);
+    } else if rules.len() == rules_count {
+        // retention was not configured before either
+        return Ok(());
+    }
+
+    if rules.is_empty() {
+        client
+            .delete_bucket_lifecycle()
+            .bucket(bucket)
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("could not remove retention: {}", e)))?;
+    } else {
+        client
+            .put_bucket_lifecycle_configuration()
+            .bucket(bucket)
+            .lifecycle_configuration(
+                BucketLifecycleConfiguration::builder()
+                    .set_rules(Some(rules))
+                    .build()
+                    .map_err(|e| PapiError::Storage(format!("could not set retention: {}", e)))?,
+            )
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("could not set retention: {}", e)))?;
+    }
+
+    match retention_days {
+        Some(days) => info!(
+            bucket,
+            days, "Raw archives expire after the retention period"
+        ),
+        None => info!(bucket, "Raw archives are kept forever"),
+    }
+    Ok(())
+}
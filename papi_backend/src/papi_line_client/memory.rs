@@ -0,0 +1,102 @@
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use std::{collections::BTreeMap, sync::RwLock};
+
+use super::{FileStore, FileTags};
+use crate::error::{PapiError, PapiResult};
+
+/// Keeps the files in memory, only meant for tests and local development
+#[derive(Default)]
+pub struct InMemoryFileStore {
+    files: RwLock<BTreeMap<String, (FileTags, Vec<u8>)>>,
+}
+
+impl InMemoryFileStore {
+    /// Returns the tags and the content of a stored file
+    pub fn file(&self, key: &str) -> Option<(FileTags, Vec<u8>)> {
+        self.files.read().ok()?.get(key).cloned()
+    }
+}
+
+#[async_trait]
+impl FileStore for InMemoryFileStore {
+    async fn put_file(&self, key: &str, tags: &FileTags, body: ByteStream) -> PapiResult<()> {
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| PapiError::Storage(format!("could not read file: {}", e)))?
+            .to_vec();
+        self.files
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .insert(key.to_string(), (tags.clone(), body));
+        Ok(())
+    }
+
+    async fn copy_file(&self, from: &str, to: &str, tags: &FileTags) -> PapiResult<()> {
+        let mut files = self
+            .files
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?;
+        let (_, body) = files
+            .get(from)
+            .cloned()
+            .ok_or(PapiError::NotFound(format!("File {} not found", from)))?;
+        files.insert(to.to_string(), (tags.clone(), body));
+        Ok(())
+    }
+
+    async fn list_files(&self, prefix: &str) -> PapiResult<Vec<String>> {
+        Ok(self
+            .files
+            .read()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_files_after(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> PapiResult<Vec<String>> {
+        Ok(self
+            .files
+            .read()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?
+            .keys()
+            .filter(|key| {
+                key.starts_with(prefix) && start_after.is_none_or(|after| key.as_str() > after)
+            })
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_file(&self, key: &str) -> PapiResult<ByteStream> {
+        self.file(key)
+            .map(|(_, body)| ByteStream::from(body))
+            .ok_or(PapiError::NotFound(format!("File {} not found", key)))
+    }
+
+    async fn delete_files(&self, keys: &[String]) -> PapiResult<()> {
+        let mut files = self
+            .files
+            .write()
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?;
+        for key in keys {
+            files.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn check_reachable(&self) -> PapiResult<()> {
+        self.files
+            .read()
+            .map(|_| ())
+            .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))
+    }
+}
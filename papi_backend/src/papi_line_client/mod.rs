@@ -1,14 +1,42 @@
-use aws_sdk_s3::error::SdkError;
-use aws_sdk_s3::operation::create_bucket::{CreateBucketError, CreateBucketOutput};
-use aws_sdk_s3::types::{BucketLocationConstraint, CreateBucketConfiguration};
-use aws_sdk_s3::{primitives::ByteStream, Client as S3Client};
-use chrono::Utc;
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
 use reqwest::{Client as ReqwestClient, Response};
-use std::error::Error;
-use std::io::Cursor;
-use std::{env, io::Read};
+use std::io::Read;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, info_span, instrument, warn, Instrument};
+use uuid::Uuid;
 use zip::read::ZipArchive;
 
+use crate::{
+    api::types::Resource,
+    config::StorageConfig,
+    error::{PapiError, PapiResult},
+    metrics::METRICS,
+};
+
+pub use memory::InMemoryFileStore;
+pub use s3::S3FileStore;
+
+mod bucket_policy;
+mod memory;
+mod s3;
+
+// all the files of a user are stored under `users/{user_id}/`
+const USERS_PREFIX: &str = "users/";
+
+// archive job ID of the files stored before the job ID was recorded
+const LEGACY_ARCHIVE_JOB_ID: &str = "legacy";
+
+// the legacy keys start with a timestamp, so only the keys starting with a digit are listed
+const LEGACY_KEY_PREFIXES: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+
+// number of keys listed at once when migrating the legacy keys
+const LEGACY_KEYS_PAGE_SIZE: usize = 1000;
+
+const DATA_KIND_TAG: &str = "data_kind";
+
 const ZIP_MIME_TYPES: [&str; 4] = [
     "application/zip",
     "application/x-zip",
@@ -16,77 +44,129 @@ const ZIP_MIME_TYPES: [&str; 4] = [
     "multipart/x-zip",
 ];
 
-async fn create_bucket(
-    client: &S3Client,
-    bucket: &str,
-    region: &str,
-) -> Result<CreateBucketOutput, SdkError<CreateBucketError>> {
-    let constraint = BucketLocationConstraint::from(region);
-    let cfg = CreateBucketConfiguration::builder()
-        .location_constraint(constraint)
-        .build();
-
-    client
-        .create_bucket()
-        .create_bucket_configuration(cfg)
-        .bucket(bucket)
-        .send()
-        .await
+/// What a stored file holds. The raw archives are deleted after the retention period, the files
+/// extracted from them are kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataKind {
+    Raw,
+    Extracted,
+}
+
+impl DataKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Extracted => "extracted",
+        }
+    }
+}
+
+/// Describes a stored file, so that it can be found and managed without reading it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileTags {
+    resource: String,
+    archive_job_id: String,
+    content_type: &'static str,
+    data_kind: DataKind,
+}
+
+impl FileTags {
+    pub fn new(key: &str, resource: &str, archive_job_id: &str, data_kind: DataKind) -> Self {
+        Self {
+            resource: resource.to_string(),
+            archive_job_id: archive_job_id.to_string(),
+            content_type: content_type(key),
+            data_kind,
+        }
+    }
+
+    pub fn resource(&self) -> String {
+        self.resource.clone()
+    }
+
+    pub fn archive_job_id(&self) -> String {
+        self.archive_job_id.clone()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    pub fn data_kind(&self) -> DataKind {
+        self.data_kind
+    }
+
+    /// Tags encoded as URL query parameters, as expected by S3
+    pub fn as_query(&self) -> PapiResult<String> {
+        serde_urlencoded::to_string([
+            ("resource", self.resource.as_str()),
+            ("archive_job_id", self.archive_job_id.as_str()),
+            ("content_type", self.content_type),
+            (DATA_KIND_TAG, self.data_kind.as_str()),
+        ])
+        .map_err(|e| PapiError::Storage(format!("could not encode file tags: {}", e)))
+    }
+}
+
+/// Where the files downloaded from Google end up
+#[async_trait]
+pub trait FileStore: Send + Sync {
+    async fn put_file(&self, key: &str, tags: &FileTags, body: ByteStream) -> PapiResult<()>;
+
+    async fn copy_file(&self, from: &str, to: &str, tags: &FileTags) -> PapiResult<()>;
+
+    /// Returns the keys of all the files starting with the prefix
+    async fn list_files(&self, prefix: &str) -> PapiResult<Vec<String>>;
+
+    /// Returns at most `limit` keys of the files starting with the prefix, in order, after the
+    /// given key if any
+    async fn list_files_after(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> PapiResult<Vec<String>>;
+
+    async fn get_file(&self, key: &str) -> PapiResult<ByteStream>;
+
+    async fn delete_files(&self, keys: &[String]) -> PapiResult<()>;
+
+    /// Fails if the underlying storage cannot be reached
+    async fn check_reachable(&self) -> PapiResult<()>;
 }
 
 pub struct PapiLineClient {
     request_client: ReqwestClient,
-    s3_client: aws_sdk_s3::Client,
-    bucket_name: String,
+    files: Arc<dyn FileStore>,
 }
 
 impl PapiLineClient {
-    pub async fn setup() -> Result<Self, String> {
-        let bucket_name = env::var("S3_BUCKET_NAME").expect("S3_BUCKET_NAME must be set");
-
-        let config = aws_config::load_from_env().await;
-        let s3_client = S3Client::new(&config);
-
-        if !s3_client
-            .list_buckets()
-            .send()
-            .await
-            .map_err(|e| format!("Error listing buckets: {}", e.to_string()))?
-            .buckets()
-            .iter()
-            .any(|b| b.name() == Some(&bucket_name))
-        {
-            create_bucket(
-                &s3_client,
-                &bucket_name,
-                env::var("AWS_REGION")
-                    .expect("AWS_REGION must be set")
-                    .as_str(),
-            )
-            .await
-            .map_err(|e| format!("Error creating table: {}", e))?;
-            println!("Created bucket: {}", bucket_name);
-        }
+    pub async fn setup(storage_config: &StorageConfig) -> PapiResult<Self> {
+        Ok(Self::new(Arc::new(
+            S3FileStore::setup(storage_config).await?,
+        )))
+    }
 
-        Ok(Self {
+    pub fn new(files: Arc<dyn FileStore>) -> Self {
+        Self {
             request_client: ReqwestClient::new(),
-            s3_client,
-            bucket_name,
-        })
+            files,
+        }
     }
 
     pub async fn download_file(
         &self,
         user_id: String,
-        resource: &String,
+        resource: &str,
+        archive_job_id: Option<String>,
         url: &str,
-    ) -> Result<(), String> {
+    ) -> PapiResult<()> {
         let response = self
             .request_client
             .get(url)
             .send()
             .await
-            .map_err(|e| format!("could not request data download: {:?}", e.to_string()))?;
+            .map_err(|e| PapiError::from(e).context("could not request data download"))?;
 
         let content_type = response
             .headers()
@@ -95,50 +175,402 @@ impl PapiLineClient {
             .unwrap_or("");
 
         if ZIP_MIME_TYPES.contains(&content_type) {
-            println!("Unzipping files for resource: {}", resource);
-            self.unzip_and_flatten(&user_id, resource, response)
+            let archive_job_id =
+                archive_job_id.unwrap_or_else(|| LEGACY_ARCHIVE_JOB_ID.to_string());
+            self.unzip_and_store(&user_id, resource, &archive_job_id, response)
                 .await
-                .map_err(|e| format!("could not unzip files: {:?}", e.to_string()))
         } else {
-            Err(format!("file is not a ZIP file:{:?}", response.headers()))
+            Err(PapiError::UpstreamPermanent(format!(
+                "file is not a ZIP file: {:?}",
+                response.headers()
+            )))
         }
     }
 
-    async fn unzip_and_flatten(
+    #[instrument(name = "extract", skip(self, response))]
+    async fn unzip_and_store(
         &self,
         user_id: &str,
-        resource: &String,
+        resource: &str,
+        archive_job_id: &str,
         response: Response,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut zip = ZipArchive::new(Cursor::new(response.bytes().await?))?;
+    ) -> PapiResult<()> {
+        // archives can be large, so they are spooled to disk rather than held in memory
+        let archive = download_to_temp_file(response).await?;
+        let size = archive
+            .as_file()
+            .metadata()
+            .map_err(|e| PapiError::Storage(format!("could not read data download: {}", e)))?
+            .len();
+        METRICS
+            .download_bytes
+            .with_label_values(&[resource])
+            .inc_by(size);
+
+        let key = raw_archive_key(user_id, resource, archive_job_id);
+        let tags = FileTags::new(&key, resource, archive_job_id, DataKind::Raw);
+        let body = ByteStream::from_path(archive.path())
+            .await
+            .map_err(|e| PapiError::Storage(format!("could not read data download: {}", e)))?;
+        self.files
+            .put_file(&key, &tags, body)
+            .instrument(info_span!("store", key = %key, size))
+            .await?;
+        info!(key = %key, size, "Stored raw archive");
+
+        let file = archive
+            .reopen()
+            .map_err(|e| PapiError::Storage(format!("could not read data download: {}", e)))?;
+        let mut zip = ZipArchive::new(file)
+            .map_err(|e| PapiError::UpstreamPermanent(format!("could not unzip files: {}", e)))?;
+        debug!(files = zip.len(), "Extracting archive");
 
         for i in 0..zip.len() {
-            let mut file = zip.by_index(i)?;
-
-            if let Some(filename) = file.name().split('/').last() {
-                println!("Extracting file: {:?}", filename);
-                let filename = format!(
-                    "{}_{}_{}_{}",
-                    Utc::now().timestamp(),
-                    user_id,
-                    resource,
-                    filename
-                );
+            // the zip entry borrows the archive and cannot be held across the upload below
+            let (path, buffer) = {
+                let mut file = zip.by_index(i).map_err(|e| {
+                    PapiError::UpstreamPermanent(format!("could not unzip files: {}", e))
+                })?;
+                if file.is_dir() {
+                    continue;
+                }
                 let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)?;
-
-                let body = ByteStream::from(buffer);
-                self.s3_client
-                    .put_object()
-                    .bucket(&self.bucket_name)
-                    .key(filename)
-                    .body(body)
-                    .send()
+                file.read_to_end(&mut buffer).map_err(|e| {
+                    PapiError::UpstreamPermanent(format!("could not unzip files: {}", e))
+                })?;
+                (file.name().to_string(), buffer)
+            };
+
+            let Some(entry_path) = sanitize_entry_path(&path) else {
+                warn!(path = %path, "Skipping file with unsafe path");
+                continue;
+            };
+            let key = user_file_key(user_id, resource, archive_job_id, &entry_path);
+            let tags = FileTags::new(&key, resource, archive_job_id, DataKind::Extracted);
+            let size = buffer.len();
+            self.files
+                .put_file(&key, &tags, ByteStream::from(buffer))
+                .instrument(info_span!("store", key = %key, size))
+                .await?;
+            info!(key = %key, size, "Stored file");
+            METRICS.extracted_files.with_label_values(&[resource]).inc();
+        }
+        Ok(())
+    }
+
+    /// Returns the keys of all the files stored for a user
+    pub async fn list_user_files(&self, user_id: &str) -> PapiResult<Vec<String>> {
+        self.files.list_files(&user_prefix(user_id)).await
+    }
+
+    pub async fn get_file(&self, key: &str) -> PapiResult<ByteStream> {
+        self.files.get_file(key).await
+    }
+
+    /// Path of a stored file relative to the user, i.e. `{resource}/{archive_job_id}/{path}` with
+    /// the path of the file in the archive, or `{resource}/{archive_job_id}.zip` for the raw archive
+    pub fn user_file_path(key: &str) -> String {
+        match key.splitn(3, '/').collect::<Vec<&str>>()[..] {
+            [_, _, path] => path.to_string(),
+            _ => key.to_string(),
+        }
+    }
+
+    /// Moves the files stored with the flat `{timestamp}_{user_id}_{resource}_{filename}` keys
+    /// under the per-user prefix, returning how many were moved.
+    ///
+    /// Only the keys of the given resources are moved, anything else in the bucket is left alone.
+    /// Files are copied before the legacy key is deleted, so the migration can be run again if interrupted.
+    pub async fn migrate_legacy_keys(&self, resources: &[Resource]) -> PapiResult<usize> {
+        let mut migrated = 0;
+        for prefix in LEGACY_KEY_PREFIXES {
+            let mut start_after = None;
+            loop {
+                let keys = self
+                    .files
+                    .list_files_after(prefix, start_after.as_deref(), LEGACY_KEYS_PAGE_SIZE)
                     .await?;
-            } else {
-                println!("Error parsing file path: {:?}", file.name());
+                for legacy_key in &keys {
+                    if self.migrate_legacy_key(legacy_key, resources).await? {
+                        migrated += 1;
+                    }
+                }
+                if keys.len() < LEGACY_KEYS_PAGE_SIZE {
+                    break;
+                }
+                start_after = keys.last().cloned();
             }
         }
-        Ok(())
+        Ok(migrated)
+    }
+
+    /// Moves a file stored with a legacy key, returning whether the key had the legacy format
+    async fn migrate_legacy_key(
+        &self,
+        legacy_key: &str,
+        resources: &[Resource],
+    ) -> PapiResult<bool> {
+        let Some(legacy) = LegacyKey::parse(legacy_key, resources) else {
+            warn!(key = %legacy_key, "Skipping file with unknown key format");
+            return Ok(false);
+        };
+        let key = user_file_key(
+            legacy.user_id,
+            legacy.resource,
+            LEGACY_ARCHIVE_JOB_ID,
+            &format!("{}_{}", legacy.timestamp, legacy.filename),
+        );
+        let tags = FileTags::new(
+            &key,
+            legacy.resource,
+            LEGACY_ARCHIVE_JOB_ID,
+            DataKind::Extracted,
+        );
+
+        self.files.copy_file(legacy_key, &key, &tags).await?;
+        self.files.delete_files(&[legacy_key.to_string()]).await?;
+
+        info!(from = %legacy_key, to = %key, "Moved file");
+        Ok(true)
+    }
+
+    pub async fn check_reachable(&self) -> PapiResult<()> {
+        self.files.check_reachable().await
+    }
+
+    /// Deletes all the files stored for a user, returning how many were deleted
+    pub async fn delete_user_files(&self, user_id: &str) -> PapiResult<usize> {
+        let keys = self.list_user_files(user_id).await?;
+        self.files.delete_files(&keys).await?;
+
+        info!(user_id = %user_id, files = keys.len(), "Deleted files of user");
+
+        Ok(keys.len())
+    }
+}
+
+/// Key of a file stored before the per-user prefixes, as written by the first versions of the
+/// backend
+#[derive(Debug, PartialEq)]
+struct LegacyKey<'a> {
+    timestamp: &'a str,
+    user_id: &'a str,
+    resource: &'a str,
+    filename: &'a str,
+}
+
+impl<'a> LegacyKey<'a> {
+    /// Matches `{timestamp}_{user_id}_{resource}_{filename}` keys whose timestamp is in seconds,
+    /// whose user ID is a UUID and whose resource is one of the given ones
+    fn parse(key: &'a str, resources: &'a [Resource]) -> Option<Self> {
+        let (timestamp, rest) = key.split_once('_')?;
+        if timestamp.is_empty() || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let (user_id, rest) = rest.split_once('_')?;
+        // the other formats accepted by the parser never appeared in keys
+        if user_id.len() != 36 || Uuid::parse_str(user_id).is_err() {
+            return None;
+        }
+        // resources are matched as a whole, since they can contain underscores
+        let (resource, filename) = resources
+            .iter()
+            .filter_map(|resource| {
+                let filename = rest.strip_prefix(resource.as_str())?.strip_prefix('_')?;
+                Some((resource.as_str(), filename))
+            })
+            .max_by_key(|(resource, _)| resource.len())?;
+        if filename.is_empty() {
+            return None;
+        }
+        Some(Self {
+            timestamp,
+            user_id,
+            resource,
+            filename,
+        })
+    }
+}
+
+/// Writes the body of the response to a temporary file, deleted once dropped
+async fn download_to_temp_file(mut response: Response) -> PapiResult<NamedTempFile> {
+    let temp_file = NamedTempFile::new()
+        .map_err(|e| PapiError::Storage(format!("could not create temporary file: {}", e)))?;
+    let file = temp_file
+        .reopen()
+        .map_err(|e| PapiError::Storage(format!("could not open temporary file: {}", e)))?;
+    let mut file = tokio::fs::File::from_std(file);
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| PapiError::from(e).context("could not read data download"))?
+    {
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| PapiError::Storage(format!("could not write data download: {}", e)))?;
+    }
+    file.flush()
+        .await
+        .map_err(|e| PapiError::Storage(format!("could not write data download: {}", e)))?;
+    Ok(temp_file)
+}
+
+/// Path of a file of an archive relative to the archive, without the components that could make
+/// its key escape the directory of the archive
+fn sanitize_entry_path(path: &str) -> Option<String> {
+    if path.starts_with('/') || path.contains('\\') || path.contains('\0') {
+        return None;
+    }
+    let mut components = vec![];
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return None;
+    }
+    Some(components.join("/"))
+}
+
+fn user_prefix(user_id: &str) -> String {
+    format!("{}{}/", USERS_PREFIX, user_id)
+}
+
+fn user_file_key(user_id: &str, resource: &str, archive_job_id: &str, filename: &str) -> String {
+    format!(
+        "{}{}/{}/{}",
+        user_prefix(user_id),
+        resource,
+        archive_job_id,
+        filename
+    )
+}
+
+// the archive is stored next to the directory of the files extracted from it
+fn raw_archive_key(user_id: &str, resource: &str, archive_job_id: &str) -> String {
+    format!(
+        "{}{}/{}.zip",
+        user_prefix(user_id),
+        resource,
+        archive_job_id
+    )
+}
+
+fn content_type(key: &str) -> &'static str {
+    match key
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .as_deref()
+    {
+        Some("json") => "application/json",
+        Some("html") => "text/html",
+        Some("csv") => "text/csv",
+        Some("txt") => "text/plain",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_ID: &str = "0b6ea3c4-1f7e-4f7c-9d2a-4c3f0f6f4b1e";
+
+    fn resources() -> Vec<Resource> {
+        vec![
+            "myactivity.search".to_string(),
+            "chrome.browser_history".to_string(),
+        ]
+    }
+
+    #[test]
+    fn legacy_keys_are_parsed() {
+        let resources = resources();
+        let key = format!(
+            "1700000000_{}_chrome.browser_history_My_History.json",
+            USER_ID
+        );
+        assert_eq!(
+            LegacyKey::parse(&key, &resources),
+            Some(LegacyKey {
+                timestamp: "1700000000",
+                user_id: USER_ID,
+                resource: "chrome.browser_history",
+                filename: "My_History.json",
+            })
+        );
+    }
+
+    #[test]
+    fn entry_paths_are_kept_unless_they_escape_the_archive() {
+        assert_eq!(
+            sanitize_entry_path("Portability/My Activity/./Search/MyActivity.json"),
+            Some("Portability/My Activity/Search/MyActivity.json".to_string())
+        );
+        for path in ["/etc/passwd", "a/../../b", "a/../b", "a\\..\\b", "", "./"] {
+            assert_eq!(sanitize_entry_path(path), None, "{} was kept", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn legacy_keys_are_migrated_page_by_page() {
+        let files = Arc::new(InMemoryFileStore::default());
+        for i in 0..LEGACY_KEYS_PAGE_SIZE + 1 {
+            let key = format!("1700000000_{}_myactivity.search_{}.json", USER_ID, i);
+            let tags = FileTags::new(&key, "unknown", LEGACY_ARCHIVE_JOB_ID, DataKind::Extracted);
+            files
+                .put_file(&key, &tags, ByteStream::from_static(b""))
+                .await
+                .unwrap();
+        }
+        let client = PapiLineClient::new(Arc::clone(&files) as Arc<dyn FileStore>);
+
+        assert_eq!(
+            client.migrate_legacy_keys(&resources()).await.unwrap(),
+            LEGACY_KEYS_PAGE_SIZE + 1
+        );
+        assert_eq!(
+            client.list_user_files(USER_ID).await.unwrap().len(),
+            LEGACY_KEYS_PAGE_SIZE + 1
+        );
+    }
+
+    #[actix_web::test]
+    async fn only_legacy_keys_are_migrated() {
+        let files = Arc::new(InMemoryFileStore::default());
+        let legacy_key = format!("1700000000_{}_myactivity.search_MyActivity.json", USER_ID);
+        let other_keys = [
+            // written by something else sharing the bucket
+            "backups_2024_01_dump.sql".to_string(),
+            format!("17000000x0_{}_myactivity.search_MyActivity.json", USER_ID),
+            "1700000000_not-a-uuid_myactivity.search_MyActivity.json".to_string(),
+            format!("1700000000_{}_unknown.resource_MyActivity.json", USER_ID),
+            format!("1700000000_{}_myactivity.search_", USER_ID),
+        ];
+        for key in other_keys.iter().chain([&legacy_key]) {
+            let tags = FileTags::new(key, "unknown", LEGACY_ARCHIVE_JOB_ID, DataKind::Extracted);
+            files
+                .put_file(key, &tags, ByteStream::from_static(b""))
+                .await
+                .unwrap();
+        }
+        let client = PapiLineClient::new(Arc::clone(&files) as Arc<dyn FileStore>);
+
+        assert_eq!(client.migrate_legacy_keys(&resources()).await.unwrap(), 1);
+
+        assert_eq!(
+            client.list_user_files(USER_ID).await.unwrap(),
+            vec![format!(
+                "users/{}/myactivity.search/legacy/1700000000_MyActivity.json",
+                USER_ID
+            )]
+        );
+        for key in other_keys {
+            assert!(files.file(&key).is_some(), "{} was moved", key);
+        }
     }
 }
@@ -0,0 +1,324 @@
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::operation::create_bucket::{CreateBucketError, CreateBucketOutput};
+use aws_sdk_s3::types::{
+    BucketLocationConstraint, CreateBucketConfiguration, Delete, MetadataDirective,
+    ObjectIdentifier, ServerSideEncryption, TaggingDirective,
+};
+use aws_sdk_s3::{primitives::ByteStream, Client as S3Client};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use tracing::info;
+
+use super::{bucket_policy, FileStore, FileTags};
+use crate::{
+    config::{S3Encryption, StorageConfig},
+    error::{PapiError, PapiResult},
+    metrics::{observe_storage, S3},
+};
+
+// maximum number of keys accepted by a single S3 DeleteObjects request
+const DELETE_OBJECTS_BATCH_SIZE: usize = 1000;
+
+const SSE_CUSTOMER_ALGORITHM: &str = "AES256";
+
+async fn create_bucket(
+    client: &S3Client,
+    bucket: &str,
+    region: &str,
+) -> Result<CreateBucketOutput, SdkError<CreateBucketError>> {
+    let constraint = BucketLocationConstraint::from(region);
+    let cfg = CreateBucketConfiguration::builder()
+        .location_constraint(constraint)
+        .build();
+
+    client
+        .create_bucket()
+        .create_bucket_configuration(cfg)
+        .bucket(bucket)
+        .send()
+        .await
+}
+
+enum Encryption {
+    S3Managed,
+    CustomerProvided { key: String, key_md5: String },
+}
+
+impl Encryption {
+    fn new(storage_config: &StorageConfig) -> PapiResult<Self> {
+        match storage_config.s3_encryption {
+            S3Encryption::S3Managed => Ok(Self::S3Managed),
+            S3Encryption::CustomerProvided => {
+                let key = STANDARD
+                    .decode(&storage_config.s3_sse_customer_key)
+                    .map_err(|e| PapiError::Config(format!("Invalid SSE customer key: {}", e)))?;
+                Ok(Self::CustomerProvided {
+                    key: storage_config.s3_sse_customer_key.clone(),
+                    key_md5: STANDARD.encode(Md5::digest(key)),
+                })
+            }
+        }
+    }
+
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        match self {
+            Self::S3Managed => Some(ServerSideEncryption::Aes256),
+            Self::CustomerProvided { .. } => None,
+        }
+    }
+
+    /// Algorithm, key and key MD5 to send with every request reading or writing an object
+    fn sse_customer_params(&self) -> [Option<String>; 3] {
+        match self {
+            Self::S3Managed => [None, None, None],
+            Self::CustomerProvided { key, key_md5 } => [
+                Some(SSE_CUSTOMER_ALGORITHM.to_string()),
+                Some(key.clone()),
+                Some(key_md5.clone()),
+            ],
+        }
+    }
+}
+
+pub struct S3FileStore {
+    s3_client: S3Client,
+    bucket_name: String,
+    encryption: Encryption,
+}
+
+impl S3FileStore {
+    pub async fn setup(storage_config: &StorageConfig) -> PapiResult<Self> {
+        let bucket_name = storage_config.s3_bucket_name.clone();
+
+        let config = aws_config::defaults(BehaviorVersion::latest())
+            .region(Region::new(storage_config.aws_region.clone()))
+            .load()
+            .await;
+        let s3_client = S3Client::new(&config);
+
+        if !s3_client
+            .list_buckets()
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("Error listing buckets: {}", e)))?
+            .buckets()
+            .iter()
+            .any(|b| b.name() == Some(&bucket_name))
+        {
+            create_bucket(&s3_client, &bucket_name, &storage_config.aws_region)
+                .await
+                .map_err(|e| PapiError::Storage(format!("Error creating bucket: {}", e)))?;
+            bucket_policy::apply_default_policy(&s3_client, &bucket_name).await?;
+            info!(bucket = %bucket_name, "Created bucket");
+        }
+
+        bucket_policy::check_bucket_policy(&s3_client, &bucket_name, storage_config.s3_encryption)
+            .await?;
+        bucket_policy::configure_retention(
+            &s3_client,
+            &bucket_name,
+            storage_config.raw_archive_retention_days,
+        )
+        .await?;
+
+        Ok(Self {
+            s3_client,
+            bucket_name,
+            encryption: Encryption::new(storage_config)?,
+        })
+    }
+}
+
+#[async_trait]
+impl FileStore for S3FileStore {
+    async fn put_file(&self, key: &str, tags: &FileTags, body: ByteStream) -> PapiResult<()> {
+        observe_storage(S3, "put_file", async {
+            let [sse_algorithm, sse_key, sse_key_md5] = self.encryption.sse_customer_params();
+            self.s3_client
+                .put_object()
+                .bucket(&self.bucket_name)
+                .key(key)
+                .body(body)
+                .content_type(tags.content_type())
+                .set_server_side_encryption(self.encryption.server_side_encryption())
+                .set_sse_customer_algorithm(sse_algorithm)
+                .set_sse_customer_key(sse_key)
+                .set_sse_customer_key_md5(sse_key_md5)
+                .metadata("resource", tags.resource())
+                .metadata("archive-job-id", tags.archive_job_id())
+                .tagging(tags.as_query()?)
+                .send()
+                .await
+                .map_err(|e| PapiError::Storage(format!("could not store file: {}", e)))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn copy_file(&self, from: &str, to: &str, tags: &FileTags) -> PapiResult<()> {
+        observe_storage(S3, "copy_file", async {
+            let [sse_algorithm, sse_key, sse_key_md5] = self.encryption.sse_customer_params();
+            // only used to migrate the legacy files, stored with the default settings, so only the
+            // copy is encrypted
+            self.s3_client
+                .copy_object()
+                .bucket(&self.bucket_name)
+                .copy_source(format!(
+                    "{}/{}",
+                    self.bucket_name,
+                    utf8_percent_encode(from, NON_ALPHANUMERIC)
+                ))
+                .key(to)
+                .content_type(tags.content_type())
+                .set_server_side_encryption(self.encryption.server_side_encryption())
+                .set_sse_customer_algorithm(sse_algorithm)
+                .set_sse_customer_key(sse_key)
+                .set_sse_customer_key_md5(sse_key_md5)
+                .metadata_directive(MetadataDirective::Replace)
+                .metadata("resource", tags.resource())
+                .metadata("archive-job-id", tags.archive_job_id())
+                .tagging_directive(TaggingDirective::Replace)
+                .tagging(tags.as_query()?)
+                .send()
+                .await
+                .map_err(|e| PapiError::Storage(format!("could not copy file {}: {}", from, e)))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_files(&self, prefix: &str) -> PapiResult<Vec<String>> {
+        observe_storage(S3, "list_files", async {
+            let mut keys = vec![];
+            let mut pages = self
+                .s3_client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix)
+                .into_paginator()
+                .send();
+            while let Some(page) = pages.next().await {
+                let page =
+                    page.map_err(|e| PapiError::Storage(format!("could not list files: {}", e)))?;
+                keys.extend(
+                    page.contents()
+                        .iter()
+                        .filter_map(|object| object.key())
+                        .map(|key| key.to_string()),
+                );
+            }
+            Ok(keys)
+        })
+        .await
+    }
+
+    async fn list_files_after(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> PapiResult<Vec<String>> {
+        observe_storage(S3, "list_files", async {
+            let output = self
+                .s3_client
+                .list_objects_v2()
+                .bucket(&self.bucket_name)
+                .prefix(prefix)
+                .set_start_after(start_after.map(str::to_string))
+                .max_keys(limit as i32)
+                .send()
+                .await
+                .map_err(|e| PapiError::Storage(format!("could not list files: {}", e)))?;
+            Ok(output
+                .contents()
+                .iter()
+                .filter_map(|object| object.key())
+                .map(|key| key.to_string())
+                .collect())
+        })
+        .await
+    }
+
+    async fn get_file(&self, key: &str) -> PapiResult<ByteStream> {
+        observe_storage(S3, "get_file", async {
+            let [sse_algorithm, sse_key, sse_key_md5] = self.encryption.sse_customer_params();
+            let encrypted_with_customer_key = sse_algorithm.is_some();
+            let output = match self
+                .s3_client
+                .get_object()
+                .bucket(&self.bucket_name)
+                .key(key)
+                .set_sse_customer_algorithm(sse_algorithm)
+                .set_sse_customer_key(sse_key)
+                .set_sse_customer_key_md5(sse_key_md5)
+                .send()
+                .await
+            {
+                // S3 rejects the customer key for the files stored before SSE-C was enabled
+                Err(e)
+                    if encrypted_with_customer_key
+                        && e.raw_response()
+                            .is_some_and(|response| response.status().as_u16() == 400) =>
+                {
+                    self.s3_client
+                        .get_object()
+                        .bucket(&self.bucket_name)
+                        .key(key)
+                        .send()
+                        .await
+                }
+                result => result,
+            }
+            .map_err(|e| PapiError::Storage(format!("could not read file {}: {}", key, e)))?;
+            Ok(output.body)
+        })
+        .await
+    }
+
+    async fn delete_files(&self, keys: &[String]) -> PapiResult<()> {
+        observe_storage(S3, "delete_files", async {
+            for batch in keys.chunks(DELETE_OBJECTS_BATCH_SIZE) {
+                let objects = batch
+                    .iter()
+                    .map(|key| ObjectIdentifier::builder().key(key).build())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| PapiError::Storage(format!("could not delete files: {}", e)))?;
+                let delete = Delete::builder()
+                    .set_objects(Some(objects))
+                    .quiet(true)
+                    .build()
+                    .map_err(|e| PapiError::Storage(format!("could not delete files: {}", e)))?;
+                let output = self
+                    .s3_client
+                    .delete_objects()
+                    .bucket(&self.bucket_name)
+                    .delete(delete)
+                    .send()
+                    .await
+                    .map_err(|e| PapiError::Storage(format!("could not delete files: {}", e)))?;
+                if let Some(error) = output.errors().first() {
+                    return Err(PapiError::Storage(format!(
+                        "could not delete file {:?}: {:?}",
+                        error.key(),
+                        error.message()
+                    )));
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn check_reachable(&self) -> PapiResult<()> {
+        self.s3_client
+            .head_bucket()
+            .bucket(&self.bucket_name)
+            .send()
+            .await
+            .map_err(|e| PapiError::Storage(format!("could not reach bucket: {}", e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,13 @@
+use serde::Serialize;
+
+use crate::error::{PapiError, PapiResult};
+
+/// Parameters sent in the query string of a request URL
+pub trait QueryParams: Serialize {
+    /// Percent-encodes the parameters as `key=value` pairs joined by `&`, skipping the unset ones
+    fn as_url(&self) -> PapiResult<String> {
+        serde_urlencoded::to_string(self).map_err(|e| {
+            PapiError::InvalidRequest(format!("Failed to serialize query parameters: {}", e))
+        })
+    }
+}
@@ -0,0 +1,365 @@
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::Payload,
+    http::{header, Method},
+    web::Data,
+    FromRequest, HttpRequest,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::time::Duration;
+
+use crate::{
+    api::types::UserId,
+    config::{SessionConfig, SessionCookieSameSite},
+    error::{PapiError, PapiResult},
+};
+
+pub use sqlite::SqliteSessionStore;
+
+mod sqlite;
+
+pub const SESSION_COOKIE_NAME: &str = "papi_session";
+
+/// Keeps the sessions ended before they expire, the session tokens themselves being stateless
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Ends all the sessions issued to the user so far
+    async fn revoke_user_sessions(&self, user_id: &UserId) -> PapiResult<()>;
+
+    /// Returns when the sessions of the user were last revoked, if ever
+    async fn sessions_revoked_at(&self, user_id: &UserId) -> PapiResult<Option<i64>>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionClaims {
+    sub: UserId,
+    iat: i64,
+    exp: i64,
+}
+
+/// Issues and verifies the session tokens identifying the users of the API.
+///
+/// Tokens are JWTs signed by the backend, sent by browsers as a cookie and by other clients as a
+/// bearer token.
+pub struct SessionManager {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    ttl: Duration,
+    cookie_same_site: SameSite,
+    /// Origins the cookie is accepted from on requests changing state
+    allowed_origins: Vec<String>,
+    session_store: Arc<dyn SessionStore>,
+}
+
+impl SessionManager {
+    pub fn new(
+        session_config: &SessionConfig,
+        allowed_origins: &[String],
+        session_store: Arc<dyn SessionStore>,
+    ) -> Self {
+        Self {
+            encoding_key: EncodingKey::from_secret(session_config.secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(session_config.secret.as_bytes()),
+            ttl: session_config.ttl,
+            cookie_same_site: match session_config.cookie_same_site {
+                SessionCookieSameSite::Strict => SameSite::Strict,
+                SessionCookieSameSite::Lax => SameSite::Lax,
+                SessionCookieSameSite::None => SameSite::None,
+            },
+            allowed_origins: allowed_origins.to_vec(),
+            session_store,
+        }
+    }
+
+    pub fn issue(&self, user_id: UserId) -> PapiResult<String> {
+        let now = Utc::now().timestamp();
+        let claims = SessionClaims {
+            sub: user_id,
+            iat: now,
+            exp: now + self.ttl.as_secs() as i64,
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .map_err(|e| PapiError::Config(format!("Failed to sign session token: {}", e)))
+    }
+
+    pub async fn verify(&self, token: &str) -> PapiResult<UserId> {
+        let claims = decode::<SessionClaims>(
+            token,
+            &self.decoding_key,
+            &Validation::new(Algorithm::HS256),
+        )
+        .map(|data| data.claims)
+        .map_err(|e| PapiError::Unauthorized(format!("Invalid session: {}", e)))?;
+
+        let revoked_at = self
+            .session_store
+            .sessions_revoked_at(&claims.sub)
+            .await
+            .map_err(|e| e.context("could not read session revocation"))?;
+        if revoked_at.is_some_and(|revoked_at| claims.iat <= revoked_at) {
+            return Err(PapiError::Unauthorized("Session revoked".to_string()));
+        }
+        Ok(claims.sub)
+    }
+
+    pub fn cookie(&self, token: String) -> Cookie<'static> {
+        Cookie::build(SESSION_COOKIE_NAME, token)
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(self.cookie_same_site)
+            .max_age(actix_web::cookie::time::Duration::seconds(
+                self.ttl.as_secs() as i64,
+            ))
+            .finish()
+    }
+
+    /// Whether the request comes with a session, valid or not, delivered the given way
+    pub fn sends_session(&self, req: &HttpRequest, delivery: SessionDelivery) -> bool {
+        bearer_token(req).is_some()
+            || (delivery == SessionDelivery::Cookie && req.cookie(SESSION_COOKIE_NAME).is_some())
+    }
+
+    /// Returns the token sent with the request, either as a bearer token or as the session cookie
+    fn token_from_request(&self, req: &HttpRequest) -> PapiResult<String> {
+        if let Some(token) = bearer_token(req) {
+            return Ok(token);
+        }
+        let cookie = req
+            .cookie(SESSION_COOKIE_NAME)
+            .ok_or(PapiError::Unauthorized("Missing session".to_string()))?;
+        self.check_origin(req)?;
+        Ok(cookie.value().to_string())
+    }
+
+    /// Rejects the requests changing state with the cookie sent by the browser on behalf of
+    /// another site (CSRF)
+    fn check_origin(&self, req: &HttpRequest) -> PapiResult<()> {
+        if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+            return Ok(());
+        }
+        let origin = req
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|value| value.to_str().ok());
+        match origin {
+            Some(origin) if self.allowed_origins.iter().any(|o| o == origin) => Ok(()),
+            _ => Err(PapiError::Forbidden(format!(
+                "Session cookie sent from untrusted origin: {:?}",
+                origin
+            ))),
+        }
+    }
+}
+
+/// How a session token is handed over to the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionDelivery {
+    /// As an HttpOnly cookie, out of reach of the scripts of the page
+    Cookie,
+    /// In the response body, for the clients other than browsers, which send it as a bearer token
+    BearerToken,
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
+}
+
+/// User identified by a valid session token, rejects the request with 401 otherwise
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    user_id: UserId,
+}
+
+impl AuthenticatedUser {
+    pub fn user_id(&self) -> UserId {
+        self.user_id.clone()
+    }
+
+    pub async fn authenticate(req: &HttpRequest) -> PapiResult<Self> {
+        let session_manager = req
+            .app_data::<Data<SessionManager>>()
+            .ok_or(PapiError::Config(
+                "Session manager not configured".to_string(),
+            ))?;
+        let token = session_manager.token_from_request(req)?;
+        let user_id = session_manager.verify(&token).await?;
+
+        Ok(Self { user_id })
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = PapiError;
+    type Future = LocalBoxFuture<'static, PapiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { AuthenticatedUser::authenticate(&req).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    const FRONTEND_ORIGIN: &str = "https://app.example.com";
+
+    fn session_manager() -> SessionManager {
+        SessionManager::new(
+            &SessionConfig {
+                secret: "a-unit-test-session-secret-of-32-bytes".to_string(),
+                ttl: Duration::from_secs(60),
+                cookie_same_site: SessionCookieSameSite::Lax,
+            },
+            &[FRONTEND_ORIGIN.to_string()],
+            Arc::new(SqliteSessionStore::setup(":memory:").unwrap()),
+        )
+    }
+
+    #[actix_web::test]
+    async fn revoked_sessions_are_rejected() {
+        let session_manager = session_manager();
+        let token = session_manager.issue("user".to_string()).unwrap();
+        assert_eq!(session_manager.verify(&token).await.unwrap(), "user");
+
+        session_manager
+            .session_store
+            .revoke_user_sessions(&"user".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            session_manager.verify(&token).await,
+            Err(PapiError::Unauthorized(_))
+        ));
+        // the sessions of other users are not affected
+        let other_token = session_manager.issue("other-user".to_string()).unwrap();
+        assert!(session_manager.verify(&other_token).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn expired_sessions_are_rejected() {
+        let session_manager = session_manager();
+        // past the leeway given to the clock skew
+        let issued_at = Utc::now().timestamp() - 10 * 60;
+        let claims = SessionClaims {
+            sub: "user".to_string(),
+            iat: issued_at,
+            exp: issued_at + 60,
+        };
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &session_manager.encoding_key,
+        )
+        .unwrap();
+        assert!(matches!(
+            session_manager.verify(&token).await,
+            Err(PapiError::Unauthorized(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn tampered_sessions_are_rejected() {
+        let session_manager = session_manager();
+        let token = session_manager.issue("user".to_string()).unwrap();
+
+        // another user ID, keeping the signature of the original token
+        let [header, _, signature] = token.split('.').collect::<Vec<_>>()[..] else {
+            panic!("session token is not a JWT: {}", token);
+        };
+        let now = Utc::now().timestamp();
+        let claims = serde_json::json!({ "sub": "other-user", "iat": now, "exp": now + 60 });
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let forged = format!("{}.{}.{}", header, payload, signature);
+
+        // signed with another secret
+        let foreign = SessionManager::new(
+            &SessionConfig {
+                secret: "another-unit-test-secret-of-32-bytes".to_string(),
+                ttl: Duration::from_secs(60),
+                cookie_same_site: SessionCookieSameSite::Lax,
+            },
+            &[FRONTEND_ORIGIN.to_string()],
+            Arc::new(SqliteSessionStore::setup(":memory:").unwrap()),
+        )
+        .issue("user".to_string())
+        .unwrap();
+
+        for token in [forged, foreign, "not-a-token".to_string()] {
+            assert!(
+                matches!(
+                    session_manager.verify(&token).await,
+                    Err(PapiError::Unauthorized(_))
+                ),
+                "{} was accepted",
+                token
+            );
+        }
+    }
+
+    fn cookie_request(method: Method, origin: Option<&str>) -> HttpRequest {
+        let session_manager = session_manager();
+        let token = session_manager.issue("user".to_string()).unwrap();
+        let mut req = TestRequest::default()
+            .method(method)
+            .cookie(session_manager.cookie(token))
+            .app_data(Data::new(session_manager));
+        if let Some(origin) = origin {
+            req = req.insert_header((header::ORIGIN, origin));
+        }
+        req.to_http_request()
+    }
+
+    #[actix_web::test]
+    async fn cookie_is_accepted_from_the_frontend() {
+        let req = cookie_request(Method::DELETE, Some(FRONTEND_ORIGIN));
+        assert_eq!(
+            AuthenticatedUser::authenticate(&req)
+                .await
+                .unwrap()
+                .user_id(),
+            "user"
+        );
+    }
+
+    #[actix_web::test]
+    async fn cookie_is_rejected_on_state_changes_from_other_origins() {
+        for origin in [Some("https://evil.example.com"), None] {
+            let req = cookie_request(Method::POST, origin);
+            assert!(matches!(
+                AuthenticatedUser::authenticate(&req).await,
+                Err(PapiError::Forbidden(_))
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn cookie_is_accepted_on_reads_from_any_origin() {
+        let req = cookie_request(Method::GET, Some("https://evil.example.com"));
+        assert!(AuthenticatedUser::authenticate(&req).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn bearer_token_is_not_checked_against_the_origin() {
+        let session_manager = session_manager();
+        let token = session_manager.issue("user".to_string()).unwrap();
+        let req = TestRequest::post()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .insert_header((header::ORIGIN, "https://evil.example.com"))
+            .app_data(Data::new(session_manager))
+            .to_http_request();
+        assert!(AuthenticatedUser::authenticate(&req).await.is_ok());
+    }
+}
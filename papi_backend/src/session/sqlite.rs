@@ -0,0 +1,71 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    api::types::UserId,
+    error::{PapiError, PapiResult},
+    sqlite::SqliteDb,
+};
+
+use super::SessionStore;
+
+pub struct SqliteSessionStore {
+    db: SqliteDb,
+}
+
+impl SqliteSessionStore {
+    pub fn setup(db_path: &str) -> PapiResult<Self> {
+        Self::new(SqliteDb::open(db_path, "session store")?)
+    }
+
+    pub fn new(connection: Connection) -> PapiResult<Self> {
+        let db = SqliteDb::new(
+            connection,
+            "session store",
+            "CREATE TABLE IF NOT EXISTS session_revocations (
+                user_id TEXT PRIMARY KEY,
+                revoked_at INTEGER NOT NULL
+            );",
+        )?;
+
+        Ok(Self { db })
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn revoke_user_sessions(&self, user_id: &UserId) -> PapiResult<()> {
+        let user_id = user_id.clone();
+        self.db
+            .with_connection(move |connection| {
+                connection
+                    .execute(
+                        "INSERT INTO session_revocations (user_id, revoked_at) VALUES (?1, ?2)
+                        ON CONFLICT (user_id) DO UPDATE SET revoked_at = excluded.revoked_at",
+                        params![user_id, Utc::now().timestamp()],
+                    )
+                    .map_err(|e| PapiError::Storage(format!("Error revoking sessions: {}", e)))?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn sessions_revoked_at(&self, user_id: &UserId) -> PapiResult<Option<i64>> {
+        let user_id = user_id.clone();
+        self.db
+            .with_connection(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT revoked_at FROM session_revocations WHERE user_id = ?1",
+                        params![user_id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(|e| {
+                        PapiError::Storage(format!("Error reading session revocation: {}", e))
+                    })
+            })
+            .await
+    }
+}
@@ -0,0 +1,48 @@
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{PapiError, PapiResult};
+
+/// Database of a SQLite-backed store, queried on blocking tasks so that the async runtime is not
+/// held up
+#[derive(Clone)]
+pub struct SqliteDb {
+    connection: Arc<Mutex<Connection>>,
+    name: &'static str,
+}
+
+impl SqliteDb {
+    pub fn open(db_path: &str, name: &'static str) -> PapiResult<Connection> {
+        Connection::open(db_path).map_err(|e| {
+            PapiError::Storage(format!("Error opening {} DB {}: {}", name, db_path, e))
+        })
+    }
+
+    /// Creates the tables of the store if they do not exist yet
+    pub fn new(connection: Connection, name: &'static str, schema: &str) -> PapiResult<Self> {
+        connection
+            .execute_batch(&format!("PRAGMA journal_mode = WAL;\n{}", schema))
+            .map_err(|e| PapiError::Storage(format!("Error creating {} tables: {}", name, e)))?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            name,
+        })
+    }
+
+    pub async fn with_connection<T, F>(&self, f: F) -> PapiResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> PapiResult<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|e| PapiError::Storage(format!("Lock is poisoned: {}", e)))?;
+            f(&mut connection)
+        })
+        .await
+        .map_err(|e| PapiError::Storage(format!("Task on the {} DB failed: {}", self.name, e)))?
+    }
+}
@@ -0,0 +1,77 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{
+    config::{LogFormat, TelemetryConfig},
+    error::{PapiError, PapiResult},
+};
+
+const SERVICE_NAME: &str = "papi_backend";
+
+/// Keeps the OTLP exporter alive, the pending spans are flushed when dropped
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Installs the global subscriber writing the logs to stderr and, if an OTLP endpoint is
+    /// configured, exporting the spans to it
+    pub fn init(config: &TelemetryConfig) -> PapiResult<Self> {
+        let fmt_layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+        let fmt_layer = match config.log_format {
+            LogFormat::Json => fmt_layer
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .boxed(),
+            LogFormat::Text => fmt_layer.boxed(),
+        };
+
+        let tracer_provider = config
+            .otlp_endpoint
+            .as_ref()
+            .map(|endpoint| {
+                let exporter = SpanExporter::builder()
+                    .with_http()
+                    .with_endpoint(endpoint)
+                    .build()
+                    .map_err(|e| {
+                        PapiError::Config(format!("Could not create OTLP exporter: {}", e))
+                    })?;
+                Ok::<_, PapiError>(
+                    SdkTracerProvider::builder()
+                        .with_batch_exporter(exporter)
+                        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+                        .build(),
+                )
+            })
+            .transpose()?;
+        let otel_layer = tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+        });
+
+        tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(otel_layer)
+            .with(
+                EnvFilter::try_new(&config.log_level)
+                    .map_err(|e| PapiError::Config(format!("Invalid log level: {}", e)))?,
+            )
+            .try_init()
+            .map_err(|e| PapiError::Config(format!("Could not install the logger: {}", e)))?;
+
+        Ok(Self { tracer_provider })
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Could not flush the pending spans: {}", e);
+            }
+        }
+    }
+}
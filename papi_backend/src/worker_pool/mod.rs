@@ -0,0 +1,382 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    select,
+    sync::{Mutex as AsyncMutex, OwnedMutexGuard, Semaphore},
+    task::AbortHandle,
+    time::{interval_at, sleep, timeout, Duration, Instant},
+};
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    api::{
+        handlers::{
+            handle_archive_initiation, handle_archive_polling, handle_data_download,
+            handle_token_exchange,
+        },
+        types::UserId,
+    },
+    audit_log::AuditLog,
+    auth_db_client::AuthDbClient,
+    config::Config,
+    consent_ledger::{ConsentLedger, ConsentPolicy},
+    erasure::UserDataStores,
+    error::{PapiError, PapiResult},
+    job_queue::{Job, JobId, JobKind, JobQueue, ReceivedJob},
+    metrics::{outcome, METRICS},
+    oauth_client::OAuthClient,
+    papi_line_client::PapiLineClient,
+};
+
+const JOB_QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum number of jobs processed concurrently in each stage
+#[derive(Debug, Clone)]
+pub struct StageConcurrency {
+    pub token_exchange: usize,
+    pub archive_initiation: usize,
+    pub data_download: usize,
+}
+
+impl Default for StageConcurrency {
+    fn default() -> Self {
+        Self {
+            token_exchange: 8,
+            archive_initiation: 8,
+            data_download: 2,
+        }
+    }
+}
+
+impl StageConcurrency {
+    fn for_kind(&self, kind: JobKind) -> usize {
+        match kind {
+            JobKind::TokenExchange => self.token_exchange,
+            // polling an archive job is as cheap as initiating it
+            JobKind::ArchiveInitiation | JobKind::ArchivePolling => self.archive_initiation,
+            JobKind::DataDownload => self.data_download,
+        }
+    }
+}
+
+/// Serializes read-modify-write cycles of a user's OAuth info across concurrent jobs.
+///
+/// The locks only exist in this process: replicas sharing the same auth DB can still interleave
+/// their updates of the same user, so the backend must run as a single replica until the writes
+/// are made conditional.
+#[derive(Default)]
+pub struct UserLocks {
+    locks: Mutex<HashMap<UserId, Arc<AsyncMutex<()>>>>,
+}
+
+impl UserLocks {
+    pub async fn lock(&self, user_id: &UserId) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+            // drop the locks nobody is holding or waiting for
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            Arc::clone(locks.entry(user_id.clone()).or_default())
+        };
+        lock.lock_owned().await
+    }
+}
+
+pub struct JobContext {
+    pub config: Config,
+    pub auth_db_client: Arc<dyn AuthDbClient>,
+    pub papi_line_client: Arc<PapiLineClient>,
+    pub oauth_client: Arc<OAuthClient>,
+    pub job_queue: Arc<dyn JobQueue>,
+    pub audit_log: Arc<dyn AuditLog>,
+    pub consent_ledger: Arc<dyn ConsentLedger>,
+    pub user_locks: Arc<UserLocks>,
+}
+
+impl JobContext {
+    pub fn new(config: Config, stores: &UserDataStores) -> Self {
+        Self {
+            config,
+            auth_db_client: Arc::clone(&stores.auth_db_client),
+            papi_line_client: Arc::clone(&stores.papi_line_client),
+            oauth_client: Arc::clone(&stores.oauth_client),
+            job_queue: Arc::clone(&stores.job_queue),
+            audit_log: Arc::clone(&stores.audit_log),
+            consent_ledger: Arc::clone(&stores.consent_ledger),
+            user_locks: Arc::clone(&stores.user_locks),
+        }
+    }
+
+    fn consent_policy(&self) -> ConsentPolicy<'_> {
+        ConsentPolicy {
+            consent_ledger: self.consent_ledger.as_ref(),
+            consent_config: &self.config.consent,
+        }
+    }
+}
+
+const STAGES: [JobKind; 4] = [
+    JobKind::TokenExchange,
+    JobKind::ArchiveInitiation,
+    JobKind::ArchivePolling,
+    JobKind::DataDownload,
+];
+
+/// Tells whether the worker pool is processing jobs, for the readiness checks
+#[derive(Default)]
+pub struct WorkerStatus {
+    running_dispatchers: AtomicUsize,
+}
+
+impl WorkerStatus {
+    /// Whether a dispatcher is running for every stage
+    pub fn is_running(&self) -> bool {
+        self.running_dispatchers.load(Ordering::SeqCst) == STAGES.len()
+    }
+}
+
+// the dispatcher is no longer counted as running once its future is dropped
+struct RunningDispatcher<'a>(&'a WorkerStatus);
+
+impl<'a> RunningDispatcher<'a> {
+    fn new(status: &'a WorkerStatus) -> Self {
+        status.running_dispatchers.fetch_add(1, Ordering::SeqCst);
+        Self(status)
+    }
+}
+
+impl Drop for RunningDispatcher<'_> {
+    fn drop(&mut self) {
+        self.0.running_dispatchers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Jobs being processed, so that the unfinished ones can be released on shutdown
+#[derive(Default)]
+struct InFlightJobs {
+    tasks: TaskTracker,
+    jobs: Mutex<HashMap<JobId, (ReceivedJob, AbortHandle)>>,
+}
+
+impl InFlightJobs {
+    fn remove(&self, job_id: &JobId) {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(job_id);
+    }
+}
+
+pub struct WorkerPool {
+    context: Arc<JobContext>,
+    concurrency: StageConcurrency,
+    status: Arc<WorkerStatus>,
+    in_flight: Arc<InFlightJobs>,
+}
+
+impl WorkerPool {
+    pub fn new(context: JobContext, concurrency: StageConcurrency) -> Self {
+        Self {
+            context: Arc::new(context),
+            concurrency,
+            status: Arc::new(WorkerStatus::default()),
+            in_flight: Arc::new(InFlightJobs::default()),
+        }
+    }
+
+    pub fn status(&self) -> Arc<WorkerStatus> {
+        Arc::clone(&self.status)
+    }
+
+    /// Runs one dispatcher per stage, each processing up to its configured number of jobs concurrently
+    pub async fn run(&self) {
+        futures::future::join_all(STAGES.map(|kind| self.dispatch(kind))).await;
+    }
+
+    /// Waits for the jobs being processed to finish, once `run` has been dropped.
+    ///
+    /// The jobs still unfinished at the deadline are interrupted and made visible again, so that
+    /// they are processed right after the restart.
+    pub async fn drain(&self, deadline: Duration) {
+        self.in_flight.tasks.close();
+        if timeout(deadline, self.in_flight.tasks.wait()).await.is_ok() {
+            return;
+        }
+
+        let unfinished: Vec<(ReceivedJob, AbortHandle)> = self
+            .in_flight
+            .jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .drain()
+            .map(|(_, job)| job)
+            .collect();
+        for (received_job, task) in unfinished {
+            task.abort();
+            match self.context.job_queue.release(&received_job).await {
+                Ok(()) => warn!(job_id = %received_job.id(), "Released unfinished job"),
+                Err(e) => error!(job_id = %received_job.id(), error = %e, "Error releasing job"),
+            }
+        }
+    }
+
+    /// How long a dispatcher waits after finding no job, so that the archive jobs are still
+    /// polled at their configured interval when it is shorter than the usual one
+    fn idle_interval(&self, kind: JobKind) -> Duration {
+        match kind {
+            JobKind::ArchivePolling => {
+                JOB_QUEUE_POLL_INTERVAL.min(self.context.config.google.archive_poll_interval)
+            }
+            _ => JOB_QUEUE_POLL_INTERVAL,
+        }
+    }
+
+    async fn dispatch(&self, kind: JobKind) {
+        let _running = RunningDispatcher::new(&self.status);
+        let permits = Arc::new(Semaphore::new(self.concurrency.for_kind(kind)));
+
+        loop {
+            let Ok(permit) = Arc::clone(&permits).acquire_owned().await else {
+                return;
+            };
+
+            match self.context.job_queue.receive(kind).await {
+                Ok(Some(received_job)) => {
+                    let context = Arc::clone(&self.context);
+                    let in_flight = Arc::clone(&self.in_flight);
+                    let job_id = received_job.id();
+                    // held until the job is registered, so that it cannot be removed before
+                    let mut jobs = self
+                        .in_flight
+                        .jobs
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner());
+                    let task = self.in_flight.tasks.spawn({
+                        let received_job = received_job.clone();
+                        async move {
+                            handle_job(&context, received_job).await;
+                            in_flight.remove(&job_id);
+                            drop(permit);
+                        }
+                    });
+                    jobs.insert(received_job.id(), (received_job, task.abort_handle()));
+                }
+                Ok(None) => sleep(self.idle_interval(kind)).await,
+                Err(e) => {
+                    error!(kind = kind.as_str(), error = %e, "Error receiving job");
+                    sleep(JOB_QUEUE_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
+
+#[instrument(
+    skip_all,
+    fields(
+        job_id = %received_job.id(),
+        kind = received_job.job().kind().as_str(),
+        user_id = %received_job.job().user_id(),
+        attempt = received_job.attempts(),
+    )
+)]
+async fn handle_job(context: &JobContext, received_job: ReceivedJob) {
+    info!("Processing job");
+    let start = Instant::now();
+
+    // the job would be delivered again to another consumer if it took longer than the visibility
+    // timeout, so the timeout is extended while the job is being processed
+    let work = process_job(context, received_job.job().clone());
+    tokio::pin!(work);
+    let period = context.config.job_queue.visibility_timeout / 3;
+    let mut heartbeat = interval_at(Instant::now() + period, period);
+    let res = loop {
+        select! {
+            res = &mut work => break res,
+            _ = heartbeat.tick() => {
+                match context.job_queue.extend_visibility(&received_job).await {
+                    Ok(()) => {}
+                    // another consumer got the job or it was removed, so the work is dropped
+                    // rather than done twice
+                    Err(PapiError::InvalidState(e)) => {
+                        warn!(error = %e, "Job no longer held, abandoning it");
+                        METRICS
+                            .job_duration
+                            .with_label_values(&[received_job.job().kind().as_str(), "abandoned"])
+                            .observe(start.elapsed().as_secs_f64());
+                        return;
+                    }
+                    Err(e) => warn!(error = %e, "Error extending job visibility"),
+                }
+            }
+        }
+    };
+
+    METRICS
+        .job_duration
+        .with_label_values(&[received_job.job().kind().as_str(), outcome(&res)])
+        .observe(start.elapsed().as_secs_f64());
+
+    let res = match res {
+        Ok(()) => context.job_queue.ack(&received_job).await,
+        Err(e) if e.is_retryable() => {
+            warn!(error = %e, "Job failed, retrying");
+            context.job_queue.nack(&received_job, &e.to_string()).await
+        }
+        // retrying the job would fail in the same way
+        Err(e) => {
+            error!(error = %e, "Job failed permanently");
+            context
+                .job_queue
+                .dead_letter(&received_job, &e.to_string())
+                .await
+        }
+    };
+    if let Err(e) = res {
+        error!(error = %e, "Error settling job");
+    }
+}
+
+async fn process_job(context: &JobContext, job: Job) -> PapiResult<()> {
+    match job {
+        Job::TokenExchange(oauth_info) => handle_token_exchange(context, oauth_info)
+            .await
+            .map_err(|e| e.context("Error handling token exchange")),
+        Job::ArchiveInitiation(initiation_info) => handle_archive_initiation(
+            context.auth_db_client.as_ref(),
+            &context.oauth_client,
+            context.job_queue.as_ref(),
+            context.audit_log.as_ref(),
+            context.consent_policy(),
+            &context.user_locks,
+            initiation_info,
+        )
+        .await
+        .map_err(|e| e.context("Error handling archive initiation")),
+        Job::ArchivePolling(polling_info) => handle_archive_polling(
+            context.auth_db_client.as_ref(),
+            &context.oauth_client,
+            context.job_queue.as_ref(),
+            &context.config.google,
+            polling_info,
+        )
+        .await
+        .map_err(|e| e.context("Error handling archive polling")),
+        Job::DataDownload(download_info) => handle_data_download(
+            context.auth_db_client.as_ref(),
+            &context.papi_line_client,
+            &context.oauth_client,
+            context.audit_log.as_ref(),
+            context.consent_policy(),
+            &context.user_locks,
+            download_info,
+        )
+        .await
+        .map_err(|e| e.context("Error handling data download")),
+    }
+}
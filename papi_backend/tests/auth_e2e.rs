@@ -0,0 +1,1413 @@
+//! Drives the authorization flow through the HTTP API against a fake Google, down to the files
+//! stored for the user.
+
+use actix_web::{App, HttpServer};
+use clap::Parser;
+use fake_google::{ArchiveState, FakeGoogle, GoogleAccount};
+use personal_api::{
+    admin,
+    api::{
+        types::{OAuthInfo, ResourceState},
+        AppState,
+    },
+    audit_log::{AuditAction, AuditLog, SqliteAuditLog},
+    auth_db_client::{AuthDbClient, InMemoryAuthDbClient},
+    config::{Cli, Config},
+    consent_ledger::{ConsentLedger, SqliteConsentLedger},
+    erasure::UserDataStores,
+    job_queue::{JobQueue, JobState, SqliteJobQueue},
+    oauth_client::OAuthClient,
+    oauth_state_store::InMemoryStateStore,
+    papi_line_client::{DataKind, FileStore, InMemoryFileStore, PapiLineClient},
+    session::{SessionStore, SqliteSessionStore, SESSION_COOKIE_NAME},
+    worker_pool::{JobContext, UserLocks, WorkerPool},
+};
+use serde_json::Value;
+use std::{
+    io::{Cursor, Read},
+    net::TcpListener,
+    sync::Arc,
+};
+use tokio::{
+    sync::Mutex,
+    task::JoinHandle,
+    time::{sleep, timeout, Duration},
+};
+use tracing_actix_web::TracingLogger;
+use zip::ZipArchive;
+
+mod fake_google;
+
+const CLIENT_ID: &str = "fake-client-id";
+const CLIENT_SECRET: &str = "fake-client-secret";
+const REQUESTED_RESOURCES: [&str; 2] = ["myactivity.search", "myactivity.shopping"];
+const FLOW_TIMEOUT: Duration = Duration::from_secs(30);
+const VIEWER_API_KEY: &str = "e2e-viewer-api-key";
+const OPERATOR_API_KEY: &str = "e2e-operator-api-key";
+// the three files of each archive and the raw archive itself
+const FILES_PER_RESOURCE: usize = 4;
+
+struct TestBackend {
+    base_url: String,
+    client: reqwest::Client,
+    config: Config,
+    auth_db: Arc<InMemoryAuthDbClient>,
+    files: Arc<InMemoryFileStore>,
+    job_queue: Arc<dyn JobQueue>,
+    audit_log: Arc<dyn AuditLog>,
+    consent_ledger: Arc<dyn ConsentLedger>,
+    session_store: Arc<dyn SessionStore>,
+    workers: Mutex<Workers>,
+}
+
+struct Workers {
+    pool: Arc<WorkerPool>,
+    run: JoinHandle<()>,
+}
+
+impl TestBackend {
+    async fn start(google: &FakeGoogle) -> Self {
+        Self::start_with(google, &[], None).await
+    }
+
+    /// Deploys the backend again with extra arguments, on the storage of this one
+    async fn redeploy(&self, google: &FakeGoogle, extra_args: &[&str]) -> Self {
+        self.stop_workers().await;
+        Self::start_with(google, extra_args, Some(self)).await
+    }
+
+    async fn start_with(
+        google: &FakeGoogle,
+        extra_args: &[&str],
+        previous: Option<&TestBackend>,
+    ) -> Self {
+        let mut args = vec![
+            "personal_api".to_string(),
+            "--cert-file-path=unused".to_string(),
+            "--key-file-path=unused".to_string(),
+            "--session-secret=an-e2e-test-session-secret-of-32-bytes".to_string(),
+            format!("--google-client-id={}", CLIENT_ID),
+            format!("--google-client-secret={}", CLIENT_SECRET),
+            "--redirect-uri=http://localhost:3000/auth/callback".to_string(),
+            "--cors-allowed-origins=http://localhost:3000".to_string(),
+            format!("--requested-resources={}", REQUESTED_RESOURCES.join(",")),
+            format!("--google-jwks-uri={}", google.jwks_uri()),
+            format!("--google-accounts-base-url={}", google.base_url()),
+            format!("--google-oauth2-base-url={}", google.base_url()),
+            format!("--data-portability-base-url={}", google.base_url()),
+            "--aws-region=unused".to_string(),
+            "--dynamo-db-auth-table-name=unused".to_string(),
+            "--oauth-state-store=memory".to_string(),
+            "--s3-bucket-name=unused".to_string(),
+            format!(
+                "--admin-api-keys=support:viewer:{},oncall:operator:{}",
+                admin::hash_api_key(VIEWER_API_KEY),
+                admin::hash_api_key(OPERATOR_API_KEY)
+            ),
+        ];
+        args.extend(extra_args.iter().map(|arg| arg.to_string()));
+        let mut config = Config::load(Cli::parse_from(args)).unwrap();
+        config.google.archive_poll_interval = Duration::from_millis(50);
+        config.job_queue.retry_delay = Duration::from_millis(50);
+
+        let (auth_db, files, job_queue, audit_log, consent_ledger, session_store) = match previous {
+            Some(previous) => (
+                Arc::clone(&previous.auth_db),
+                Arc::clone(&previous.files),
+                Arc::clone(&previous.job_queue),
+                Arc::clone(&previous.audit_log),
+                Arc::clone(&previous.consent_ledger),
+                Arc::clone(&previous.session_store),
+            ),
+            None => (
+                Arc::new(InMemoryAuthDbClient::default()),
+                Arc::new(InMemoryFileStore::default()),
+                Arc::new(SqliteJobQueue::setup(":memory:", config.job_queue.clone()).unwrap())
+                    as Arc<dyn JobQueue>,
+                Arc::new(SqliteAuditLog::setup(":memory:").unwrap()) as Arc<dyn AuditLog>,
+                Arc::new(SqliteConsentLedger::setup(":memory:").unwrap()) as Arc<dyn ConsentLedger>,
+                Arc::new(SqliteSessionStore::setup(":memory:").unwrap()) as Arc<dyn SessionStore>,
+            ),
+        };
+        let stores = stores(
+            &config,
+            &auth_db,
+            &files,
+            &job_queue,
+            &audit_log,
+            &consent_ledger,
+            &session_store,
+        );
+        let workers = Workers::start(&config, &stores);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let app_state =
+            AppState::new(stores, config.clone()).with_worker_status(workers.pool.status());
+        // as in the server, the request IDs recorded in the audit log are assigned by the logger
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(TracingLogger::default())
+                .configure(|cfg| app_state.configure(cfg))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        tokio::spawn(server);
+
+        Self {
+            base_url,
+            client: reqwest::Client::new(),
+            config,
+            auth_db,
+            files,
+            job_queue,
+            audit_log,
+            consent_ledger,
+            session_store,
+            workers: Mutex::new(workers),
+        }
+    }
+
+    /// Shuts the background work down as on SIGTERM
+    async fn stop_workers(&self) {
+        let workers = self.workers.lock().await;
+        workers.run.abort();
+        workers.pool.drain(self.config.shutdown_timeout).await;
+    }
+
+    /// Starts the background work again on the same job queue and storage, as after a restart
+    async fn restart_workers(&self) {
+        let stores = stores(
+            &self.config,
+            &self.auth_db,
+            &self.files,
+            &self.job_queue,
+            &self.audit_log,
+            &self.consent_ledger,
+            &self.session_store,
+        );
+        *self.workers.lock().await = Workers::start(&self.config, &stores);
+    }
+
+    /// Returns the user ID and the session token
+    async fn create_session(&self) -> (String, String) {
+        let session: Value = self
+            .client
+            .post(format!("{}/session/token", self.base_url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        (
+            session["user_id"].as_str().unwrap().to_string(),
+            session["token"].as_str().unwrap().to_string(),
+        )
+    }
+
+    async fn get_authorization_url(&self, token: &str) -> String {
+        let response: Value = self
+            .client
+            .post(format!("{}/auth/url", self.base_url))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        response["url"].as_str().unwrap().to_string()
+    }
+
+    async fn get_consents(&self, token: &str) -> Value {
+        self.client
+            .get(format!("{}/user/consents", self.base_url))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    async fn post_authorization_code(
+        &self,
+        token: &str,
+        code: &str,
+        state: &str,
+    ) -> reqwest::Response {
+        self.client
+            .post(format!("{}/auth", self.base_url))
+            .bearer_auth(token)
+            .json(&serde_json::json!({"code": code, "state": state}))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// Goes through the authorization flow and waits until all the resources are downloaded
+    async fn authorize(&self, google: &FakeGoogle, token: &str, user_id: &str) {
+        self.grant(google, token).await;
+        self.wait_for_downloads(user_id).await;
+    }
+
+    /// Goes through the authorization flow, without waiting for the resources to be downloaded
+    async fn grant(&self, google: &FakeGoogle, token: &str) {
+        let authorization_url = self.get_authorization_url(token).await;
+        self.grant_with_url(google, token, &authorization_url).await;
+    }
+
+    async fn grant_with_url(&self, google: &FakeGoogle, token: &str, authorization_url: &str) {
+        let consent = google.consent(authorization_url, test_account());
+        let response = self
+            .post_authorization_code(token, &consent.code, &consent.state)
+            .await;
+        assert!(response.status().is_success());
+    }
+
+    async fn wait_for_downloads(&self, user_id: &str) {
+        timeout(FLOW_TIMEOUT, async {
+            loop {
+                let downloaded = self
+                    .auth_db
+                    .read_last_auth_for_user(user_id.to_string())
+                    .await
+                    .is_ok_and(|oauth_info| oauth_info.is_all_resources_downloaded());
+                if downloaded {
+                    return;
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("resources were not downloaded in time");
+    }
+}
+
+fn stores(
+    config: &Config,
+    auth_db: &Arc<InMemoryAuthDbClient>,
+    files: &Arc<InMemoryFileStore>,
+    job_queue: &Arc<dyn JobQueue>,
+    audit_log: &Arc<dyn AuditLog>,
+    consent_ledger: &Arc<dyn ConsentLedger>,
+    session_store: &Arc<dyn SessionStore>,
+) -> UserDataStores {
+    UserDataStores {
+        auth_db_client: Arc::clone(auth_db) as Arc<dyn AuthDbClient>,
+        oauth_client: Arc::new(OAuthClient::new(config.google.clone())),
+        papi_line_client: Arc::new(PapiLineClient::new(Arc::clone(files) as Arc<dyn FileStore>)),
+        job_queue: Arc::clone(job_queue),
+        oauth_state_store: Arc::new(InMemoryStateStore::default()),
+        audit_log: Arc::clone(audit_log),
+        consent_ledger: Arc::clone(consent_ledger),
+        session_store: Arc::clone(session_store),
+        user_locks: Arc::new(UserLocks::default()),
+    }
+}
+
+impl Workers {
+    fn start(config: &Config, stores: &UserDataStores) -> Self {
+        let pool = Arc::new(WorkerPool::new(
+            JobContext::new(config.clone(), stores),
+            config.concurrency.clone(),
+        ));
+        let run = tokio::spawn({
+            let pool = Arc::clone(&pool);
+            async move { pool.run().await }
+        });
+        Self { pool, run }
+    }
+}
+
+fn test_account() -> GoogleAccount {
+    GoogleAccount {
+        sub: "1234567890".to_string(),
+        email: "user@example.com".to_string(),
+    }
+}
+
+#[actix_web::test]
+async fn authorization_flow_stores_the_requested_resources() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+
+    let authorization_url = backend.get_authorization_url(&token).await;
+    assert!(authorization_url.starts_with(&google.base_url()));
+
+    backend.authorize(&google, &token, &user_id).await;
+
+    let oauth_info = backend
+        .auth_db
+        .read_last_auth_for_user(user_id.clone())
+        .await
+        .unwrap();
+    assert_eq!(oauth_info.google_sub(), Some(test_account().sub));
+    assert_eq!(oauth_info.email(), Some(test_account().email));
+    // the authorization is reset once everything is downloaded
+    assert_eq!(google.resets(), 1);
+
+    let keys = backend
+        .files
+        .list_files(&format!("users/{}/", user_id))
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), REQUESTED_RESOURCES.len() * FILES_PER_RESOURCE);
+    for resource in REQUESTED_RESOURCES {
+        // the files keep their path in the archive, so files with the same name do not collide
+        for (name, content) in FakeGoogle::archive_files(resource) {
+            let key = keys
+                .iter()
+                .find(|key| {
+                    key.starts_with(&format!("users/{}/{}/", user_id, resource))
+                        && key.ends_with(&format!("/{}", name))
+                })
+                .unwrap_or_else(|| panic!("{} of {} not stored", name, resource));
+            let (tags, body) = backend.files.file(key).unwrap();
+            assert_eq!(tags.resource(), resource);
+            assert_eq!(tags.data_kind(), DataKind::Extracted);
+            assert_eq!(body, content.as_bytes());
+        }
+        let archive_key = keys
+            .iter()
+            .find(|key| {
+                key.starts_with(&format!("users/{}/{}/", user_id, resource))
+                    && key.ends_with(".zip")
+            })
+            .unwrap_or_else(|| panic!("raw archive of {} not stored", resource));
+        let (tags, _) = backend.files.file(archive_key).unwrap();
+        assert_eq!(tags.data_kind(), DataKind::Raw);
+    }
+}
+
+#[actix_web::test]
+async fn archive_state_is_polled_until_complete() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    google.script_archive_states(vec![
+        ArchiveState::InProgress,
+        ArchiveState::InProgress,
+        ArchiveState::InProgress,
+        ArchiveState::Complete,
+    ]);
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+
+    backend.authorize(&google, &token, &user_id).await;
+
+    let polls = google.archive_polls();
+    for resource in REQUESTED_RESOURCES {
+        assert_eq!(polls[resource], 4);
+    }
+}
+
+#[actix_web::test]
+async fn archive_state_is_polled_again_when_google_is_unavailable() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    google.script_archive_states(vec![ArchiveState::Unavailable, ArchiveState::Complete]);
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+
+    backend.authorize(&google, &token, &user_id).await;
+
+    let polls = google.archive_polls();
+    for resource in REQUESTED_RESOURCES {
+        assert_eq!(polls[resource], 2);
+    }
+}
+
+#[actix_web::test]
+async fn failed_archive_jobs_are_dead_lettered() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    google.script_archive_states(vec![ArchiveState::Failed]);
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+
+    backend.grant(&google, &token).await;
+    let jobs = timeout(FLOW_TIMEOUT, async {
+        loop {
+            let jobs = backend.job_queue.list_user_jobs(&user_id).await.unwrap();
+            if jobs
+                .iter()
+                .filter(|job| job.state == JobState::DeadLettered)
+                .count()
+                == REQUESTED_RESOURCES.len()
+            {
+                return jobs;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("archive polling jobs were not dead-lettered in time");
+
+    // the failure is not retried
+    for job in jobs {
+        assert_eq!(job.kind, "archive_polling");
+        assert_eq!(job.attempts, 1);
+        assert!(job.last_error.unwrap().contains("ended in state FAILED"));
+    }
+    for resource in REQUESTED_RESOURCES {
+        assert_eq!(google.archive_polls()[resource], 1);
+    }
+}
+
+#[actix_web::test]
+async fn archive_jobs_never_ready_are_given_up() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    google.script_archive_states(vec![ArchiveState::InProgress]);
+    let backend =
+        TestBackend::start_with(&google, &["--archive-polling-timeout-secs=1"], None).await;
+    let (user_id, token) = backend.create_session().await;
+
+    backend.grant(&google, &token).await;
+    let jobs = timeout(FLOW_TIMEOUT, async {
+        loop {
+            let jobs = backend.job_queue.list_user_jobs(&user_id).await.unwrap();
+            if jobs
+                .iter()
+                .filter(|job| job.state == JobState::DeadLettered)
+                .count()
+                == REQUESTED_RESOURCES.len()
+            {
+                return jobs;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("archive polling jobs were not given up in time");
+
+    for job in jobs {
+        assert_eq!(job.kind, "archive_polling");
+        assert!(job.last_error.unwrap().contains("still not ready after 1s"));
+    }
+}
+
+#[actix_web::test]
+async fn authorization_code_with_unknown_state_is_rejected() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (_, token) = backend.create_session().await;
+
+    let authorization_url = backend.get_authorization_url(&token).await;
+    let consent = google.consent(&authorization_url, test_account());
+    let response = backend
+        .post_authorization_code(&token, &consent.code, "unknown-state")
+        .await;
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // the state can only be used once
+    let response = backend
+        .post_authorization_code(&token, &consent.code, &consent.state)
+        .await;
+    assert!(response.status().is_success());
+    let response = backend
+        .post_authorization_code(&token, &consent.code, &consent.state)
+        .await;
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn revoking_the_authorization_disconnects_google_and_deletes_the_files() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+
+    backend.authorize(&google, &token, &user_id).await;
+    let access_tokens = google.issued_access_tokens();
+    let resets = google.resets();
+
+    let receipt: Value = backend
+        .client
+        .delete(format!("{}/auth?delete_data=true", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        receipt["deleted_files"],
+        REQUESTED_RESOURCES.len() * FILES_PER_RESOURCE
+    );
+    assert_eq!(receipt["withdrawn_consents"], 1);
+
+    assert_eq!(google.resets(), resets + 1);
+    assert_eq!(google.revoked_tokens(), access_tokens);
+    assert!(backend
+        .files
+        .list_files(&format!("users/{}/", user_id))
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(backend
+        .auth_db
+        .read_last_auth_for_user(user_id)
+        .await
+        .unwrap()
+        .is_revoked());
+}
+
+#[actix_web::test]
+async fn erased_users_cannot_reuse_their_session() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    backend.authorize(&google, &token, &user_id).await;
+
+    let response = backend
+        .client
+        .delete(format!("{}/user", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = backend
+        .client
+        .post(format!("{}/auth/url", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+
+    // the revoked token is reported rather than replaced by a new user
+    let response = backend
+        .client
+        .post(format!("{}/session/token", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+}
+
+#[actix_web::test]
+async fn browser_sessions_are_only_sent_as_a_cookie() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+
+    let response = backend
+        .client
+        .post(format!("{}/session", backend.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let cookie = session_cookie(&response);
+    assert!(cookie.contains("HttpOnly"), "{}", cookie);
+    let session: Value = response.json().await.unwrap();
+    assert!(session["user_id"].is_string());
+    assert!(session.get("token").is_none());
+
+    // an invalid session cookie is reported and removed, so that the page can start a new one
+    let response = backend
+        .client
+        .post(format!("{}/session", backend.base_url))
+        .header(
+            reqwest::header::COOKIE,
+            format!("{}=not-a-token", SESSION_COOKIE_NAME),
+        )
+        .header(reqwest::header::ORIGIN, "http://localhost:3000")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    let removal = session_cookie(&response);
+    assert!(
+        removal.starts_with(&format!("{}=;", SESSION_COOKIE_NAME)),
+        "{}",
+        removal
+    );
+}
+
+/// The `Set-Cookie` header of the session cookie
+fn session_cookie(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get_all(reqwest::header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find(|value| value.starts_with(&format!("{}=", SESSION_COOKIE_NAME)))
+        .unwrap()
+        .to_string()
+}
+
+#[actix_web::test]
+async fn revocation_waits_for_the_downloads_in_flight() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    google.delay_downloads(Duration::from_millis(500));
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    backend.grant(&google, &token).await;
+    timeout(FLOW_TIMEOUT, async {
+        while google.downloads() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no download started in time");
+
+    let receipt: Value = backend
+        .client
+        .delete(format!("{}/auth?delete_data=true", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(receipt["deleted_files"], FILES_PER_RESOURCE);
+
+    // the downloads still pending are skipped, as the authorization is revoked
+    sleep(Duration::from_secs(1)).await;
+    assert!(backend
+        .files
+        .list_files(&format!("users/{}/", user_id))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[actix_web::test]
+async fn erasure_waits_for_the_downloads_in_flight() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    google.delay_downloads(Duration::from_millis(500));
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    backend.grant(&google, &token).await;
+    timeout(FLOW_TIMEOUT, async {
+        while google.downloads() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no download started in time");
+
+    let receipt: Value = backend
+        .client
+        .delete(format!("{}/user", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(receipt["deleted_files"], FILES_PER_RESOURCE);
+    assert!(receipt["kept_audit_events"].as_u64().unwrap() > 0);
+
+    // nothing is stored by the jobs still running or polling
+    sleep(Duration::from_secs(1)).await;
+    assert!(backend
+        .files
+        .list_files(&format!("users/{}/", user_id))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+/// Value of a sample of the Prometheus text exposition, e.g. `papi_archive_polls_total{resource="a"}`
+fn metric_value(metrics: &str, sample: &str) -> Option<f64> {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(sample)?.trim().parse().ok())
+}
+
+#[actix_web::test]
+async fn metrics_count_the_steps_of_the_flow() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+
+    backend.authorize(&google, &token, &user_id).await;
+
+    // the metrics tell about the activity of the users, so they are not public
+    let response = backend
+        .client
+        .get(format!("{}/metrics", backend.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let response = backend
+        .client
+        .get(format!("{}/metrics", backend.base_url))
+        .header(admin::ADMIN_API_KEY_HEADER, VIEWER_API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let metrics = response.text().await.unwrap();
+
+    // the metrics are shared by the tests running concurrently in this process
+    let at_least = |sample: &str, min: f64| {
+        let value = metric_value(&metrics, sample).unwrap_or_else(|| panic!("{} missing", sample));
+        assert!(value >= min, "{} is {}", sample, value);
+    };
+    at_least(
+        r#"papi_authorization_attempts_total{outcome="code_received"}"#,
+        1.0,
+    );
+    at_least(r#"papi_token_exchanges_total{outcome="success"}"#, 1.0);
+    for resource in REQUESTED_RESOURCES {
+        let files = FakeGoogle::archive_files(resource);
+        at_least(
+            &format!(r#"papi_extracted_files_total{{resource="{}"}}"#, resource),
+            files.len() as f64,
+        );
+        at_least(
+            &format!(r#"papi_download_bytes_total{{resource="{}"}}"#, resource),
+            1.0,
+        );
+        at_least(
+            &format!(r#"papi_archive_polls_total{{resource="{}"}}"#, resource),
+            1.0,
+        );
+        at_least(
+            &format!(
+                r#"papi_archive_job_duration_seconds_count{{outcome="completed",resource="{}"}}"#,
+                resource
+            ),
+            1.0,
+        );
+    }
+    at_least(
+        r#"papi_job_duration_seconds_count{kind="data_download",outcome="success"}"#,
+        2.0,
+    );
+    // counted from the queue, so it cannot go negative whichever replica polled the archives
+    at_least("papi_archives_in_flight", 0.0);
+}
+
+#[actix_web::test]
+async fn health_endpoints_report_a_ready_backend() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+
+    let response = backend
+        .client
+        .get(format!("{}/healthz", backend.base_url))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    // the worker pool starts concurrently with the server
+    let readiness: Value = timeout(FLOW_TIMEOUT, async {
+        loop {
+            let response = backend
+                .client
+                .get(format!("{}/readyz", backend.base_url))
+                .send()
+                .await
+                .unwrap();
+            if response.status().is_success() {
+                return response.json().await.unwrap();
+            }
+            assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("backend not ready in time");
+    assert_eq!(readiness["ready"], true);
+    for check in [
+        "auth_db",
+        "oauth_state_store",
+        "file_store",
+        "config",
+        "worker_pool",
+    ] {
+        assert_eq!(readiness["checks"][check], "ok");
+    }
+}
+
+#[actix_web::test]
+async fn archive_polls_pending_on_shutdown_resume_after_restart() {
+    const ARCHIVE_POLLS_BEFORE_COMPLETION: usize = 60;
+
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    // the archive initiation jobs of a user are processed one at a time, so the first archive
+    // must still be in progress when the second one is first polled
+    google.script_archive_states(
+        [
+            vec![ArchiveState::InProgress; ARCHIVE_POLLS_BEFORE_COMPLETION],
+            vec![ArchiveState::Complete],
+        ]
+        .concat(),
+    );
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+
+    backend.grant(&google, &token).await;
+    timeout(FLOW_TIMEOUT, async {
+        while google
+            .archive_polls()
+            .values()
+            .filter(|polls| **polls > 0)
+            .count()
+            < REQUESTED_RESOURCES.len()
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("archive jobs were not polled in time");
+
+    backend.stop_workers().await;
+    let pending_polls = backend
+        .job_queue
+        .list_user_jobs(&user_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|job| job.kind == "archive_polling")
+        .count();
+    assert_eq!(pending_polls, REQUESTED_RESOURCES.len());
+    let polls = google.archive_polls();
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(google.archive_polls(), polls);
+
+    backend.restart_workers().await;
+    backend.wait_for_downloads(&user_id).await;
+    let keys = backend
+        .files
+        .list_files(&format!("users/{}/", user_id))
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), REQUESTED_RESOURCES.len() * FILES_PER_RESOURCE);
+    for resource in REQUESTED_RESOURCES {
+        assert_eq!(
+            google.archive_polls()[resource],
+            ARCHIVE_POLLS_BEFORE_COMPLETION + 1
+        );
+    }
+}
+
+#[actix_web::test]
+async fn admin_commands_retrigger_downloads_and_reset_authorizations() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    backend.authorize(&google, &token, &user_id).await;
+    // the admin commands run on their own stores, like the CLI does
+    let stores = stores(
+        &backend.config,
+        &backend.auth_db,
+        &backend.files,
+        &backend.job_queue,
+        &backend.audit_log,
+        &backend.consent_ledger,
+        &backend.session_store,
+    );
+
+    let users = serde_json::to_value(admin::list_users(&stores).await.unwrap()).unwrap();
+    assert_eq!(users[0]["user_id"], user_id.as_str());
+    assert_eq!(users[0]["granted_resources"].as_object().unwrap().len(), 2);
+
+    // the users are read from the auth DB page by page
+    let other_user_id = format!("{}-other", user_id);
+    backend
+        .auth_db
+        .create_auth(OAuthInfo::new(
+            other_user_id.clone(),
+            "other-state".to_string(),
+            "other-code".to_string(),
+            "other-verifier".to_string(),
+        ))
+        .await
+        .unwrap();
+    let page =
+        serde_json::to_value(admin::search_users(&stores, "", 1, None).await.unwrap()).unwrap();
+    assert_eq!(page["users"][0]["user_id"], user_id.as_str());
+    let cursor = page["next_cursor"].as_str().unwrap().to_string();
+    let page = serde_json::to_value(
+        admin::search_users(&stores, "", 1, Some(cursor))
+            .await
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(page["users"][0]["user_id"], other_user_id.as_str());
+    assert!(page["next_cursor"].is_null());
+    backend
+        .auth_db
+        .delete_auth_for_user(&other_user_id)
+        .await
+        .unwrap();
+
+    let resource = REQUESTED_RESOURCES[0].to_string();
+    let key = backend
+        .files
+        .list_files(&format!("users/{}/{}/", user_id, resource))
+        .await
+        .unwrap()
+        .remove(0);
+    let archive_job_id = key
+        .split('/')
+        .nth(3)
+        .unwrap()
+        .trim_end_matches(".zip")
+        .to_string();
+    let polls = google.archive_polls()[resource.as_str()];
+
+    admin::retrigger_download(&stores, &user_id, &resource, archive_job_id)
+        .await
+        .unwrap();
+    backend.wait_for_downloads(&user_id).await;
+    assert_eq!(google.archive_polls()[resource.as_str()], polls + 1);
+
+    let resets = google.resets();
+    admin::reset_authorization(&stores, &user_id).await.unwrap();
+    assert_eq!(google.resets(), resets + 1);
+
+    let unknown_user = "unknown-user".to_string();
+    assert!(
+        admin::retrigger_archive_initiation(&stores, &unknown_user, &resource)
+            .await
+            .is_err()
+    );
+}
+
+#[actix_web::test]
+async fn admin_api_requires_a_key_with_the_right_role() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    backend.authorize(&google, &token, &user_id).await;
+    let admin_request = |method: reqwest::Method, path: &str, api_key: Option<&str>| {
+        let request = backend
+            .client
+            .request(method, format!("{}/admin{}", backend.base_url, path));
+        match api_key {
+            Some(api_key) => request.header(admin::ADMIN_API_KEY_HEADER, api_key),
+            None => request,
+        }
+    };
+
+    // user sessions do not give access to the admin API
+    let response = admin_request(reqwest::Method::GET, "/stats", None)
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let response = admin_request(reqwest::Method::GET, "/stats", Some("unknown-key"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let stats: Value = admin_request(reqwest::Method::GET, "/stats", Some(VIEWER_API_KEY))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["users"], 1);
+    assert_eq!(stats["resources"]["Downloaded"], REQUESTED_RESOURCES.len());
+
+    let users: Value = admin_request(
+        reqwest::Method::GET,
+        "/users?search=USER@example",
+        Some(VIEWER_API_KEY),
+    )
+    .send()
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(users["users"][0]["user_id"], user_id.as_str());
+    assert!(users["next_cursor"].is_null());
+    // the user list reads the data of the user, unlike the stats
+    let reads = backend
+        .audit_log
+        .list_user_events(&user_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|event| event.action() == AuditAction::DataRead)
+        .count();
+    assert_eq!(reads, 1);
+
+    let retry_path = format!(
+        "/users/{}/resources/{}/retry",
+        user_id, REQUESTED_RESOURCES[0]
+    );
+    let response = admin_request(reqwest::Method::POST, &retry_path, Some(VIEWER_API_KEY))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+    let denied = backend
+        .audit_log
+        .list_user_events(&user_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|event| event.action() == AuditAction::AdminActionDenied)
+        .count();
+    assert_eq!(denied, 1);
+
+    let response = admin_request(reqwest::Method::POST, &retry_path, Some(OPERATOR_API_KEY))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    backend.wait_for_downloads(&user_id).await;
+
+    let timeline: Value = admin_request(
+        reqwest::Method::GET,
+        &format!("/users/{}/jobs", user_id),
+        Some(VIEWER_API_KEY),
+    )
+    .send()
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    assert!(timeline["jobs"].as_array().unwrap().is_empty());
+
+    // the retried archive job stored its files next to the ones of the first job
+    let files = backend
+        .files
+        .list_files(&format!("users/{}/", user_id))
+        .await
+        .unwrap();
+    assert_eq!(
+        files.len(),
+        (REQUESTED_RESOURCES.len() + 1) * FILES_PER_RESOURCE
+    );
+    let receipt: Value = admin_request(
+        reqwest::Method::POST,
+        &format!("/users/{}/revoke?delete_data=true", user_id),
+        Some(OPERATOR_API_KEY),
+    )
+    .send()
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(receipt["deleted_files"], files.len());
+    assert!(backend
+        .auth_db
+        .read_last_auth_for_user(user_id)
+        .await
+        .unwrap()
+        .is_revoked());
+}
+
+#[actix_web::test]
+async fn users_can_see_who_accessed_their_data() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    backend.authorize(&google, &token, &user_id).await;
+
+    let response = backend
+        .client
+        .get(format!("{}/admin/users/{}", backend.base_url, user_id))
+        .header(admin::ADMIN_API_KEY_HEADER, VIEWER_API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let response = backend
+        .client
+        .get(format!("{}/user/export", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let export = response.bytes().await.unwrap();
+    let mut export = ZipArchive::new(Cursor::new(export)).unwrap();
+    assert!(export.by_name("authorization_history.json").is_ok());
+    for resource in REQUESTED_RESOURCES {
+        let activity_path = format!("Portability/My Activity/{}/MyActivity.json", resource);
+        let file_name = export
+            .file_names()
+            .find(|name| name.starts_with("files/") && name.ends_with(&activity_path))
+            .unwrap_or_else(|| panic!("activity file of {} not exported", resource))
+            .to_string();
+        // the normalized records keep the fields of the data pipeline, set to null when missing
+        let mut records = String::new();
+        export
+            .by_name(&format!(
+                "records/{}l",
+                file_name.trim_start_matches("files/")
+            ))
+            .unwrap()
+            .read_to_string(&mut records)
+            .unwrap();
+        let record: Value = serde_json::from_str(records.lines().next().unwrap()).unwrap();
+        assert_eq!(record["header"], resource);
+        assert_eq!(record["title"], "Searched for papi");
+        assert!(record["description"].is_null());
+        assert!(record.get("products").is_none());
+    }
+
+    let audit_log: Value = backend
+        .client
+        .get(format!("{}/user/audit-log", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let events = audit_log["events"].as_array().unwrap();
+    let count = |action: &str| events.iter().filter(|e| e["action"] == action).count();
+    assert_eq!(count("authorization_granted"), 1);
+    assert_eq!(count("token_exchanged"), 1);
+    assert_eq!(count("archive_initiated"), REQUESTED_RESOURCES.len());
+    assert_eq!(count("data_downloaded"), REQUESTED_RESOURCES.len());
+    assert_eq!(count("authorization_reset"), 1);
+    assert_eq!(count("data_exported"), 1);
+
+    let granted = events
+        .iter()
+        .find(|e| e["action"] == "authorization_granted")
+        .unwrap();
+    assert_eq!(granted["actor"]["type"], "user");
+    assert!(granted["request_id"].is_string());
+    let read = events.iter().find(|e| e["action"] == "data_read").unwrap();
+    assert_eq!(read["actor"]["type"], "admin");
+    assert_eq!(read["actor"]["name"], "support");
+
+    // the events of other users are not shown
+    let (_, other_token) = backend.create_session().await;
+    let audit_log: Value = backend
+        .client
+        .get(format!("{}/user/audit-log", backend.base_url))
+        .bearer_auth(&other_token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(audit_log["events"].as_array().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn consent_to_an_outdated_privacy_notice_is_asked_again() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    backend.authorize(&google, &token, &user_id).await;
+
+    let consents = backend.get_consents(&token).await;
+    assert_eq!(consents["reprompt_required"], false);
+    let consent = &consents["consents"][0];
+    assert_eq!(consent["privacy_notice_version"], "1");
+    assert_eq!(consent["purposes"], serde_json::json!(["archiving"]));
+    assert_eq!(
+        consent["granted_resources"],
+        serde_json::json!(REQUESTED_RESOURCES)
+    );
+
+    let backend = backend
+        .redeploy(&google, &["--privacy-notice-version=2"])
+        .await;
+    let consents = backend.get_consents(&token).await;
+    assert_eq!(consents["privacy_notice_version"], "2");
+    assert_eq!(consents["reprompt_required"], true);
+
+    // archiving is not resumed on the consent given to the previous notice
+    let resource = REQUESTED_RESOURCES[0];
+    let response = backend
+        .client
+        .post(format!(
+            "{}/admin/users/{}/resources/{}/retry",
+            backend.base_url, user_id, resource
+        ))
+        .header(admin::ADMIN_API_KEY_HEADER, OPERATOR_API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    timeout(FLOW_TIMEOUT, async {
+        while !backend
+            .job_queue
+            .list_user_jobs(&user_id)
+            .await
+            .unwrap()
+            .is_empty()
+        {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("the retried job was not processed in time");
+    let oauth_info = backend
+        .auth_db
+        .read_last_auth_for_user(user_id.clone())
+        .await
+        .unwrap();
+    assert!(!oauth_info.is_all_resources_downloaded());
+    let initiated = backend
+        .audit_log
+        .list_user_events(&user_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|event| serde_json::to_value(event).unwrap()["action"] == "archive_initiated")
+        .count();
+    assert_eq!(initiated, REQUESTED_RESOURCES.len());
+
+    backend.authorize(&google, &token, &user_id).await;
+    let consents = backend.get_consents(&token).await;
+    assert_eq!(consents["reprompt_required"], false);
+    assert_eq!(consents["consents"].as_array().unwrap().len(), 2);
+    assert_eq!(consents["consents"][1]["privacy_notice_version"], "2");
+}
+
+#[actix_web::test]
+async fn resources_left_out_of_the_authorization_can_be_granted_later() {
+    let google = FakeGoogle::start(CLIENT_ID, CLIENT_SECRET).await;
+    let backend = TestBackend::start(&google).await;
+    let (user_id, token) = backend.create_session().await;
+    let [granted, left_out] = REQUESTED_RESOURCES;
+
+    let authorization_url = backend.get_authorization_url(&token).await;
+    let consent = google.consent_partially(&authorization_url, test_account(), &[left_out]);
+    let response = backend
+        .post_authorization_code(&token, &consent.code, &consent.state)
+        .await;
+    assert!(response.status().is_success());
+    backend.wait_for_downloads(&user_id).await;
+
+    let oauth_info = backend
+        .auth_db
+        .read_last_auth_for_user(user_id.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        oauth_info.granted_resources()[left_out],
+        ResourceState::NotGranted
+    );
+    assert_eq!(
+        oauth_info.missing_resources(&backend.config.google.requested_resources),
+        vec![left_out.to_string()]
+    );
+    let user_prefix = format!("users/{}/", user_id);
+    let keys = backend.files.list_files(&user_prefix).await.unwrap();
+    assert!(keys
+        .iter()
+        .all(|key| key.starts_with(&format!("{}{}/", user_prefix, granted))));
+
+    // only the resource left out is asked for
+    let incremental: Value = backend
+        .client
+        .post(format!("{}/auth/url?incremental=true", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        incremental["requested_resources"],
+        serde_json::json!([left_out])
+    );
+    let authorization_url = incremental["url"].as_str().unwrap();
+    assert!(authorization_url.contains(left_out));
+    assert!(!authorization_url.contains(granted));
+
+    backend
+        .grant_with_url(&google, &token, authorization_url)
+        .await;
+    // the previous authorization reads as all downloaded until the new one is stored
+    timeout(FLOW_TIMEOUT, async {
+        while !backend
+            .auth_db
+            .read_last_auth_for_user(user_id.clone())
+            .await
+            .is_ok_and(|oauth_info| {
+                oauth_info.granted_resources().get(left_out) == Some(&ResourceState::Downloaded)
+            })
+        {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("the resource left out was not downloaded in time");
+    let oauth_info = backend
+        .auth_db
+        .read_last_auth_for_user(user_id.clone())
+        .await
+        .unwrap();
+    assert!(oauth_info.is_all_resources_downloaded());
+    assert_eq!(google.resets(), 2);
+
+    // the resource downloaded with the first authorization is not archived again
+    let keys = backend.files.list_files(&user_prefix).await.unwrap();
+    assert_eq!(keys.len(), REQUESTED_RESOURCES.len() * FILES_PER_RESOURCE);
+    let initiated = backend
+        .audit_log
+        .list_user_events(&user_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|event| serde_json::to_value(event).unwrap()["action"] == "archive_initiated")
+        .count();
+    assert_eq!(initiated, REQUESTED_RESOURCES.len());
+
+    let response = backend
+        .client
+        .post(format!("{}/auth/url?incremental=true", backend.base_url))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_client_error());
+    // both authorizations are part of the history, not only the one kept in the auth DB
+    let history: Value = backend
+        .client
+        .get(format!("{}/admin/users/{}", backend.base_url, user_id))
+        .header(admin::ADMIN_API_KEY_HEADER, VIEWER_API_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let consents = history["consents"].as_array().unwrap();
+    assert_eq!(consents.len(), 2);
+    assert_eq!(
+        consents[0]["granted_resources"],
+        serde_json::json!([granted])
+    );
+    assert_eq!(
+        consents[1]["requested_resources"],
+        serde_json::json!([left_out])
+    );
+    assert_eq!(
+        history["current_authorization"]["granted_resources"][left_out],
+        "Downloaded"
+    );
+    let exchanges = history["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|event| event["action"] == "token_exchanged")
+        .count();
+    assert_eq!(exchanges, 2);
+}
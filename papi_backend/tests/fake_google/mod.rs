@@ -0,0 +1,488 @@
+//! In-process fake of the Google OAuth 2.0 and Data Portability APIs, serving the endpoints the
+//! backend calls on the base URLs it is configured with.
+
+use actix_web::{
+    dev::ServerHandle,
+    http::header,
+    web::{self, Data, Form, Path, Query},
+    App, HttpRequest, HttpResponse, HttpServer,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use reqwest::Url;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{Cursor, Write},
+    sync::Mutex,
+    time::Duration,
+};
+use uuid::Uuid;
+use zip::{write::FileOptions, ZipWriter};
+
+const ID_TOKEN_KEY_ID: &str = "fake-google-key";
+const ID_TOKEN_ISSUER: &str = "https://accounts.google.com";
+const ACCESS_TOKEN_TTL_SECS: i64 = 3600;
+const DATA_PORTABILITY_SCOPE_PREFIX: &str = "https://www.googleapis.com/auth/dataportability.";
+
+/// State reported by the fake for an archive job on each poll
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArchiveState {
+    InProgress,
+    /// Google fails to answer the poll
+    Unavailable,
+    /// Repeated for all the following polls
+    Failed,
+    /// Repeated for all the following polls
+    Complete,
+}
+
+/// The Google account consenting to the authorization
+#[derive(Debug, Clone)]
+pub struct GoogleAccount {
+    pub sub: String,
+    pub email: String,
+}
+
+/// What the user would be redirected back to the frontend with
+#[derive(Debug, Clone)]
+pub struct Consent {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Debug, Clone)]
+struct Grant {
+    account: GoogleAccount,
+    scope: String,
+    redirect_uri: String,
+    code_challenge: String,
+}
+
+#[derive(Debug)]
+struct ArchiveJob {
+    resource: String,
+    states: VecDeque<ArchiveState>,
+    polls: usize,
+}
+
+struct FakeState {
+    base_url: String,
+    client_id: String,
+    client_secret: String,
+    encoding_key: EncodingKey,
+    codes: Mutex<HashMap<String, Grant>>,
+    access_tokens: Mutex<HashMap<String, Grant>>,
+    revoked_tokens: Mutex<Vec<String>>,
+    /// Scopes each account granted since the last reset, by subject
+    granted_scopes: Mutex<HashMap<String, Vec<String>>>,
+    resets: Mutex<usize>,
+    archive_script: Mutex<Vec<ArchiveState>>,
+    archive_jobs: Mutex<HashMap<String, ArchiveJob>>,
+    download_delay: Mutex<Duration>,
+    downloads: Mutex<usize>,
+}
+
+pub struct FakeGoogle {
+    state: Data<FakeState>,
+    handle: ServerHandle,
+}
+
+impl FakeGoogle {
+    pub async fn start(client_id: &str, client_secret: &str) -> Self {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let state = Data::new(FakeState {
+            base_url,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            encoding_key: EncodingKey::from_rsa_pem(include_bytes!("id_token_key.pem")).unwrap(),
+            codes: Mutex::default(),
+            access_tokens: Mutex::default(),
+            revoked_tokens: Mutex::default(),
+            granted_scopes: Mutex::default(),
+            resets: Mutex::default(),
+            archive_script: Mutex::new(vec![ArchiveState::InProgress, ArchiveState::Complete]),
+            archive_jobs: Mutex::default(),
+            download_delay: Mutex::default(),
+            downloads: Mutex::default(),
+        });
+
+        let app_state = Data::clone(&state);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::clone(&app_state))
+                .route("/token", web::post().to(token))
+                .route("/revoke", web::post().to(revoke))
+                .route("/oauth2/v3/certs", web::get().to(certs))
+                .route(
+                    "/v1beta/portabilityArchive:initiate",
+                    web::post().to(initiate_archive),
+                )
+                .route(
+                    "/v1beta/archiveJobs/{job_id}/portabilityArchiveState",
+                    web::get().to(archive_state),
+                )
+                .route("/v1beta/authorization:reset", web::post().to(reset))
+                .route("/downloads/{job_id}", web::get().to(download))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        Self { state, handle }
+    }
+
+    pub fn base_url(&self) -> String {
+        self.state.base_url.clone()
+    }
+
+    pub fn jwks_uri(&self) -> String {
+        format!("{}/oauth2/v3/certs", self.state.base_url)
+    }
+
+    /// Sets the states reported by the archive jobs initiated from now on
+    pub fn script_archive_states(&self, states: Vec<ArchiveState>) {
+        *self.state.archive_script.lock().unwrap() = states;
+    }
+
+    /// Holds the archives back for the delay before sending them, as a slow download would
+    pub fn delay_downloads(&self, delay: Duration) {
+        *self.state.download_delay.lock().unwrap() = delay;
+    }
+
+    /// Number of archive downloads started so far
+    pub fn downloads(&self) -> usize {
+        *self.state.downloads.lock().unwrap()
+    }
+
+    /// Plays the user granting everything requested on the authorization page
+    pub fn consent(&self, authorization_url: &str, account: GoogleAccount) -> Consent {
+        self.consent_partially(authorization_url, account, &[])
+    }
+
+    /// Plays the user unchecking some of the requested resources on the authorization page
+    pub fn consent_partially(
+        &self,
+        authorization_url: &str,
+        account: GoogleAccount,
+        unchecked_resources: &[&str],
+    ) -> Consent {
+        let url = Url::parse(authorization_url).unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+        assert_eq!(params["client_id"], self.state.client_id);
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        let unchecked_scopes: Vec<String> = unchecked_resources
+            .iter()
+            .map(|resource| format!("{}{}", DATA_PORTABILITY_SCOPE_PREFIX, resource))
+            .collect();
+        let mut granted_scopes = self.state.granted_scopes.lock().unwrap();
+        let account_scopes = granted_scopes.entry(account.sub.clone()).or_default();
+        for scope in params["scope"].split(' ') {
+            if !unchecked_scopes.iter().any(|s| s == scope)
+                && !account_scopes.iter().any(|s| s == scope)
+            {
+                account_scopes.push(scope.to_string());
+            }
+        }
+        let scope = if params.get("include_granted_scopes").map(String::as_str) == Some("true") {
+            account_scopes.join(" ")
+        } else {
+            params["scope"]
+                .split(' ')
+                .filter(|scope| !unchecked_scopes.iter().any(|s| s == scope))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        drop(granted_scopes);
+
+        let code = Uuid::new_v4().to_string();
+        self.state.codes.lock().unwrap().insert(
+            code.clone(),
+            Grant {
+                account,
+                scope,
+                redirect_uri: params["redirect_uri"].clone(),
+                code_challenge: params["code_challenge"].clone(),
+            },
+        );
+        Consent {
+            code,
+            state: params["state"].clone(),
+        }
+    }
+
+    pub fn issued_access_tokens(&self) -> Vec<String> {
+        self.state
+            .access_tokens
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub fn revoked_tokens(&self) -> Vec<String> {
+        self.state.revoked_tokens.lock().unwrap().clone()
+    }
+
+    pub fn resets(&self) -> usize {
+        *self.state.resets.lock().unwrap()
+    }
+
+    /// Number of times the state of each resource's archive job was polled
+    pub fn archive_polls(&self) -> HashMap<String, usize> {
+        self.state
+            .archive_jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|job| (job.resource.clone(), job.polls))
+            .collect()
+    }
+
+    /// The files in the archive of a resource, as named in the ZIP file
+    pub fn archive_files(resource: &str) -> Vec<(String, String)> {
+        vec![
+            (
+                format!("Portability/My Activity/{}/MyActivity.json", resource),
+                format!(
+                    r#"[{{"header":"{}","title":"Searched for papi","time":"2024-01-01T00:00:00Z","products":["Search"]}}]"#,
+                    resource
+                ),
+            ),
+            (
+                format!("Portability/My Activity/{}/archive_browser.html", resource),
+                format!("<html>{}</html>", resource),
+            ),
+            // same name as the first file, in another directory
+            (
+                format!("Portability/Older/{}/MyActivity.json", resource),
+                format!("[{{\"resource\":\"{}\",\"older\":true}}]", resource),
+            ),
+        ]
+    }
+}
+
+impl Drop for FakeGoogle {
+    fn drop(&mut self) {
+        // stops accepting requests without waiting for the in-flight ones
+        drop(self.handle.stop(false));
+    }
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims {
+    iss: String,
+    aud: String,
+    sub: String,
+    email: String,
+    iat: i64,
+    exp: i64,
+}
+
+fn error(status: u16, message: &str) -> HttpResponse {
+    HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap())
+        .json(serde_json::json!({"error": {"code": status, "message": message}}))
+}
+
+fn bearer_grant(state: &FakeState, req: &HttpRequest) -> Option<Grant> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    state.access_tokens.lock().unwrap().get(token).cloned()
+}
+
+async fn token(state: Data<FakeState>, form: Form<HashMap<String, String>>) -> HttpResponse {
+    if form.get("client_id") != Some(&state.client_id)
+        || form.get("client_secret") != Some(&state.client_secret)
+    {
+        return error(401, "invalid_client");
+    }
+    if form.get("grant_type").map(String::as_str) != Some("authorization_code") {
+        return error(400, "unsupported_grant_type");
+    }
+    // authorization codes can only be exchanged once
+    let Some(grant) = form
+        .get("code")
+        .and_then(|code| state.codes.lock().unwrap().remove(code))
+    else {
+        return error(400, "invalid_grant");
+    };
+    if form.get("redirect_uri") != Some(&grant.redirect_uri) {
+        return error(400, "redirect_uri_mismatch");
+    }
+    let code_challenge = form
+        .get("code_verifier")
+        .map(|verifier| URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())));
+    if code_challenge.as_ref() != Some(&grant.code_challenge) {
+        return error(400, "invalid_grant");
+    }
+
+    let now = Utc::now().timestamp();
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(ID_TOKEN_KEY_ID.to_string());
+    let id_token = encode(
+        &header,
+        &IdTokenClaims {
+            iss: ID_TOKEN_ISSUER.to_string(),
+            aud: state.client_id.clone(),
+            sub: grant.account.sub.clone(),
+            email: grant.account.email.clone(),
+            iat: now,
+            exp: now + ACCESS_TOKEN_TTL_SECS,
+        },
+        &state.encoding_key,
+    )
+    .unwrap();
+
+    let access_token = Uuid::new_v4().to_string();
+    let scope = grant.scope.clone();
+    state
+        .access_tokens
+        .lock()
+        .unwrap()
+        .insert(access_token.clone(), grant);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "access_token": access_token,
+        "expires_in": ACCESS_TOKEN_TTL_SECS,
+        "scope": scope,
+        "token_type": "Bearer",
+        "id_token": id_token,
+    }))
+}
+
+async fn revoke(state: Data<FakeState>, form: Form<HashMap<String, String>>) -> HttpResponse {
+    let Some(token) = form.get("token") else {
+        return error(400, "invalid_request");
+    };
+    if state.access_tokens.lock().unwrap().remove(token).is_none() {
+        return error(400, "invalid_token");
+    }
+    state.revoked_tokens.lock().unwrap().push(token.clone());
+    HttpResponse::Ok().finish()
+}
+
+async fn certs() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(include_str!("jwks.json"))
+}
+
+async fn initiate_archive(
+    state: Data<FakeState>,
+    req: HttpRequest,
+    query: Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let Some(grant) = bearer_grant(&state, &req) else {
+        return error(401, "UNAUTHENTICATED");
+    };
+    let Some(resource) = query.get("resources") else {
+        return error(400, "INVALID_ARGUMENT");
+    };
+    let scope = format!("{}{}", DATA_PORTABILITY_SCOPE_PREFIX, resource);
+    if !grant.scope.split(' ').any(|s| s == scope) {
+        return error(403, "PERMISSION_DENIED");
+    }
+
+    let job_id = Uuid::new_v4().to_string();
+    let states = state.archive_script.lock().unwrap().clone().into();
+    state.archive_jobs.lock().unwrap().insert(
+        job_id.clone(),
+        ArchiveJob {
+            resource: resource.clone(),
+            states,
+            polls: 0,
+        },
+    );
+    HttpResponse::Ok().json(serde_json::json!({"archiveJobId": job_id}))
+}
+
+async fn archive_state(
+    state: Data<FakeState>,
+    req: HttpRequest,
+    job_id: Path<String>,
+) -> HttpResponse {
+    if bearer_grant(&state, &req).is_none() {
+        return error(401, "UNAUTHENTICATED");
+    }
+    let mut jobs = state.archive_jobs.lock().unwrap();
+    let Some(job) = jobs.get_mut(job_id.as_str()) else {
+        return error(404, "NOT_FOUND");
+    };
+    job.polls += 1;
+    let archive_state = if job.states.len() > 1 {
+        job.states.pop_front()
+    } else {
+        job.states.front().cloned()
+    };
+
+    match archive_state {
+        Some(ArchiveState::InProgress) => {
+            HttpResponse::Ok().json(serde_json::json!({"state": "IN_PROGRESS"}))
+        }
+        Some(ArchiveState::Unavailable) => error(503, "UNAVAILABLE"),
+        Some(ArchiveState::Failed) => {
+            HttpResponse::Ok().json(serde_json::json!({"state": "FAILED"}))
+        }
+        Some(ArchiveState::Complete) => HttpResponse::Ok().json(serde_json::json!({
+            "state": "COMPLETE",
+            "urls": [format!("{}/downloads/{}", state.base_url, job_id)],
+        })),
+        None => error(500, "INTERNAL"),
+    }
+}
+
+async fn reset(state: Data<FakeState>, req: HttpRequest) -> HttpResponse {
+    let Some(grant) = bearer_grant(&state, &req) else {
+        return error(401, "UNAUTHENTICATED");
+    };
+    state
+        .granted_scopes
+        .lock()
+        .unwrap()
+        .remove(&grant.account.sub);
+    *state.resets.lock().unwrap() += 1;
+    HttpResponse::Ok().json(serde_json::json!({}))
+}
+
+async fn download(state: Data<FakeState>, job_id: Path<String>) -> HttpResponse {
+    let Some(resource) = state
+        .archive_jobs
+        .lock()
+        .unwrap()
+        .get(job_id.as_str())
+        .map(|job| job.resource.clone())
+    else {
+        return error(404, "NOT_FOUND");
+    };
+    *state.downloads.lock().unwrap() += 1;
+    let delay = *state.download_delay.lock().unwrap();
+    tokio::time::sleep(delay).await;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.add_directory("Portability/", FileOptions::default())
+        .unwrap();
+    for (name, content) in FakeGoogle::archive_files(&resource) {
+        zip.start_file(name, FileOptions::default()).unwrap();
+        zip.write_all(content.as_bytes()).unwrap();
+    }
+    let archive = zip.finish().unwrap().into_inner();
+
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .body(archive)
+}
@@ -4,26 +4,24 @@ import React, { createContext, useState, useEffect } from "react";
 
 const ClientIdContext = createContext("clientId");
 
-// Generate a random client ID
-const generateClientId = () => {
-  return Math.random().toString(36).substring(2, 15);
-};
-
 // Create a provider component
 export const ClientIdProvider = ({ children }) => {
   const [clientId, setClientId] = useState(null);
 
   useEffect(() => {
-    // Generate the client ID only once
-    const storedClientId = localStorage.getItem("clientId");
-    if (storedClientId) {
-      setClientId(storedClientId);
-    } else {
-      const id = generateClientId();
-      localStorage.setItem("clientId", id);
-      setClientId(id);
-    }
-  }, [clientId]);
+    // The backend identifies the user with a session cookie, creating a new user on the first visit
+    const createSession = () =>
+      fetch(process.env.NEXT_PUBLIC_AUTH_API_BASE_URL + "/session", {
+        method: "POST",
+        credentials: "include",
+      });
+    createSession()
+      // an expired or revoked session cookie is removed by the backend, a new session is started
+      .then((response) => (response.status === 401 ? createSession() : response))
+      .then((response) => response.json())
+      .then((data) => setClientId(data["user_id"]))
+      .catch((error) => console.error("Error:", error));
+  }, []);
 
   return (
     <ClientIdContext.Provider value={clientId}>
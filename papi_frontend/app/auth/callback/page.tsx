@@ -16,9 +16,9 @@ const MyProfile = () => {
       console.log("Client Id:", clientId);
       fetch(authApiBaseUrl + "/auth", {
         method: "POST",
+        credentials: "include",
         headers: {
           "Content-Type": "application/json",
-          "X-Client-Id": clientId,
         },
         body: JSON.stringify({ state, code }),
       })
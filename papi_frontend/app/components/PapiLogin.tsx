@@ -12,11 +12,11 @@ export default function PapiLogin() {
     if (clientId) {
       console.log("Auth API base URL:", authApiBaseUrl);
       console.log("Client Id:", clientId);
-      fetch(authApiBaseUrl + "/auth", {
-        method: "GET",
+      fetch(authApiBaseUrl + "/auth/url", {
+        method: "POST",
+        credentials: "include",
         headers: {
           "Content-Type": "application/json",
-          "X-Client-Id": clientId,
         },
       })
         .then((response) => response.json())